@@ -1,8 +1,10 @@
-use std::{path::PathBuf, fs};
+use std::{path::PathBuf, fs, io::{self, Write}, str::FromStr, process, time::{SystemTime, UNIX_EPOCH, Duration}};
 
-use clap::{Parser, command, Subcommand, ValueEnum};
-use servefs_lib::{FSConnection, File, FSError, Directory};
-use sqlx::Row;
+use clap::{Parser, Subcommand, ValueEnum};
+use servefs_lib::{FSConnection, File, FSError, Directory, Metadata, FileType, Find, SortBy, Quota, Compression, EncryptionKey, Import, ImportMode, Export, ExportExec, CHUNK_SIZE};
+
+/// How often `watch` checks the change log when nothing has happened.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -35,6 +37,125 @@ enum Commands {
         /// Path to directory
         path: PathBuf,
      },
+    /// Manage custom file types
+    Type {
+        #[clap(subcommand)]
+        type_command: TypeCommands,
+    },
+    /// Save or roll back the whole tree
+    Snapshot {
+        #[clap(subcommand)]
+        snapshot_command: SnapshotCommands,
+    },
+    /// Restore or purge deleted files and directories
+    Trash {
+        #[clap(subcommand)]
+        trash_command: TrashCommands,
+    },
+    /// Choose how stored data is compressed
+    Compress {
+        #[clap(subcommand)]
+        compress_command: CompressCommands,
+    },
+    /// Copy or link a host directory tree into a directory
+    Import {
+        /// Host directory to import
+        host: PathBuf,
+        /// Directory to import into, made if it doesn't exist
+        path: PathBuf,
+        /// Make file entries pointing at the host files instead of copying their data
+        #[arg(short, long)]
+        link: bool,
+        /// Only import files matching this glob, relative to the host directory; may be repeated
+        #[arg(short, long)]
+        include: Vec<String>,
+        /// Leave out files and directories matching this glob; may be repeated
+        #[arg(short, long)]
+        exclude: Vec<String>,
+        /// Report what would be imported without changing anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Write a directory tree out to a host directory
+    Export {
+        /// Directory to export
+        path: PathBuf,
+        /// Host directory to write to, made if it doesn't exist
+        host: PathBuf,
+        /// Run exec files and write their output instead of writing them as scripts
+        #[arg(short, long)]
+        run: bool,
+        /// Keep permission bits and access and modification times
+        #[arg(short = 'm', long)]
+        preserve_metadata: bool,
+    },
+    /// Search the names and contents of text files
+    Search {
+        /// FTS5 query, e.g. 'budget AND 2023' or '"exact phrase"'
+        query: String,
+        /// Maximum number of results
+        #[arg(short, long, default_value_t = 20)]
+        limit: u32,
+    },
+    /// Find files below a directory
+    Find {
+        /// Directory to search from
+        path: PathBuf,
+        /// Name glob, e.g. '*.md'
+        #[arg(long)]
+        name: Option<String>,
+        /// Only files of this type
+        #[arg(long = "type", value_parser = parse_file_type)]
+        ftype: Option<FileType>,
+        /// Minimum size in bytes
+        #[arg(long)]
+        min_size: Option<u64>,
+        /// Maximum size in bytes
+        #[arg(long)]
+        max_size: Option<u64>,
+        /// Modified at or after this time, as unix seconds or an HTTP date
+        #[arg(long, value_parser = parse_time)]
+        modified_after: Option<SystemTime>,
+        /// Modified before this time, as unix seconds or an HTTP date
+        #[arg(long, value_parser = parse_time)]
+        modified_before: Option<SystemTime>,
+        /// Levels to descend; 1 lists only the directory's own files
+        #[arg(long)]
+        max_depth: Option<u32>,
+        #[arg(long, value_enum, default_value_t = Sort::Path)]
+        sort: Sort,
+        /// Sort in descending order
+        #[arg(short, long)]
+        reverse: bool,
+        /// Maximum number of results
+        #[arg(short, long)]
+        limit: Option<u32>,
+    },
+    /// Print changes to the tree as they happen
+    Watch {
+        /// Start after this change number instead of at the latest change
+        #[arg(long)]
+        since: Option<i64>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum Sort {
+    Path,
+    Name,
+    Size,
+    Modified,
+}
+
+impl From<Sort> for SortBy {
+    fn from(sort: Sort) -> SortBy {
+        match sort {
+            Sort::Path => SortBy::Path,
+            Sort::Name => SortBy::Name,
+            Sort::Size => SortBy::Size,
+            Sort::Modified => SortBy::Modified,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -43,9 +164,14 @@ enum FileCommands {
     Exists,
     /// Make a file
     Mk {
+        /// File data, a link target, or a host path to copy from for blob files
         data: String, 
-        #[arg(value_enum)]
-        ftype: FileType 
+        /// text, exec, file, blob, link, or a registered custom type
+        #[arg(value_parser = parse_file_type)]
+        ftype: FileType,
+        /// Encrypt the data with the key in SERVEFS_KEY or SERVEFS_KEY_FILE
+        #[arg(short, long)]
+        encrypt: bool,
     },
     /// Delete file
     Del,
@@ -58,13 +184,42 @@ enum FileCommands {
         directory: PathBuf
     },
     /// Read file
-    Read,
+    Read {
+        /// Read an earlier version instead, as numbered by history
+        #[arg(short, long)]
+        version: Option<i64>,
+    },
     // Write to file
     Write {
+        /// File data, a link target, or a host path to copy from for blob files
         data: String, 
-        #[arg(value_enum)]
+        /// text, exec, file, blob, link, or a registered custom type
+        #[arg(value_parser = parse_file_type)]
         ftype: FileType 
     },
+    /// Show file metadata
+    Stat,
+    /// Set file permission bits
+    Chmod {
+        /// Octal mode, e.g. 644
+        #[arg(value_parser = parse_mode)]
+        mode: u32
+    },
+    /// Set file owner
+    Chown {
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    /// List earlier versions of the file
+    History,
+    /// Make an earlier version the current one
+    Restore {
+        version: i64
+    },
+    /// Encrypt the file's data and history with the key in SERVEFS_KEY or SERVEFS_KEY_FILE
+    Encrypt,
+    /// Store the file's data and history in plaintext again
+    Decrypt,
 }
 
 #[derive(Subcommand, Debug)]
@@ -74,7 +229,11 @@ enum DirCommands {
     /// Make a directory
     Mk,
     /// Delete directory
-    Del,
+    Del {
+        /// Delete the directory and everything inside it
+        #[arg(short, long)]
+        recursive: bool
+    },
     /// Rename directory
     Rn {
         name: String
@@ -88,38 +247,165 @@ enum DirCommands {
         /// Show contents of directory recursively 
         #[arg(short, long)]
         recursive: bool
-    }
+    },
+    /// Show directory metadata
+    Stat,
+    /// Set directory permission bits
+    Chmod {
+        /// Octal mode, e.g. 755
+        #[arg(value_parser = parse_mode)]
+        mode: u32
+    },
+    /// Set directory owner
+    Chown {
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    /// Show the directory's quota and usage
+    Quota,
+    /// Limit what the directory and everything below it may hold
+    SetQuota {
+        /// Maximum total size of files in bytes
+        #[arg(long)]
+        bytes: Option<u64>,
+        /// Maximum number of files and directories
+        #[arg(long)]
+        entries: Option<u64>,
+    },
+    /// Remove the directory's quota
+    DelQuota,
 }
 
-#[derive(ValueEnum, Clone, Debug)]
-enum FileType {
-    Text,
-    Exec,
-    File,
-}
-
-impl From<servefs_lib::FileType> for FileType {
-    fn from(ftype: servefs_lib::FileType) -> Self {
-        match ftype {
-            servefs_lib::FileType::File => FileType::File,
-            servefs_lib::FileType::Text => FileType::Text,
-            servefs_lib::FileType::Exec => FileType::Exec,
-        }
+#[derive(Subcommand, Debug)]
+enum TypeCommands {
+    /// List file types and their handlers
+    List,
+    /// Register a custom file type, or replace its handler
+    Mk {
+        /// Name of the type
+        name: String,
+        /// Bash command that renders a file; it gets the data on stdin and the path in SERVEFS_PATH
+        handler: String,
+    },
+    /// Remove a custom file type
+    Del {
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TrashCommands {
+    /// List deleted files and directories, most recent first
+    List,
+    /// Put an entry back where it was deleted from
+    Restore {
+        id: i64,
+    },
+    /// Permanently delete an entry
+    Purge {
+        id: i64,
+    },
+    /// Permanently delete everything in the trash
+    Empty,
+    /// Show or set how long deleted entries are kept
+    Retention {
+        /// Keep deleted entries for this many days
+        #[arg(long, conflicts_with = "forever")]
+        days: Option<u64>,
+        /// Keep deleted entries until they are purged
+        #[arg(long)]
+        forever: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CompressCommands {
+    /// List compression rules
+    List,
+    /// Compress data written to paths starting with a prefix
+    Set {
+        /// Path prefix, e.g. '/logs/'
+        prefix: String,
+        /// none or zstd
+        #[arg(value_parser = parse_compression)]
+        compression: Compression,
+    },
+    /// Remove the rule for a prefix
+    Remove {
+        prefix: String,
+    },
+    /// Rewrite the stored data below a directory to follow the current rules
+    Run {
+        #[arg(default_value = "/")]
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommands {
+    /// List snapshots
+    List,
+    /// Snapshot the tree as it is now
+    Mk {
+        name: String,
+    },
+    /// Roll the tree back to a snapshot, discarding everything since
+    Restore {
+        name: String,
+    },
+    /// Delete a snapshot
+    Del {
+        name: String,
+    },
+}
+
+fn parse_file_type(ftype: &str) -> Result<FileType, String> {
+    FileType::from_str(ftype).map_err(|_| format!("invalid file type {}", ftype))
+}
+
+fn parse_compression(compression: &str) -> Result<Compression, String> {
+    Compression::from_str(compression).map_err(|_| format!("invalid compression {}", compression))
+}
+
+fn parse_time(time: &str) -> Result<SystemTime, String> {
+    match time.parse::<u64>() {
+        Ok(secs) => Ok(UNIX_EPOCH + Duration::from_secs(secs)),
+        Err(_) => httpdate::parse_http_date(time).map_err(|_| format!("invalid time {}", time)),
     }
 }
 
-impl Into<servefs_lib::FileType> for FileType {
-    fn into(self) -> servefs_lib::FileType {
-        match self {
-            FileType::Text => servefs_lib::FileType::Text,
-            FileType::Exec => servefs_lib::FileType::Exec,
-            FileType::File => servefs_lib::FileType::File,
-        }
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8).map_err(|_| format!("invalid octal mode {}", mode))
+}
+
+fn load_data(data: String, ftype: &FileType) -> Result<Vec<u8>, FSError> {
+    match ftype {
+        FileType::Blob => fs::read(&data).map_err(FSError::Io),
+        _ => Ok(data.into_bytes()),
     }
 }
 
+
+fn print_metadata(meta: &Metadata) {
+    let owner = |id: Option<u32>| id.map(|id| id.to_string()).unwrap_or_else(|| "-".to_string());
+    println!("size: {}", meta.size);
+    println!("mode: {:o}", meta.mode);
+    println!("uid: {}", owner(meta.uid));
+    println!("gid: {}", owner(meta.gid));
+    println!("created: {}", httpdate::fmt_http_date(meta.created));
+    println!("modified: {}", httpdate::fmt_http_date(meta.modified));
+    println!("accessed: {}", httpdate::fmt_http_date(meta.accessed));
+}
+
 #[tokio::main]
-async fn main() -> Result<(), FSError> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("servefs: {}", e);
+        process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), FSError> {
     let default_config_dir = "servefs/";
     let default_db_path_prefix = "sqlite://";
     let default_db_name = "fs.db";
@@ -146,34 +432,87 @@ async fn main() -> Result<(), FSError> {
         None => default_db_prefix.to_string(),
     };
 
-    let fs_conn = FSConnection::new(&db_loc, &db_prefix, true).await.map_err(|e| FSError::SqlX(e))?;
+    let mut fs_conn = FSConnection::new(&db_loc, &db_prefix, true).await?;
+    fs_conn.set_key(EncryptionKey::from_env()?);
 
     match args.command {
         Commands::File { file_command, path } => {
             let mut file = File::new(path)?;
             match file_command {
                 FileCommands::Exists => {
-                    println!("{}", file.exists(&fs_conn).await.map_err(|e| FSError::SqlX(e))?);
+                    println!("{}", file.exists(&fs_conn).await?);
                 }
-                FileCommands::Mk { data, ftype } => {
-                    file.mk(&data, &ftype.into(), &fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                FileCommands::Mk { data, ftype, encrypt: false } => {
+                    let data = load_data(data, &ftype)?;
+                    file.mk_bytes(&data, &ftype, &fs_conn).await?;
+                },
+                FileCommands::Mk { data, ftype, encrypt: true } => {
+                    let data = load_data(data, &ftype)?;
+                    file.mk_encrypted(&data, &ftype, &fs_conn).await?;
                 },
                 FileCommands::Del => {
-                    file.del(&fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                    file.del(&fs_conn).await?;
                 },
                 FileCommands::Rn { name } => {
-                    file.rename(&name, &fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                    file.rename(&name, &fs_conn).await?;
                 },
                 FileCommands::Mv { directory } => {
                     let dir = Directory::new(directory)?;
-                    file.mv(dir, &fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                    file.mv(dir, &fs_conn).await?;
+                },
+                FileCommands::Read { version: None } if file.open(&fs_conn).await? == FileType::Blob => {
+                    let mut stdout = io::stdout();
+                    let mut offset = 0;
+                    loop {
+                        let data = file.read_at(offset, CHUNK_SIZE, &fs_conn).await?;
+                        if data.is_empty() {
+                            break;
+                        }
+                        stdout.write_all(&data).expect("Couldn't write to stdout");
+                        offset += data.len() as u64;
+                    }
+                },
+                FileCommands::Read { version } => {
+                    let (data, ftype) = match version {
+                        Some(version) => file.read_version(version, &fs_conn).await?,
+                        None => file.read_bytes(&fs_conn).await?,
+                    };
+                    if matches!(FileType::from_str(&ftype), Ok(FileType::Blob)) {
+                        io::stdout().write_all(&data).expect("Couldn't write to stdout");
+                    } else {
+                        println!("{}, {}", String::from_utf8_lossy(&data), ftype);
+                    }
                 },
-                FileCommands::Read => {
-                    let (data, ftype) = file.read(&fs_conn).await.map_err(|e| FSError::SqlX(e))?;
-                    println!("{}, {}", data, ftype);
+                FileCommands::Write { data, ftype: FileType::Blob } => {
+                    let blob = tokio::fs::File::open(&data).await.map_err(FSError::Io)?;
+                    file.write_from(blob, FileType::Blob, &fs_conn).await?;
                 },
                 FileCommands::Write { data, ftype } => {
-                    file.write(&data, ftype.into(), &fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                    let data = load_data(data, &ftype)?;
+                    file.write_bytes(&data, ftype, &fs_conn).await?;
+                },
+                FileCommands::Stat => {
+                    print_metadata(&file.metadata(&fs_conn).await?);
+                },
+                FileCommands::Chmod { mode } => {
+                    file.set_mode(mode, &fs_conn).await?;
+                },
+                FileCommands::Chown { uid, gid } => {
+                    file.set_owner(uid, gid, &fs_conn).await?;
+                },
+                FileCommands::History => {
+                    for version in file.history(&fs_conn).await? {
+                        println!("{}\t{}\t{}\t{}", version.version, httpdate::fmt_http_date(version.modified), version.ftype, version.size);
+                    }
+                },
+                FileCommands::Restore { version } => {
+                    file.restore(version, &fs_conn).await?;
+                },
+                FileCommands::Encrypt => {
+                    file.set_encrypted(true, &fs_conn).await?;
+                },
+                FileCommands::Decrypt => {
+                    file.set_encrypted(false, &fs_conn).await?;
                 },
             };
         },
@@ -181,44 +520,231 @@ async fn main() -> Result<(), FSError> {
             let mut dir = Directory::new(path)?;
             match directory_command {
                 DirCommands::Exists => {
-                    dir.exists(&fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                    dir.exists(&fs_conn).await?;
                 },
                 DirCommands::Mk => {
-                    dir.mk(&fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                    dir.mk(&fs_conn).await?;
                 },
-                DirCommands::Del => {
-                    dir.del(&fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                DirCommands::Del { recursive } => {
+                    dir.del(recursive, &fs_conn).await?;
                 },
                 DirCommands::Rn { name } => {
                     let new_path = dir.rename(&name)?;
                     let new_dir = Directory::new(new_path)?;
-                    dir.mv(&new_dir, &fs_conn).await.map_err(|e| FSError::SqlX(e))?;
-                    
+                    dir.mv(&new_dir, &fs_conn).await?;
                 },
                 DirCommands::Mv { directory } => {
                     let new_dir = Directory::new(directory)?;
-                    dir.mv(&new_dir, &fs_conn).await.map_err(|e| FSError::SqlX(e))?;
+                    dir.mv(&new_dir, &fs_conn).await?;
                 }
                 DirCommands::Contents { recursive } => {
                     let (files, dirs) = if recursive {
-                        dir.recurse(&fs_conn).await.map_err(|e| FSError::SqlX(e))?
+                        dir.recurse(&fs_conn).await?
                     } else {
-                        dir.contents(&fs_conn).await.map_err(|e| FSError::SqlX(e))?
+                        dir.contents(&fs_conn).await?
                     };
 
                     let dirs = dirs
-                        .iter()
-                        .map(|row| row.get::<String, &str>("directory"))
-                        .map(|name| name);
+                        .into_iter()
+                        .map(|dir| dir.path);
                     let files = files
-                        .iter()
-                        .map(|row| row.get::<String, &str>("name"))
-                        .map(|name| name);
+                        .into_iter()
+                        .map(|file| file.path);
 
                     dirs.chain(files).for_each(|n| println!("{}", n));
-                }
+                },
+                DirCommands::Stat => {
+                    print_metadata(&dir.metadata(&fs_conn).await?);
+                },
+                DirCommands::Chmod { mode } => {
+                    dir.set_mode(mode, &fs_conn).await?;
+                },
+                DirCommands::Chown { uid, gid } => {
+                    dir.set_owner(uid, gid, &fs_conn).await?;
+                },
+                DirCommands::Quota => {
+                    let quota = dir.quota(&fs_conn).await?.unwrap_or_default();
+                    let usage = dir.usage(&fs_conn).await?;
+                    let limit = |max: Option<u64>| max.map_or("unlimited".to_string(), |max| max.to_string());
+                    println!("bytes: {} / {}", usage.bytes, limit(quota.max_bytes));
+                    println!("entries: {} / {}", usage.entries, limit(quota.max_entries));
+                },
+                DirCommands::SetQuota { bytes, entries } => {
+                    dir.set_quota(&Quota { max_bytes: bytes, max_entries: entries }, &fs_conn).await?;
+                },
+                DirCommands::DelQuota => {
+                    dir.remove_quota(&fs_conn).await?;
+                },
             };
-        }
+        },
+        Commands::Type { type_command } => {
+            match type_command {
+                TypeCommands::List => {
+                    for entry in fs_conn.file_types().await? {
+                        match entry.handler {
+                            Some(handler) => println!("{}: {}", entry.ftype, handler),
+                            None => println!("{}", entry.ftype),
+                        }
+                    }
+                },
+                TypeCommands::Mk { name, handler } => {
+                    let ftype = FileType::from_str(&name)?;
+                    fs_conn.register_file_type(&ftype, &handler).await?;
+                },
+                TypeCommands::Del { name } => {
+                    let ftype = FileType::from_str(&name)?;
+                    fs_conn.unregister_file_type(&ftype).await?;
+                },
+            };
+        },
+        Commands::Snapshot { snapshot_command } => {
+            match snapshot_command {
+                SnapshotCommands::List => {
+                    for snapshot in fs_conn.snapshots().await? {
+                        println!("{}\t{}", snapshot.name, httpdate::fmt_http_date(snapshot.created));
+                    }
+                },
+                SnapshotCommands::Mk { name } => {
+                    fs_conn.create_snapshot(&name).await?;
+                },
+                SnapshotCommands::Restore { name } => {
+                    fs_conn.restore_snapshot(&name).await?;
+                },
+                SnapshotCommands::Del { name } => {
+                    fs_conn.delete_snapshot(&name).await?;
+                },
+            };
+        },
+        Commands::Trash { trash_command } => {
+            match trash_command {
+                TrashCommands::List => {
+                    for entry in fs_conn.trash().await? {
+                        println!("{}\t{}\t{}", entry.id, httpdate::fmt_http_date(entry.deleted), entry.path);
+                    }
+                },
+                TrashCommands::Restore { id } => {
+                    fs_conn.restore_trash(id).await?;
+                },
+                TrashCommands::Purge { id } => {
+                    fs_conn.purge_trash(id).await?;
+                },
+                TrashCommands::Empty => {
+                    fs_conn.empty_trash().await?;
+                },
+                TrashCommands::Retention { days: Some(days), .. } => {
+                    fs_conn.set_trash_retention(Some(Duration::from_secs(days * 24 * 60 * 60))).await?;
+                },
+                TrashCommands::Retention { forever: true, .. } => {
+                    fs_conn.set_trash_retention(None).await?;
+                },
+                TrashCommands::Retention { .. } => {
+                    match fs_conn.trash_retention().await? {
+                        Some(retention) => println!("{} days", retention.as_secs() / (24 * 60 * 60)),
+                        None => println!("forever"),
+                    }
+                },
+            };
+        },
+        Commands::Compress { compress_command } => {
+            match compress_command {
+                CompressCommands::List => {
+                    for rule in fs_conn.compression_rules().await? {
+                        println!("{}\t{}", rule.prefix, rule.compression);
+                    }
+                },
+                CompressCommands::Set { prefix, compression } => {
+                    fs_conn.set_compression(&prefix, compression).await?;
+                },
+                CompressCommands::Remove { prefix } => {
+                    fs_conn.remove_compression(&prefix).await?;
+                },
+                CompressCommands::Run { path } => {
+                    let dir = Directory::new(path)?;
+                    println!("recompressed {}", dir.recompress(&fs_conn).await?);
+                },
+            };
+        },
+        Commands::Find { path, name, ftype, min_size, max_size, modified_after, modified_before, max_depth, sort, reverse, limit } => {
+            let mut find = Find::new().sort(sort.into(), reverse);
+            if let Some(name) = name {
+                find = find.name(&name);
+            }
+            if let Some(ftype) = ftype {
+                find = find.ftype(ftype);
+            }
+            if let Some(size) = min_size {
+                find = find.min_size(size);
+            }
+            if let Some(size) = max_size {
+                find = find.max_size(size);
+            }
+            if let Some(time) = modified_after {
+                find = find.modified_after(time);
+            }
+            if let Some(time) = modified_before {
+                find = find.modified_before(time);
+            }
+            if let Some(depth) = max_depth {
+                find = find.max_depth(depth);
+            }
+            if let Some(limit) = limit {
+                find = find.limit(limit);
+            }
+
+            for file in Directory::new(path)?.find(&find, &fs_conn).await? {
+                println!("{}", file.path);
+            }
+        },
+        Commands::Import { host, path, link, include, exclude, dry_run } => {
+            let mode = if link { ImportMode::Link } else { ImportMode::Copy };
+            let mut import = Import::new().mode(mode).dry_run(dry_run);
+            for glob in &include {
+                import = import.include(glob);
+            }
+            for glob in &exclude {
+                import = import.exclude(glob);
+            }
+            let report = Directory::new(path)?.import(&host, &import, &fs_conn).await?;
+            for dir in &report.dirs {
+                println!("dir\t{}", dir);
+            }
+            for file in &report.files {
+                println!("{}\t{}\t{}", file.ftype, file.path, file.size);
+            }
+            for skipped in &report.skipped {
+                println!("skip\t{}", skipped.display());
+            }
+            let verb = if dry_run { "would import" } else { "imported" };
+            println!("{} {} files and {} directories", verb, report.files.len(), report.dirs.len());
+        },
+        Commands::Export { path, host, run, preserve_metadata } => {
+            let exec = if run { ExportExec::Run } else { ExportExec::Script };
+            let export = Export::new().exec(exec).preserve_metadata(preserve_metadata);
+            let report = Directory::new(path)?.export(&host, &export, &fs_conn).await?;
+            for file in &report.files {
+                println!("{}\t{}\t{}", file.ftype, file.host.display(), file.size);
+            }
+            println!("exported {} files and {} directories", report.files.len(), report.dirs.len());
+        },
+        Commands::Search { query, limit } => {
+            for result in fs_conn.search(&query, limit).await? {
+                println!("{}\t{}", result.path, result.snippet);
+            }
+        },
+        Commands::Watch { since } => {
+            let since = match since {
+                Some(since) => since,
+                None => fs_conn.last_change().await?,
+            };
+            let mut subscription = fs_conn.subscribe(since, WATCH_INTERVAL);
+            loop {
+                let change = subscription.next().await?;
+                match change.old_path {
+                    Some(old_path) => println!("{}\t{}\t{} -> {}", change.seq, change.kind, old_path, change.path),
+                    None => println!("{}\t{}\t{}", change.seq, change.kind, change.path),
+                }
+            }
+        },
     };
     
     Ok(())
@@ -1,14 +1,13 @@
-use std::{time::{Duration, UNIX_EPOCH, Instant}, path::PathBuf, str, str::FromStr, fs, collections::HashMap, process::{Stdio}, os::{unix::prelude::{PermissionsExt}, linux::fs::MetadataExt}, sync::{Mutex}};
+use std::{time::{Duration, UNIX_EPOCH, Instant}, str, fs, collections::HashMap, process::{Stdio}, os::{unix::prelude::{PermissionsExt, OsStrExt}, linux::fs::MetadataExt}, sync::{Mutex}, ffi::OsStr, os::unix::fs::FileExt, path::{Path, PathBuf}};
 use clap::Parser;
 use fuser::{Filesystem, FileAttr, FileType, MountOption, consts::FOPEN_DIRECT_IO};
-use libc::{ENOENT};
+use libc::{ENOENT, EINVAL, EEXIST, ENOTEMPTY, ENOTDIR, EISDIR, ELOOP, EBUSY, EPERM, EIO, EDQUOT, EACCES, ENODATA, ERANGE, XATTR_CREATE, XATTR_REPLACE};
 use rand::{rngs::ThreadRng, Rng};
-use servefs_lib::{FSConnection, Directory, File};
-use sqlx::Row;
+use servefs_lib::{FSConnection, Directory, File, Metadata, FSError, FSType, EncryptionKey};
 use tokio::{runtime::Runtime, io::{BufReader, AsyncBufReadExt}};
 
 const TTL: Duration = Duration::from_secs(1);
-const INODE_SPLIT:u64 = std::u64::MAX / 2;
+const INODE_SPLIT:u64 = u64::MAX / 2;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -17,20 +16,17 @@ struct Args {
    #[arg(short, long)]
    db: Option<String>,
 
+   /// Mount read-write so extended attributes can be set. File data can't be written through the
+   /// mount either way
+   #[arg(short, long)]
+   writable: bool,
+
    #[clap()]
    /// Mount path
    mnt_path: String,
 }
 
 
-fn calc_size(size: usize, offset:usize, data: &Vec<u8>) -> usize {
-    let size = offset as usize + size as usize;
-    if size > data.len() {
-        data.len()
-    } else {
-        size
-    }
-}
 fn file_id_to_ino(id:i64) -> u64 {
     (id as u64) + INODE_SPLIT
 }
@@ -38,7 +34,7 @@ fn file_id_to_ino(id:i64) -> u64 {
 async fn exec(command: &str, rt: &Runtime) -> Vec<u8>{
     if let Ok(mut child) = tokio::process::Command::new("bash")
         .arg("-c")
-        .arg(&command)
+        .arg(command)
         .stdout(Stdio::piped())
         .spawn() {
             let stdout = match child.stdout.take() {
@@ -54,7 +50,7 @@ async fn exec(command: &str, rt: &Runtime) -> Vec<u8>{
 
             while start.elapsed() < Duration::from_secs(1) {
                 if let Ok(Ok(line)) = async {tokio::time::timeout(Duration::from_millis(100), reader.next_line()).await}.await {
-                    if let None = line {
+                    if line.is_none() {
                         break;
                     }
                     buffer.extend(line);
@@ -69,49 +65,124 @@ async fn exec(command: &str, rt: &Runtime) -> Vec<u8>{
         }
 }
 
-fn get_data(data: &str, ftype: &servefs_lib::FileType, rt: &Runtime) -> Vec<u8> {
-    println!("get data {}", data);
+fn get_data(data: &[u8], ftype: &servefs_lib::FileType, file: &File, rt: &Runtime, fs_conn: &FSConnection) -> Vec<u8> {
+    println!("get data {}", String::from_utf8_lossy(data));
     match ftype {
-        servefs_lib::FileType::File => match fs::read(&data) {
+        servefs_lib::FileType::File => match fs::read(OsStr::from_bytes(data)) {
             Ok(file) => file,
             Err(e) => {
                 println!("{:?}", e);
                 vec![0x0]
             },
         },
-        servefs_lib::FileType::Text => data.as_bytes().to_vec(),
-        servefs_lib::FileType::Exec => rt.block_on(exec(&data, rt)),
+        servefs_lib::FileType::Text => data.to_vec(),
+        servefs_lib::FileType::Exec => match str::from_utf8(data) {
+            Ok(command) => rt.block_on(exec(command, rt)),
+            Err(_) => vec![0x0],
+        },
+        servefs_lib::FileType::Blob | servefs_lib::FileType::Link => data.to_vec(),
+        servefs_lib::FileType::Custom(_) => {
+            let path = file.path();
+            match rt.block_on(fs_conn.render_custom(ftype, &path, data)) {
+                Ok(data) => data,
+                Err(e) => {
+                    println!("{:?}", e);
+                    vec![0x0]
+                },
+            }
+        },
+    }
+}
+
+/// Absolute link targets point into the servefs tree, so they're rewritten relative to the link
+/// for the kernel to resolve them inside the mount rather than on the host.
+fn mount_relative_target(file: &File, target: &Path) -> PathBuf {
+    match target.strip_prefix("/") {
+        Ok(rest) => {
+            let depth = file.directory.path.split('/').filter(|name| !name.is_empty()).count();
+            let mut relative: PathBuf = std::iter::repeat_n("..", depth).collect();
+            relative.push(rest);
+            if relative.as_os_str().is_empty() {
+                relative.push(".");
+            }
+            relative
+        },
+        Err(_) => target.to_path_buf(),
+    }
+}
+
+/// How reads through an open file handle are served. Stored data is read a range at a time from
+/// the database and host files are read at an offset, so neither is held in memory; only the
+/// output of exec and custom files, which has to be produced in one go, is kept per handle.
+enum Handle {
+    Stored,
+    Host(fs::File),
+    Rendered(Vec<u8>),
+}
+
+impl Handle {
+    /// Fails if the file can't be opened at all, e.g. when it is encrypted and no key is configured.
+    fn open(file: &File, rt: &Runtime, fs_conn: &FSConnection) -> Result<Handle, FSError> {
+        let ftype = rt.block_on(file.open(fs_conn))?;
+        Ok(match ftype {
+            servefs_lib::FileType::Text | servefs_lib::FileType::Blob | servefs_lib::FileType::Link => Handle::Stored,
+            servefs_lib::FileType::File => match rt.block_on(file.read_bytes(fs_conn)).and_then(|(data, _)| fs::File::open(OsStr::from_bytes(&data)).map_err(FSError::Io)) {
+                Ok(host) => Handle::Host(host),
+                Err(e) => {
+                    println!("{:?}", e);
+                    Handle::Rendered(vec![0x0])
+                },
+            },
+            servefs_lib::FileType::Exec | servefs_lib::FileType::Custom(_) => {
+                let data = rt.block_on(file.read_bytes(fs_conn)).map(|(data, _)| data).unwrap_or_default();
+                Handle::Rendered(get_data(&data, &ftype, file, rt, fs_conn))
+            },
+        })
+    }
+
+    fn read(&self, file: &File, offset: u64, size: usize, rt: &Runtime, fs_conn: &FSConnection) -> Result<Vec<u8>, FSError> {
+        match self {
+            Handle::Stored => rt.block_on(file.read_at(offset, size, fs_conn)),
+            Handle::Host(host) => {
+                let mut data = vec![0; size];
+                let mut filled = 0;
+                while filled < size {
+                    match host.read_at(&mut data[filled..], offset + filled as u64).map_err(FSError::Io)? {
+                        0 => break,
+                        read => filled += read,
+                    }
+                }
+                data.truncate(filled);
+                Ok(data)
+            },
+            Handle::Rendered(data) => {
+                let start = (offset as usize).min(data.len());
+                let end = (start + size).min(data.len());
+                Ok(data[start..end].to_vec())
+            },
+        }
     }
 }
 
 struct Store {
-    store: HashMap<u64, Vec<u8>>,
+    store: HashMap<u64, Handle>,
     rng: ThreadRng,
 }
 
 impl Store {
-    pub fn insert(&mut self, file: &File, rt: &Runtime, fs_conn: &FSConnection) -> u64 {
-        let data = rt.block_on(file.read(fs_conn))
-            .map(|(data, ftype)| {
-                servefs_lib::FileType::from_str(&ftype)
-                    .map(|ftype| get_data(&data, &ftype, rt))
-                    .unwrap_or(vec![0x0])
-            }).unwrap_or(vec![0x0]);
+    pub fn insert(&mut self, file: &File, rt: &Runtime, fs_conn: &FSConnection) -> Result<u64, FSError> {
+        let handle = Handle::open(file, rt, fs_conn)?;
         let mut fh = self.rng.gen::<u64>();
         while self.store.contains_key(&fh) {
             fh = self.rng.gen();
         }
-        self.store.insert(fh, data);
-        println!("insert {} into {}", rt.block_on(file.get_id(&fs_conn)).unwrap_or(-1), fh);
-        fh
+        self.store.insert(fh, handle);
+        println!("insert {} into {}", rt.block_on(file.get_id(fs_conn)).unwrap_or(-1), fh);
+        Ok(fh)
     }
 
-    pub fn get(&self, fh: &u64) -> Option<&Vec<u8>> {
-        if let Some(data) = self.store.get(&fh){
-            return Some(data);
-        }
-
-        return None
+    pub fn get(&self, fh: &u64) -> Option<&Handle> {
+        self.store.get(fh)
     }
 
     #[allow(dead_code)]
@@ -132,17 +203,20 @@ struct ServeFS {
 
 impl ServeFS {
     fn create_file_attr(&self, ino: u64, size: u64, file: &File) -> FileAttr {
-        let (data, ftype) = self.rt.block_on(file.read(&self.fs_conn))
-            .map(|(data, ftype)| {
-                servefs_lib::FileType::from_str(&ftype)
-                    .map(|ftype| (data, ftype))
-                    .unwrap_or(("".to_string(), servefs_lib::FileType::Exec))
-            }).unwrap_or(("".to_string(), servefs_lib::FileType::Exec));
-        
-        match ftype {
-            servefs_lib::FileType::File =>if let Ok(meta) = fs::File::open(&data).and_then(|file| file.metadata()) {
+        let (ftype, meta) = match self.rt.block_on(file.stat(&self.fs_conn)) {
+            Ok(stat) => stat,
+            Err(e) => {
+                println!("{:?}", e);
+                return ServeFS::create_attr(ino, FileType::RegularFile, size, &ServeFS::default_metadata(0o644));
+            },
+        };
+
+        // Host files report the metadata of the file they point at.
+        if ftype == servefs_lib::FileType::File {
+            let data = self.rt.block_on(file.read_at(0, meta.size as usize, &self.fs_conn)).unwrap_or_default();
+            if let Ok(meta) = fs::File::open(OsStr::from_bytes(&data)).and_then(|file| file.metadata()) {
                 return FileAttr{
-                    ino: ino,
+                    ino,
                     size: meta.st_size(),
                     blocks: 1,
                     atime: meta.accessed().unwrap_or(UNIX_EPOCH),
@@ -159,68 +233,123 @@ impl ServeFS {
                     blksize: 512,
                     padding: 0,
                 };
-            },
-            servefs_lib::FileType::Text => return FileAttr{
-                ino: ino,
-                size: data.len() as u64,
-                blocks: 1,
-                atime: UNIX_EPOCH,
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: FileType::RegularFile,
-                perm: 0o644,
-                nlink: 1,
-                uid: unsafe {libc::geteuid() as u32},
-                gid: unsafe {libc::getegid() as u32},
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-                padding: 0,
-            },
-            servefs_lib::FileType::Exec => (),
+            }
         }
-        FileAttr{
-            ino: ino,
-            size: size,
-            blocks: 1,
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
-            kind: FileType::RegularFile,
-            perm: 0o644,
-            nlink: 1,
-            uid: unsafe {libc::geteuid() as u32},
-            gid: unsafe {libc::getegid() as u32},
-            rdev: 0,
-            flags: 0,
-            blksize: 512,
-            padding: 0,
+        let kind = match ftype {
+            servefs_lib::FileType::Link => FileType::Symlink,
+            _ => FileType::RegularFile,
+        };
+        let size = match ftype {
+            servefs_lib::FileType::Exec => size,
+            _ => meta.size,
+        };
+        ServeFS::create_attr(ino, kind, size, &meta)
+    }
+
+    fn create_dir_attr(&self, ino: u64, dir: &Directory) -> FileAttr {
+        let meta = self.rt.block_on(dir.metadata(&self.fs_conn))
+            .unwrap_or_else(|_| ServeFS::default_metadata(0o755));
+        ServeFS::create_attr(ino, FileType::Directory, 0, &meta)
+    }
+
+    fn entry(&self, ino: u64) -> Result<FSType, FSError> {
+        if ino >= INODE_SPLIT {
+            self.rt.block_on(File::from_id((ino - INODE_SPLIT) as i64, &self.fs_conn)).map(FSType::File)
+        } else {
+            self.rt.block_on(Directory::from_id(ino as i64, &self.fs_conn)).map(FSType::Directory)
         }
     }
-    
-    fn create_dir_attr(&self, ino: u64) -> FileAttr {
-        FileAttr{
-            ino: ino,
+
+    fn get_xattr(&self, ino: u64, name: &str) -> Result<Option<Vec<u8>>, FSError> {
+        match self.entry(ino)? {
+            FSType::File(file) => self.rt.block_on(file.attr(name, &self.fs_conn)),
+            FSType::Directory(dir) => self.rt.block_on(dir.attr(name, &self.fs_conn)),
+        }
+    }
+
+    fn set_xattr(&self, ino: u64, name: &str, value: &[u8]) -> Result<(), FSError> {
+        match self.entry(ino)? {
+            FSType::File(file) => self.rt.block_on(file.set_attr(name, value, &self.fs_conn)),
+            FSType::Directory(dir) => self.rt.block_on(dir.set_attr(name, value, &self.fs_conn)),
+        }
+    }
+
+    fn list_xattr(&self, ino: u64) -> Result<Vec<String>, FSError> {
+        let attrs = match self.entry(ino)? {
+            FSType::File(file) => self.rt.block_on(file.attrs(&self.fs_conn)),
+            FSType::Directory(dir) => self.rt.block_on(dir.attrs(&self.fs_conn)),
+        }?;
+        Ok(attrs.into_keys().collect())
+    }
+
+    fn remove_xattr(&self, ino: u64, name: &str) -> Result<(), FSError> {
+        match self.entry(ino)? {
+            FSType::File(file) => self.rt.block_on(file.remove_attr(name, &self.fs_conn)),
+            FSType::Directory(dir) => self.rt.block_on(dir.remove_attr(name, &self.fs_conn)),
+        }
+    }
+
+    fn default_metadata(mode: u32) -> Metadata {
+        Metadata {
+            created: UNIX_EPOCH,
+            modified: UNIX_EPOCH,
+            accessed: UNIX_EPOCH,
+            mode,
+            uid: None,
+            gid: None,
             size: 0,
-            blocks: 0,
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
-            crtime: UNIX_EPOCH,
-            kind: FileType::Directory,
-            perm: 0o755,
-            nlink: 2,
-            uid: unsafe {libc::geteuid() as u32},
-            gid: unsafe {libc::getegid() as u32},
+        }
+    }
+
+    /// Files and directories without an owner are reported as owned by whoever mounted the fs.
+    fn create_attr(ino: u64, kind: FileType, size: u64, meta: &Metadata) -> FileAttr {
+        FileAttr{
+            ino,
+            size,
+            blocks: if kind == FileType::Directory {0} else {1},
+            atime: meta.accessed,
+            mtime: meta.modified,
+            ctime: meta.modified,
+            crtime: meta.created,
+            kind,
+            perm: meta.mode as u16,
+            nlink: if kind == FileType::Directory {2} else {1},
+            uid: meta.uid.unwrap_or_else(|| unsafe {libc::geteuid() as u32}),
+            gid: meta.gid.unwrap_or_else(|| unsafe {libc::getegid() as u32}),
             rdev: 0,
             flags: 0,
             blksize: 512,
             padding: 0,
         }
     }
-    
+}
+
+/// Answers an xattr request: a `size` of 0 asks for the length, otherwise the data must fit.
+fn reply_xattr(data: &[u8], size: u32, reply: fuser::ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+fn errno(e: &FSError) -> i32 {
+    match e {
+        FSError::NotFound(_) => ENOENT,
+        FSError::AlreadyExists(_) => EEXIST,
+        FSError::NotEmpty(_) => ENOTEMPTY,
+        FSError::PathIsNotADir(_) => ENOTDIR,
+        FSError::PathIsNotAFile(_) => EISDIR,
+        FSError::NotALink(_) | FSError::MoveIntoSelf(_) | FSError::InvalidType(_) | FSError::InvalidData(_) => EINVAL,
+        FSError::LinkLoop(_) => ELOOP,
+        FSError::CannotDeleteRoot | FSError::TypeInUse(_) | FSError::NestedTransaction => EBUSY,
+        FSError::BuiltinType(_) => EPERM,
+        FSError::QuotaExceeded(_) => EDQUOT,
+        FSError::KeyRequired | FSError::InvalidKey(_) => EACCES,
+        _ => EIO,
+    }
 }
 
 impl Filesystem for ServeFS {
@@ -238,44 +367,44 @@ impl Filesystem for ServeFS {
                             return;
                         },
                     };
-                    let file = parent.file(name);
-                    
-                    if self.rt.block_on(file.exists(&self.fs_conn)).unwrap_or(false) {
-                        let id = match self.rt.block_on(file.get_id(&self.fs_conn)) {
-                            Ok(id) => id,
-                            Err(e) => {
-                                println!("{:?}", e);
-                                reply.error(ENOENT);
-                                return;
-                            }
-                        };
-                        reply.entry(
-                            &TTL, 
-                            &self.create_file_attr(file_id_to_ino(id), 1, &file), 
-                            0);
-                    } else {
-                        if let Ok(dir) = parent.dir(&name) {
-                            if self.rt.block_on(dir.exists(&self.fs_conn)).unwrap_or(false) {
-                                let id = match self.rt.block_on(dir.get_id(&self.fs_conn)) {
-                                    Ok(id) => id,
-                                    Err(e) => {
-                                        println!("{:?}", e);
-                                        reply.error(ENOENT);
-                                        return;
-                                    }
-                                };
-        
-                                reply.entry(
-                                    &TTL, 
-                                    &self.create_dir_attr(id as u64), 
-                                    0);
-                            }
-                        }
+                    // The parent's id is cached, so each candidate costs a single lookup.
+                    let mut file = parent.file(name);
+                    match self.rt.block_on(file.resolve(&self.fs_conn)) {
+                        Ok(id) => {
+                            reply.entry(
+                                &TTL, 
+                                &self.create_file_attr(file_id_to_ino(id), 1, &file), 
+                                0);
+                            return;
+                        },
+                        Err(FSError::NotFound(_)) => (),
+                        Err(e) => {
+                            println!("{:?}", e);
+                            reply.error(errno(&e));
+                            return;
+                        },
+                    }
+
+                    let mut dir = match parent.dir(name) {
+                        Ok(dir) => dir,
+                        Err(_) => {
+                            reply.error(ENOENT);
+                            return;
+                        },
+                    };
+                    match self.rt.block_on(dir.resolve(&self.fs_conn)) {
+                        Ok(id) => {
+                            reply.entry(
+                                &TTL, 
+                                &self.create_dir_attr(id as u64, &dir), 
+                                0);
+                        },
+                        Err(e) => reply.error(errno(&e)),
                     }
                 },
                 Err(e) => {
                     println!("{:?}", e);
-                    reply.error(ENOENT)
+                    reply.error(errno(&e))
                 },
             }
         }
@@ -291,17 +420,17 @@ impl Filesystem for ServeFS {
                         &self.create_file_attr(ino, 1, &file));
                 }
                 Err(e) => {println!("{:?}", e);
-                reply.error(ENOENT)},
+                reply.error(errno(&e))},
             }
         } else {
             match self.rt.block_on(Directory::from_id(ino as i64, &self.fs_conn)) {
-                Ok(_) => {
+                Ok(dir) => {
                     reply.attr(
                         &TTL, 
-                        &self.create_dir_attr(ino));
+                        &self.create_dir_attr(ino, &dir));
                 }
                 Err(e) => {println!("{:?}", e);
-                reply.error(ENOENT)},
+                reply.error(errno(&e))},
             }
         }
     }
@@ -314,7 +443,7 @@ impl Filesystem for ServeFS {
                     reply.ok()
                 }
                 Err(e) => {println!("{:?}", e);
-                    reply.error(ENOENT)},
+                    reply.error(errno(&e))},
             }
         } else {
             match self.rt.block_on(Directory::from_id(ino as i64, &self.fs_conn)) {
@@ -322,23 +451,133 @@ impl Filesystem for ServeFS {
                     reply.ok()
                 }
                 Err(e) => {println!("{:?}", e);
-                    reply.error(ENOENT)},
+                    reply.error(errno(&e))},
             }
         }
     }
 
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        if ino < INODE_SPLIT {
+            reply.error(EINVAL);
+            return;
+        }
+        let ino = ino - INODE_SPLIT;
+        let target = self.rt.block_on(async {
+            let file = File::from_id(ino as i64, &self.fs_conn).await?;
+            let target = file.read_link(&self.fs_conn).await?;
+            Ok::<PathBuf, FSError>(mount_relative_target(&file, &target))
+        });
+        match target {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(e) => {
+                println!("{:?}", e);
+                reply.error(errno(&e))
+            },
+        }
+    }
+
+    fn getxattr(&mut self, _req: &fuser::Request<'_>, ino: u64, name: &OsStr, size: u32, reply: fuser::ReplyXattr) {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(ENODATA);
+                return;
+            },
+        };
+        match self.get_xattr(ino, name) {
+            Ok(Some(value)) => reply_xattr(&value, size, reply),
+            Ok(None) => reply.error(ENODATA),
+            Err(e) => {
+                println!("{:?}", e);
+                reply.error(errno(&e))
+            },
+        }
+    }
+
+    fn listxattr(&mut self, _req: &fuser::Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        match self.list_xattr(ino) {
+            Ok(names) => {
+                let mut data = vec![];
+                for name in names {
+                    data.extend_from_slice(name.as_bytes());
+                    data.push(0);
+                }
+                reply_xattr(&data, size, reply)
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                reply.error(errno(&e))
+            },
+        }
+    }
+
+    fn setxattr(&mut self, _req: &fuser::Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, _position: u32, reply: fuser::ReplyEmpty) {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(EINVAL);
+                return;
+            },
+        };
+        if flags & (XATTR_CREATE | XATTR_REPLACE) != 0 {
+            let error = match self.get_xattr(ino, name) {
+                Ok(Some(_)) if flags & XATTR_CREATE != 0 => Some(EEXIST),
+                Ok(None) if flags & XATTR_REPLACE != 0 => Some(ENODATA),
+                Ok(_) => None,
+                Err(e) => {
+                    println!("{:?}", e);
+                    Some(errno(&e))
+                },
+            };
+            if let Some(error) = error {
+                reply.error(error);
+                return;
+            }
+        }
+        match self.set_xattr(ino, name, value) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                println!("{:?}", e);
+                reply.error(errno(&e))
+            },
+        }
+    }
+
+    fn removexattr(&mut self, _req: &fuser::Request<'_>, ino: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(ENODATA);
+                return;
+            },
+        };
+        if let Err(e) = self.entry(ino) {
+            println!("{:?}", e);
+            reply.error(errno(&e));
+            return;
+        }
+        match self.remove_xattr(ino, name) {
+            Ok(()) => reply.ok(),
+            // The entry exists, so a missing row means the attribute isn't set.
+            Err(FSError::NotFound(_)) => reply.error(ENODATA),
+            Err(e) => {
+                println!("{:?}", e);
+                reply.error(errno(&e))
+            },
+        }
+    }
+
     fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
         if ino >=  INODE_SPLIT {
             let ino = ino - INODE_SPLIT;
-            match self.rt.block_on(File::from_id(ino as i64, &self.fs_conn)) {
-                Ok(file) => {
-                    let fh = self.store.lock().unwrap().insert( &file, &self.rt, &self.fs_conn);
+            match self.rt.block_on(File::from_id(ino as i64, &self.fs_conn)).and_then(|file| self.store.lock().unwrap().insert(&file, &self.rt, &self.fs_conn)) {
+                Ok(fh) => {
                     println!("created fh {}", fh);
                     reply.opened(fh, FOPEN_DIRECT_IO);
                 },
                 Err(e) => {
                     println!("{:?}", e);
-                    reply.error(ENOENT)
+                    reply.error(errno(&e))
                 },
             }
         } else {
@@ -361,28 +600,33 @@ impl Filesystem for ServeFS {
             let ino = ino - INODE_SPLIT;
             match self.rt.block_on(File::from_id(ino as i64, &self.fs_conn)) {
                 Ok(file) => {
-                    let empty = vec![0x0];
-                    let mut tmp = vec![];
                     let store = self.store.lock().unwrap();
-                    let data = store.get(&fh).or_else(|| {
-                        self.rt.block_on(file.read(&self.fs_conn)).map(|(data_str, ftype)| {
-                            servefs_lib::FileType::from_str(&ftype).map(|ftype| {
-                                tmp = get_data(&data_str, &ftype, &self.rt);
+                    let tmp;
+                    let handle = match store.get(&fh) {
+                        Some(handle) => handle,
+                        None => match Handle::open(&file, &self.rt, &self.fs_conn) {
+                            Ok(handle) => {
+                                tmp = handle;
                                 &tmp
-                            }).unwrap_or(&empty)
-                        }).ok()
-                    }).unwrap_or(&empty);
-                    let size = calc_size(size as usize, offset as usize, data);
-                    if offset as usize > data.len() {
-                        reply.data(&vec![]);
-                        return;
-                    }
+                            },
+                            Err(e) => {
+                                println!("{:?}", e);
+                                return reply.error(errno(&e));
+                            },
+                        },
+                    };
                     println!("read {} {} {} {}", fh, ino, offset, size);
-                    reply.data(&data[offset as usize..size]);
+                    match handle.read(&file, offset as u64, size as usize, &self.rt, &self.fs_conn) {
+                        Ok(data) => reply.data(&data),
+                        Err(e) => {
+                            println!("{:?}", e);
+                            reply.error(errno(&e))
+                        },
+                    }
                 },
                 Err(e) => {
                     println!("{:?}", e);
-                    reply.error(ENOENT)
+                    reply.error(errno(&e))
                 },
             }
         } else {
@@ -426,20 +670,17 @@ impl Filesystem for ServeFS {
         ];
 
         if let Ok(dir) = self.rt.block_on(Directory::from_id(ino as i64, &self.fs_conn)) { 
-            if let Ok((file, dirs)) = self.rt.block_on(dir.contents(&self.fs_conn)) {
-                entries.extend(file.iter()
-                    .map(|row| 
-                        (
-                            file_id_to_ino(row.get::<i64, &str>("id")), 
-                            FileType::RegularFile, row.get::<String, &str>("name")
-                        )
-                    ).collect::<Vec<(u64, FileType, String)>>());
-                entries.extend(dirs.iter()
-                    .filter_map(|row| {
-                        let path = PathBuf::from_str(&row.get::<String, &str>("directory")).ok()?;
-                        let name = path.components().last()?.as_os_str().to_string_lossy().to_string();
-                        Some((row.get::<i64, &str>("id") as u64, FileType::Directory, name))
-                    }).collect::<Vec<(u64, FileType, String)>>());
+            if let Ok((files, dirs)) = self.rt.block_on(dir.contents(&self.fs_conn)) {
+                entries.extend(files.into_iter()
+                    .map(|file| {
+                        let kind = match file.ftype {
+                            servefs_lib::FileType::Link => FileType::Symlink,
+                            _ => FileType::RegularFile,
+                        };
+                        (file_id_to_ino(file.id), kind, file.name)
+                    }));
+                entries.extend(dirs.into_iter()
+                    .map(|dir| (dir.id as u64, FileType::Directory, dir.name)));
             }
         }
 
@@ -471,16 +712,26 @@ fn main() {
             format!("{}{}", default_db_path_prefix, db_loc)
         },
     };
-    let options = vec![
-        MountOption::RO,
+    let mut options = vec![
         MountOption::FSName("servefs".to_string()), 
         MountOption::AutoUnmount, 
         MountOption::Exec,
         MountOption::Async,
         MountOption::NoAtime,
     ];
+    if !args.writable {
+        options.push(MountOption::RO);
+    }
     let rt = Runtime::new().unwrap();
-    let fs_conn =  rt.block_on(FSConnection::new(&db_loc, "servefs_", true)).unwrap();
+    let mut fs_conn =  rt.block_on(FSConnection::new(&db_loc, "servefs_", true)).unwrap();
+    let key = match EncryptionKey::from_env() {
+        Ok(key) => key,
+        Err(e) => {
+            println!("Key error: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+    fs_conn.set_key(key);
     let servefs = ServeFS{ fs_conn, rt, store: Mutex::new(Store { store: HashMap::new(), rng: rand::thread_rng() }) };
     fuser::mount2(servefs, args.mnt_path, &options).unwrap();
 }
@@ -1,8 +1,18 @@
 
 
-use std::{str::FromStr, path::{PathBuf}};
-use sqlx::{SqlitePool, sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteRow}, QueryBuilder, pool::PoolConnection, Sqlite, Row, ConnectOptions};
+use std::{borrow::Cow, collections::{VecDeque, BTreeMap, HashSet}, process::Stdio, str::FromStr, path::{PathBuf, Path}, ops::{Deref, DerefMut}, fmt::{self, Display, Formatter}, time::{SystemTime, UNIX_EPOCH, Duration}, fs::FileTimes, os::unix::fs::PermissionsExt};
+use sqlx::{SqlitePool, sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteRow}, QueryBuilder, SqliteConnection, Row, ConnectOptions, Connection, Transaction, Sqlite, pool::PoolConnection};
+use tokio::{sync::{Mutex, MutexGuard}, io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, process::Command};
 use path_absolutize::*;
+use sha2::{Sha256, Digest};
+use hmac::{Hmac, Mac};
+use hkdf::Hkdf;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key, KeyInit, aead::{Aead, AeadCore, OsRng, Payload}};
+
+mod migrations;
+
+pub use migrations::SCHEMA_VERSION;
 
 pub enum FSType {
     File(File),
@@ -13,23 +23,601 @@ pub enum FSType {
 pub enum FSError {
     PathIsNotAFile(String),
     PathIsNotADir(String),
-    DoesNotExist(String),
+    NotFound(String),
+    AlreadyExists(String),
+    NotEmpty(String),
     InvalidType(String),
-    SqlX(sqlx::Error),
+    /// The stored data couldn't be read as requested, e.g. text that isn't UTF-8.
+    InvalidData(String),
+    SchemaTooNew(i64),
+    MigrationFailed(String),
+    NestedTransaction,
+    CannotDeleteRoot,
+    MoveIntoSelf(String),
+    NotALink(String),
+    LinkLoop(String),
+    BuiltinType(String),
+    TypeInUse(String),
+    HandlerFailed(String),
+    /// A search query that FTS5 couldn't parse.
+    InvalidQuery(String),
+    /// An include or exclude glob that couldn't be parsed.
+    InvalidGlob(String),
+    /// The change would take the directory at this path over its quota.
+    QuotaExceeded(String),
+    /// The data is encrypted and the connection has no key, see [`FSConnection::set_key`].
+    KeyRequired,
+    /// A key that couldn't be parsed, or encrypted data the key doesn't open.
+    InvalidKey(String),
+    /// Reading the data to store failed.
+    Io(std::io::Error),
+    /// Any other failure of the underlying database.
+    Database(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl FSError {
+    pub(crate) fn database(e: sqlx::Error) -> FSError {
+        FSError::Database(Box::new(e))
+    }
+
+    /// Reads "no row" as `path` not existing and a uniqueness violation as it already existing.
+    fn from_sqlx(e: sqlx::Error, path: &str) -> FSError {
+        match e {
+            sqlx::Error::RowNotFound => FSError::NotFound(path.to_string()),
+            sqlx::Error::Database(ref db) if matches!(db.code().as_deref(), Some(SQLITE_CONSTRAINT_PRIMARYKEY) | Some(SQLITE_CONSTRAINT_UNIQUE)) => {
+                FSError::AlreadyExists(path.to_string())
+            },
+            e => FSError::database(e),
+        }
+    }
+
+    /// Like [`FSError::from_sqlx`], for writes that set a file's type: the only foreign key such a
+    /// write can break is the one requiring `ftype` to be registered.
+    fn from_sqlx_typed(e: sqlx::Error, path: &str, ftype: &FileType) -> FSError {
+        match e {
+            sqlx::Error::Database(ref db) if db.code().as_deref() == Some(SQLITE_CONSTRAINT_FOREIGNKEY) => {
+                FSError::InvalidType(ftype.to_string())
+            },
+            e => FSError::from_sqlx(e, path),
+        }
+    }
+}
+
+impl Display for FSError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FSError::PathIsNotAFile(path) => write!(f, "{} is not a file", path),
+            FSError::PathIsNotADir(path) => write!(f, "{} is not a directory", path),
+            FSError::NotFound(path) => write!(f, "{} does not exist", path),
+            FSError::AlreadyExists(path) => write!(f, "{} already exists", path),
+            FSError::NotEmpty(path) => write!(f, "{} is not empty", path),
+            FSError::InvalidType(ftype) => write!(f, "{} is not a known file type", ftype),
+            FSError::InvalidData(path) => write!(f, "{} can't be read as text", path),
+            FSError::SchemaTooNew(version) => write!(f, "database schema version {} is newer than this build supports ({})", version, SCHEMA_VERSION),
+            FSError::MigrationFailed(reason) => write!(f, "database migration failed: {}", reason),
+            FSError::NestedTransaction => write!(f, "a transaction is already open"),
+            FSError::CannotDeleteRoot => write!(f, "the root directory can't be deleted"),
+            FSError::MoveIntoSelf(path) => write!(f, "can't move a directory into itself: {}", path),
+            FSError::NotALink(path) => write!(f, "{} is not a link", path),
+            FSError::LinkLoop(path) => write!(f, "too many levels of links resolving {}", path),
+            FSError::BuiltinType(ftype) => write!(f, "{} is a built-in file type", ftype),
+            FSError::TypeInUse(ftype) => write!(f, "file type {} is still in use", ftype),
+            FSError::HandlerFailed(reason) => write!(f, "file type handler failed: {}", reason),
+            FSError::InvalidQuery(reason) => write!(f, "invalid search query: {}", reason),
+            FSError::InvalidGlob(reason) => write!(f, "invalid glob: {}", reason),
+            FSError::QuotaExceeded(path) => write!(f, "quota exceeded for {}", path),
+            FSError::KeyRequired => write!(f, "the data is encrypted and no key is configured"),
+            FSError::InvalidKey(reason) => write!(f, "invalid encryption key: {}", reason),
+            FSError::Io(e) => write!(f, "i/o error: {}", e),
+            FSError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FSError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FSError::Io(e) => Some(e),
+            FSError::Database(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+const SQLITE_CONSTRAINT_PRIMARYKEY: &str = "1555";
+const SQLITE_CONSTRAINT_UNIQUE: &str = "2067";
+const SQLITE_CONSTRAINT_FOREIGNKEY: &str = "787";
+
+/// Attaches the path being operated on to database errors.
+trait AtPath<T> {
+    fn at(self, path: &str) -> Result<T, FSError>;
+}
+
+impl<T> AtPath<T> for Result<T, sqlx::Error> {
+    fn at(self, path: &str) -> Result<T, FSError> {
+        self.map_err(|e| FSError::from_sqlx(e, path))
+    }
+}
+
+/// Commits `tx` if `result` is ok and rolls it back otherwise. The rollback has to be explicit: a
+/// dropped transaction is only rolled back in the background, holding the write lock until then.
+async fn finish<T>(tx: Transaction<'_, Sqlite>, result: Result<T, FSError>) -> Result<T, FSError> {
+    match result {
+        Ok(value) => {
+            tx.commit().await.map_err(FSError::database)?;
+            Ok(value)
+        },
+        Err(e) => {
+            tx.rollback().await.map_err(FSError::database)?;
+            Err(e)
+        },
+    }
+}
+
+/// Fails with [`FSError::NotFound`] when an update or delete matched nothing.
+fn affected(result: sqlx::sqlite::SqliteQueryResult, path: &str) -> Result<(), FSError> {
+    if result.rows_affected() == 0 {
+        return Err(FSError::NotFound(path.to_string()));
+    }
+    Ok(())
+}
+
+/// Key of `data` in the content table: its SHA-256 as lowercase hex.
+pub(crate) fn content_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Stored data is split into chunks of this many bytes, so reading part of a file only loads the
+/// chunks that cover it.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Hash of content that is still being written, see [`PendingContent`].
+const PENDING_CONTENT: &str = "pending";
+
+/// Prefix of the keys of content patched by [`File::write_at`]. Those hash the content they were
+/// patched from and the patch rather than the data, which would mean reading all of it; the prefix
+/// keeps them apart from hashes of data.
+const PATCHED_CONTENT: &str = "patched-";
+
+/// How a content row's chunks are stored: compressed first, then sealed with the connection's
+/// [`EncryptionKey`] if the content is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct ContentFormat {
+    compression: Compression,
+    encrypted: bool,
+}
+
+impl ContentFormat {
+    /// Reads the `compression` and `encrypted` columns of a content row.
+    fn from_row(row: &SqliteRow) -> Result<ContentFormat, FSError> {
+        Ok(ContentFormat {
+            compression: Compression::from_column(row.get("compression"))?,
+            encrypted: row.get("encrypted"),
+        })
+    }
+
+    /// Hashes data into its key in the content table. Encrypted data gets an HMAC under a key derived
+    /// from the encryption key, so the database never holds a plain hash of it and it is never shared
+    /// with plaintext content.
+    fn hasher(&self, fs_conn: &FSConnection) -> Result<ContentHasher, FSError> {
+        if self.encrypted {
+            Ok(ContentHasher::Keyed(fs_conn.key()?.hmac()))
+        } else {
+            Ok(ContentHasher::Plain(Sha256::new()))
+        }
+    }
+
+    /// Encodes chunk `seq` of the content row with id `content`.
+    fn encode<'a>(&self, content: i64, seq: i64, data: &'a [u8], fs_conn: &FSConnection) -> Result<Cow<'a, [u8]>, FSError> {
+        let data = self.compression.encode(data)?;
+        if self.encrypted {
+            Ok(Cow::Owned(fs_conn.key()?.seal(content, seq, &data)?))
+        } else {
+            Ok(data)
+        }
+    }
+
+    fn decode(&self, content: i64, seq: i64, data: Vec<u8>, fs_conn: &FSConnection) -> Result<Vec<u8>, FSError> {
+        let data = if self.encrypted {
+            fs_conn.key()?.open(content, seq, &data)?
+        } else {
+            data
+        };
+        self.compression.decode(data)
+    }
+}
+
+/// Hash of a content row's data, see [`ContentFormat::hasher`].
+enum ContentHasher {
+    Plain(Sha256),
+    Keyed(Hmac<Sha256>),
+}
+
+impl ContentHasher {
+    fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            ContentHasher::Plain(hasher) => Digest::update(hasher, data),
+            ContentHasher::Keyed(hasher) => Mac::update(hasher, data.as_ref()),
+        }
+    }
+
+    fn chain_update(mut self, data: impl AsRef<[u8]>) -> ContentHasher {
+        self.update(data);
+        self
+    }
+
+    /// The hash as hex digits.
+    fn finalize(self) -> String {
+        match self {
+            ContentHasher::Plain(hasher) => hex::encode(hasher.finalize()),
+            ContentHasher::Keyed(hasher) => hex::encode(hasher.finalize().into_bytes()),
+        }
+    }
+}
+
+/// Adds a row to the content table and returns its id, or `None` if `hash` is already stored.
+async fn insert_content(hash: &str, size: i64, format: ContentFormat, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Option<i64>, sqlx::Error> {
+    Ok(QueryBuilder::new(format!("INSERT INTO {}(hash, size, compression, encrypted) VALUES(", fs_conn.content_table))
+        .push_bind(hash)
+        .push(",")
+        .push_bind(size)
+        .push(",")
+        .push_bind(format.compression.column())
+        .push(",")
+        .push_bind(format.encrypted)
+        .push(") ON CONFLICT(hash) DO NOTHING RETURNING id")
+        .build()
+        .fetch_optional(conn)
+        .await?
+        .map(|row| row.get("id")))
+}
+
+/// Stores chunk `seq` of a content row, replacing the chunk already there if any.
+async fn insert_chunk(content: i64, seq: i64, data: &[u8], conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!("INSERT INTO {}(content, seq, data) VALUES(", fs_conn.chunk_table))
+        .push_bind(content)
+        .push(",")
+        .push_bind(seq)
+        .push(",")
+        .push_bind(data)
+        .push(") ON CONFLICT(content, seq) DO UPDATE SET data=excluded.data")
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Stores `data` in the content table unless identical data is already there, and returns the hash
+/// a file row should refer to. Reference counts are kept by triggers on the file table. Data that is
+/// already stored keeps the compression it was stored with. Data patched by [`File::write_at`] is
+/// keyed by the patch instead, so it is never found here.
+async fn store_content(data: &[u8], format: ContentFormat, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<String, FSError> {
+    let hash = format.hasher(fs_conn)?.chain_update(data).finalize();
+    if let Some(id) = insert_content(&hash, data.len() as i64, format, conn, fs_conn).await.map_err(FSError::database)? {
+        for (seq, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            let seq = seq as i64;
+            insert_chunk(id, seq, &format.encode(id, seq, chunk, fs_conn)?, conn, fs_conn).await.map_err(FSError::database)?;
+        }
+    }
+    Ok(hash)
+}
+
+/// Like [`store_content`], with the data read from `reader` and stored a chunk at a time. Returns
+/// the hash and the size.
+async fn stream_content<R: AsyncRead + Unpin + Send>(mut reader: R, format: ContentFormat, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(String, i64), FSError> {
+    let mut pending = PendingContent::new(format, conn, fs_conn).await?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let mut filled = 0;
+        while filled < CHUNK_SIZE {
+            match reader.read(&mut buffer[filled..]).await.map_err(FSError::Io)? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if filled > 0 {
+            pending.push(&buffer[..filled], conn, fs_conn).await?;
+        }
+        if filled < CHUNK_SIZE {
+            break;
+        }
+    }
+    pending.finish(conn, fs_conn).await
+}
+
+/// All of the data stored under `hash`.
+async fn read_content(hash: &str, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Vec<u8>, FSError> {
+    let chunks = QueryBuilder::new(format!(r#"
+            SELECT content, seq, {chunks}.data AS data, compression, encrypted FROM {chunks} JOIN {contents} ON {contents}.id = {chunks}.content WHERE hash=
+        "#, chunks = fs_conn.chunk_table, contents = fs_conn.content_table))
+        .push_bind(hash)
+        .push("ORDER BY seq")
+        .build()
+        .fetch_all(conn)
+        .await
+        .map_err(FSError::database)?;
+    let mut data = vec![];
+    for row in chunks {
+        data.extend(ContentFormat::from_row(&row)?.decode(row.get("content"), row.get("seq"), row.get("data"), fs_conn)?);
+    }
+    Ok(data)
+}
+
+/// Stores a copy of the content under `hash` in another format, a chunk at a time, and returns the
+/// copy's hash.
+async fn convert_content(hash: &str, from: ContentFormat, to: ContentFormat, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<String, FSError> {
+    let id: i64 = QueryBuilder::new(format!("SELECT id FROM {} WHERE hash=", fs_conn.content_table))
+        .push_bind(hash)
+        .build()
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(FSError::database)?
+        .get("id");
+    let mut pending = PendingContent::new(to, conn, fs_conn).await?;
+    for seq in query_seqs(id, conn, fs_conn).await.map_err(FSError::database)? {
+        let data = query_chunk(id, seq, conn, fs_conn).await.map_err(FSError::database)?;
+        pending.push(&from.decode(id, seq, data, fs_conn)?, conn, fs_conn).await?;
+    }
+    Ok(pending.finish(conn, fs_conn).await?.0)
+}
+
+/// Chunk numbers of the content with this id, in order.
+async fn query_seqs(content: i64, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Vec<i64>, sqlx::Error> {
+    Ok(QueryBuilder::new(format!("SELECT seq FROM {} WHERE content=", fs_conn.chunk_table))
+        .push_bind(content)
+        .push("ORDER BY seq")
+        .build()
+        .fetch_all(conn)
+        .await?
+        .iter()
+        .map(|row| row.get("seq"))
+        .collect())
+}
+
+/// The stored bytes of one chunk, still encoded.
+async fn query_chunk(content: i64, seq: i64, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Vec<u8>, sqlx::Error> {
+    Ok(QueryBuilder::new(format!("SELECT data FROM {} WHERE content=", fs_conn.chunk_table))
+        .push_bind(content)
+        .push("AND seq=")
+        .push_bind(seq)
+        .build()
+        .fetch_one(conn)
+        .await?
+        .get("data"))
+}
+
+/// The compression called for by the longest rule whose prefix `path` starts with.
+async fn query_compression(path: &str, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Compression, FSError> {
+    let algorithm: Option<String> = QueryBuilder::new(format!("SELECT algorithm FROM {} WHERE substr(", fs_conn.compression_table))
+        .push_bind(path)
+        .push(", 1, length(prefix)) = prefix ORDER BY length(prefix) DESC LIMIT 1")
+        .build()
+        .fetch_optional(conn)
+        .await
+        .map_err(FSError::database)?
+        .map(|row| row.get("algorithm"));
+    match algorithm {
+        Some(algorithm) => Compression::from_str(&algorithm),
+        None => Ok(Compression::None),
+    }
+}
+
+/// Rewrites the chunks of the content with this id with another compression, one chunk at a time.
+/// The hash doesn't depend on the compression, so the content stays where it is.
+async fn recompress_content(id: i64, from: ContentFormat, compression: Compression, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+    let to = ContentFormat { compression, ..from };
+    for seq in query_seqs(id, conn, fs_conn).await.map_err(FSError::database)? {
+        let data = query_chunk(id, seq, conn, fs_conn).await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("UPDATE {} SET data=", fs_conn.chunk_table))
+            .push_bind(to.encode(id, seq, &from.decode(id, seq, data, fs_conn)?, fs_conn)?.as_ref())
+            .push("WHERE content=")
+            .push_bind(id)
+            .push("AND seq=")
+            .push_bind(seq)
+            .build()
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+    }
+    QueryBuilder::new(format!("UPDATE {} SET compression=", fs_conn.content_table))
+        .push_bind(compression.column())
+        .push("WHERE id=")
+        .push_bind(id)
+        .build()
+        .execute(conn)
+        .await
+        .map_err(FSError::database)?;
+    Ok(())
+}
+
+/// Fills in the search body of compressed text files, which the triggers on the file table leave
+/// empty as they can't decompress it. Indexes only `file` if one is given. Encrypted files are never
+/// indexed, as that would put their text in the database in the clear.
+async fn index_compressed(file: Option<i64>, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+    let mut query = QueryBuilder::new(format!(r#"
+            SELECT {files}.id AS id, content FROM {files} JOIN {contents} ON {contents}.hash = {files}.content
+            WHERE type="text" AND compression IS NOT NULL AND NOT encrypted
+        "#, files = fs_conn.file_table, contents = fs_conn.content_table));
+    if let Some(file) = file {
+        query.push(format!("AND {}.id=", fs_conn.file_table))
+            .push_bind(file);
+    }
+    let rows = query.build()
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(FSError::database)?;
+    for row in rows {
+        let data = read_content(row.get("content"), conn, fs_conn).await?;
+        QueryBuilder::new(format!("UPDATE {} SET body=", fs_conn.search_table))
+            .push_bind(String::from_utf8_lossy(&data))
+            .push("WHERE rowid=")
+            .push_bind(row.get::<i64, &str>("id"))
+            .build()
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+    }
+    Ok(())
+}
+
+/// Content written a chunk at a time, for data that needn't fit in memory. It is stored under
+/// [`PENDING_CONTENT`] until [`PendingContent::finish`] knows its hash; chunks refer to the content
+/// by id, so renaming it doesn't touch them.
+struct PendingContent {
+    id: i64,
+    seq: i64,
+    size: i64,
+    hasher: ContentHasher,
+    format: ContentFormat,
+}
+
+impl PendingContent {
+    async fn new(format: ContentFormat, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<PendingContent, FSError> {
+        let hasher = format.hasher(fs_conn)?;
+        let id = insert_content(PENDING_CONTENT, 0, format, conn, fs_conn).await
+            .map_err(FSError::database)?
+            .ok_or_else(|| FSError::database(sqlx::Error::Protocol("content is already being written".to_string())))?;
+        Ok(PendingContent { id, seq: 0, size: 0, hasher, format })
+    }
+
+    /// Appends the next chunk, which must be [`CHUNK_SIZE`] bytes unless it is the last.
+    async fn push(&mut self, data: &[u8], conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+        insert_chunk(self.id, self.seq, &self.format.encode(self.id, self.seq, data, fs_conn)?, conn, fs_conn).await.map_err(FSError::database)?;
+        self.hasher.update(data);
+        self.seq += 1;
+        self.size += data.len() as i64;
+        Ok(())
+    }
+
+    /// Files the content under its hash, or drops it if identical data is already stored. Returns the
+    /// hash and the size.
+    async fn finish(self, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(String, i64), FSError> {
+        let hash = self.hasher.finalize();
+        let stored = QueryBuilder::new(format!("SELECT EXISTS(SELECT 1 FROM {} WHERE hash=", fs_conn.content_table))
+            .push_bind(&hash)
+            .push(") AS stored")
+            .build()
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .get::<bool, &str>("stored");
+        let mut query = if stored {
+            // Its chunks go with it by cascade.
+            QueryBuilder::new(format!("DELETE FROM {}", fs_conn.content_table))
+        } else {
+            let mut query = QueryBuilder::new(format!("UPDATE {} SET hash=", fs_conn.content_table));
+            query.push_bind(&hash)
+                .push(", size=")
+                .push_bind(self.size);
+            query
+        };
+        query.push(" WHERE id=")
+            .push_bind(self.id)
+            .build()
+            .execute(conn)
+            .await
+            .map_err(FSError::database)?;
+        Ok((hash, self.size))
+    }
+}
+
+/// Appends a row to the change log. Written in the same transaction as the change itself, so a
+/// rolled back change is never reported.
+async fn log_change(kind: ChangeKind, path: &str, old_path: Option<&str>, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!("INSERT INTO {}(time, kind, path, old_path) VALUES(", fs_conn.change_table))
+        .push_bind(now())
+        .push(",")
+        .push_bind(kind.to_string())
+        .push(",")
+        .push_bind(path)
+        .push(",")
+        .push_bind(old_path)
+        .push(")")
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn get_attr(table: &str, entry: i64, key: &str, conn: &mut SqliteConnection) -> Result<Option<Vec<u8>>, FSError> {
+    Ok(QueryBuilder::new(format!("SELECT value FROM {} WHERE entry=", table))
+        .push_bind(entry)
+        .push("AND key=")
+        .push_bind(key)
+        .build()
+        .fetch_optional(conn)
+        .await
+        .map_err(FSError::database)?
+        .map(|row| row.get("value")))
+}
+
+async fn get_attrs(table: &str, entry: i64, conn: &mut SqliteConnection) -> Result<BTreeMap<String, Vec<u8>>, FSError> {
+    Ok(QueryBuilder::new(format!("SELECT key, value FROM {} WHERE entry=", table))
+        .push_bind(entry)
+        .build()
+        .fetch_all(conn)
+        .await
+        .map_err(FSError::database)?
+        .iter()
+        .map(|row| (row.get("key"), row.get("value")))
+        .collect())
 }
 
+async fn set_attr(table: &str, entry: i64, key: &str, value: &[u8], conn: &mut SqliteConnection) -> Result<(), FSError> {
+    QueryBuilder::new(format!("INSERT INTO {}(entry, key, value) VALUES(", table))
+        .push_bind(entry)
+        .push(",")
+        .push_bind(key)
+        .push(",")
+        .push_bind(value)
+        .push(") ON CONFLICT(entry, key) DO UPDATE SET value=excluded.value")
+        .build()
+        .execute(conn)
+        .await
+        .map_err(FSError::database)?;
+    Ok(())
+}
+
+async fn remove_attr(table: &str, entry: i64, key: &str, path: &str, conn: &mut SqliteConnection) -> Result<(), FSError> {
+    let changed = QueryBuilder::new(format!("DELETE FROM {} WHERE entry=", table))
+        .push_bind(entry)
+        .push("AND key=")
+        .push_bind(key)
+        .build()
+        .execute(conn)
+        .await
+        .map_err(FSError::database)?;
+    affected(changed, &format!("{} attribute {}", path, key))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FileType {
     File,
     Text,
-    Exec
+    Exec,
+    Blob,
+    /// A symbolic link. The data is the target path, absolute or relative to the link's directory.
+    Link,
+    /// A type registered with [`FSConnection::register_file_type`]. Its data is rendered by the
+    /// registered handler.
+    Custom(String),
 }
 
-impl ToString for FileType {
-    fn to_string(&self) -> String {
+impl FileType {
+    pub fn is_builtin(&self) -> bool {
+        !matches!(self, FileType::Custom(_))
+    }
+}
+
+impl Display for FileType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            FileType::File => String::from("file"),
-            FileType::Text => String::from("text"),
-            FileType::Exec => String::from("exec"),
+            FileType::File => write!(f, "file"),
+            FileType::Text => write!(f, "text"),
+            FileType::Exec => write!(f, "exec"),
+            FileType::Blob => write!(f, "blob"),
+            FileType::Link => write!(f, "link"),
+            FileType::Custom(name) => write!(f, "{}", name),
         }
     }
 }
@@ -42,504 +630,3393 @@ impl FromStr for FileType {
             "file" => Ok(FileType::File),
             "text" => Ok(FileType::Text),
             "exec" => Ok(FileType::Exec),
+            "blob" => Ok(FileType::Blob),
+            "link" => Ok(FileType::Link),
+            _ if !s.is_empty() && !s.contains(|c: char| c == '/' || c.is_whitespace() || c.is_control()) => Ok(FileType::Custom(s.to_string())),
             _ => Err(FSError::InvalidType(s.to_string()))
         }
     }
 }
 
-pub struct File {
+/// How long a file type handler may run before its output is abandoned.
+const HANDLER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs a file type handler: `handler` is a bash command that is given the stored data on stdin and
+/// the file's path in `SERVEFS_PATH`. Its stdout is the rendered file.
+pub async fn run_handler(handler: &str, path: &str, data: &[u8]) -> Result<Vec<u8>, FSError> {
+    let failed = |e: std::io::Error| FSError::HandlerFailed(format!("{}: {}", handler, e));
+    let mut child = Command::new("bash")
+        .arg("-c")
+        .arg(handler)
+        .env("SERVEFS_PATH", path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(failed)?;
+
+    // Feed stdin alongside reading stdout so large files can't deadlock on a full pipe.
+    let mut stdin = child.stdin.take();
+    let data = data.to_vec();
+    let writer = tokio::spawn(async move {
+        if let Some(stdin) = stdin.as_mut() {
+            let _ = stdin.write_all(&data).await;
+        }
+    });
+    let output = tokio::time::timeout(HANDLER_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| FSError::HandlerFailed(format!("{}: timed out", handler)))?
+        .map_err(failed)?;
+    let _ = writer.await;
+    Ok(output.stdout)
+}
+
+/// A row of the file type registry, as listed by [`FSConnection::file_types`].
+#[derive(Debug, Clone)]
+pub struct FileTypeEntry {
+    pub ftype: FileType,
+    /// The command run by [`run_handler`]. Built-in types have none.
+    pub handler: Option<String>,
+}
+
+/// A text file matching a [`FSConnection::search`] query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub path: String,
+    /// The best matching part of the file, with matches wrapped in `[` and `]`.
+    pub snippet: String,
+    /// FTS5's bm25 rank; lower is a better match.
+    pub rank: f64,
+}
+
+/// Columns copied between each live table and its snapshot table.
+const SNAPSHOT_FILE_TYPE_COLUMNS: &str = "type,handler";
+const SNAPSHOT_DIR_COLUMNS: &str = "id,parent,name,created,modified,accessed,mode,uid,gid";
+const SNAPSHOT_FILE_COLUMNS: &str = "id,name,type,content,directory,created,modified,accessed,mode,uid,gid,size";
+const SNAPSHOT_ATTR_COLUMNS: &str = "entry,key,value";
+const SNAPSHOT_QUOTA_COLUMNS: &str = "directory,max_bytes,max_entries";
+const SNAPSHOT_TRASH_COLUMNS: &str = "id,container,path,deleted";
+
+/// A saved copy of the whole tree, as listed by [`FSConnection::snapshots`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
     pub name: String,
-    pub directory: Directory,
+    pub created: SystemTime,
 }
 
-impl File {
-    fn path_to_str(path: &PathBuf) ->  Result<String, FSError> {
-        match path.file_name() {
-            Some(name) => Ok(name.to_string_lossy().to_string()),
-            None => return Err(FSError::PathIsNotAFile(path.display().to_string())),
+/// A deleted file or directory, as listed by [`FSConnection::trash`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    /// Identifies the entry to [`FSConnection::restore_trash`] and [`FSConnection::purge_trash`].
+    pub id: i64,
+    /// Where the entry was deleted from, and where it is restored to. Directory paths end in `/`.
+    pub path: String,
+    pub deleted: SystemTime,
+}
+
+impl TrashEntry {
+    pub fn is_dir(&self) -> bool {
+        self.path.ends_with('/')
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Create,
+    /// The data, metadata or attributes changed.
+    Update,
+    Delete,
+    /// Renamed or moved; the previous path is in [`Change::old_path`].
+    Move,
+}
+
+impl Display for ChangeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeKind::Create => write!(f, "create"),
+            ChangeKind::Update => write!(f, "update"),
+            ChangeKind::Delete => write!(f, "delete"),
+            ChangeKind::Move => write!(f, "move"),
         }
     }
+}
 
-    pub fn new(path: PathBuf) -> Result<File, FSError>{
-        let name = File::path_to_str(&path)?;
+impl FromStr for ChangeKind {
+    type Err = FSError;
 
-        let path = match path.absolutize_virtually("/") {
-            Ok(path) => path,
-            Err(_) => Err(FSError::PathIsNotAFile(path.display().to_string()))?,
-        };
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(ChangeKind::Create),
+            "update" => Ok(ChangeKind::Update),
+            "delete" => Ok(ChangeKind::Delete),
+            "move" => Ok(ChangeKind::Move),
+            _ => Err(FSError::InvalidData(format!("change kind {}", s))),
+        }
+    }
+}
 
-        let directory = match path.parent() {
-            Some(directory) => Directory::new(directory.to_path_buf())?,
-            None => Directory::root(),
-        };
+/// An entry of the change log, as returned by [`FSConnection::changes_since`] and [`Subscription::next`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Increases with every change; pass the last one seen to [`FSConnection::subscribe`] to resume.
+    pub seq: i64,
+    pub kind: ChangeKind,
+    /// The path after the change. Directory paths end in `/`.
+    pub path: String,
+    /// The path before a [`ChangeKind::Move`].
+    pub old_path: Option<String>,
+    pub time: SystemTime,
+}
 
-        Ok(File{name, directory})
+impl Change {
+    fn from_row(row: &SqliteRow) -> Result<Change, sqlx::Error> {
+        let kind: String = row.try_get("kind")?;
+        Ok(Change {
+            seq: row.try_get("seq")?,
+            kind: ChangeKind::from_str(&kind).map_err(|_| sqlx::Error::ColumnDecode {
+                index: "kind".to_string(),
+                source: format!("unknown change kind {}", kind).into(),
+            })?,
+            path: row.try_get("path")?,
+            old_path: row.try_get("old_path")?,
+            time: from_timestamp(row.try_get("time")?),
+        })
     }
+}
 
-    pub async fn from_id(id: i64, fs_conn: &FSConnection) -> Result<File, FSError> {
-        let mut conn = fs_conn.pool.acquire().await.map_err(|e| FSError::SqlX(e))?;
-        let row = QueryBuilder::new(format!(r#"
-                SELECT name,directory FROM {} WHERE id=
-            "#, fs_conn.file_table))
-            .push_bind(&id)
-            .build()
-            .fetch_one(&mut conn)
-            .await.map_err(|e| FSError::SqlX(e))?;
+/// Number of changes fetched per query by a [`Subscription`].
+const SUBSCRIPTION_BATCH: i64 = 100;
+
+/// A stream of changes made after a given sequence number, from [`FSConnection::subscribe`]. The log
+/// is read from the database, so changes made by other processes sharing it are seen too, once committed.
+pub struct Subscription {
+    pool: SqlitePool,
+    change_table: String,
+    last: i64,
+    interval: Duration,
+    pending: VecDeque<Change>,
+}
+
+impl Subscription {
+    /// The sequence number of the last change returned.
+    pub fn last_seq(&self) -> i64 {
+        self.last
+    }
+
+    /// Waits for the next change, checking the log every `interval` while there is none.
+    pub async fn next(&mut self) -> Result<Change, FSError> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                self.last = change.seq;
+                return Ok(change);
+            }
+            let mut conn = self.pool.acquire().await.map_err(FSError::database)?;
+            self.pending = query_changes(&self.change_table, self.last, SUBSCRIPTION_BATCH, &mut conn).await?.into();
+            drop(conn);
+            if self.pending.is_empty() {
+                tokio::time::sleep(self.interval).await;
+            }
+        }
+    }
+}
+
+async fn query_changes(table: &str, since: i64, limit: i64, conn: &mut SqliteConnection) -> Result<Vec<Change>, FSError> {
+    QueryBuilder::new(format!("SELECT seq, time, kind, path, old_path FROM {} WHERE seq >", table))
+        .push_bind(since)
+        .push("ORDER BY seq LIMIT")
+        .push_bind(limit)
+        .build()
+        .fetch_all(conn)
+        .await
+        .map_err(FSError::database)?
+        .iter()
+        .map(Change::from_row)
+        .collect::<Result<_, _>>()
+        .map_err(FSError::database)
+}
+
+/// How stored data is compressed. Which one a file gets is decided by the rules set with
+/// [`FSConnection::set_compression`] when its data is written; each chunk is compressed on its own,
+/// so offset reads still only load the chunks they cover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    /// Value of the content table's `compression` column.
+    fn column(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    fn from_column(column: Option<String>) -> Result<Compression, FSError> {
+        match column {
+            Some(column) => Compression::from_str(&column),
+            None => Ok(Compression::None),
+        }
+    }
+
+    fn encode<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, FSError> {
+        match self {
+            Compression::None => Ok(Cow::Borrowed(data)),
+            Compression::Zstd => Ok(Cow::Owned(zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL).map_err(FSError::Io)?)),
+        }
+    }
+
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, FSError> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Zstd => zstd::decode_all(&data[..]).map_err(FSError::Io),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = FSError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(FSError::InvalidData(format!("compression {}", s))),
+        }
+    }
+}
+
+/// Environment variable holding the encryption key as 64 hex digits, see [`EncryptionKey::from_env`].
+pub const KEY_ENV: &str = "SERVEFS_KEY";
+/// Environment variable naming a file that holds the encryption key, as 32 raw bytes or 64 hex digits.
+pub const KEY_FILE_ENV: &str = "SERVEFS_KEY_FILE";
+
+/// Length of the nonce in front of each encrypted chunk.
+const NONCE_SIZE: usize = 24;
+
+/// Associated data of an encrypted chunk: the id of its content row and its number, so chunks can't
+/// be reordered or moved to other content.
+fn chunk_aad(content: i64, seq: i64) -> [u8; 16] {
+    let mut aad = [0; 16];
+    aad[..8].copy_from_slice(&content.to_le_bytes());
+    aad[8..].copy_from_slice(&seq.to_le_bytes());
+    aad
+}
+
+/// A 256-bit key for the data of encrypted files. Chunks are sealed with XChaCha20-Poly1305 under a
+/// random nonce, with the chunk's content row and number as associated data.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> EncryptionKey {
+        EncryptionKey(key)
+    }
+
+    /// Reads a key of 64 hex digits, surrounding whitespace ignored, or of 32 raw bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<EncryptionKey, FSError> {
+        let hex_key = std::str::from_utf8(bytes).ok()
+            .and_then(|key| hex::decode(key.trim()).ok())
+            .filter(|key| key.len() == 32);
+        hex_key.unwrap_or_else(|| bytes.to_vec())
+            .try_into()
+            .map(EncryptionKey)
+            .map_err(|_| FSError::InvalidKey("expected 64 hex digits or 32 bytes".to_string()))
+    }
+
+    /// The key in [`KEY_ENV`], or else in the file named by [`KEY_FILE_ENV`]. `None` if neither is set.
+    pub fn from_env() -> Result<Option<EncryptionKey>, FSError> {
+        if let Ok(key) = std::env::var(KEY_ENV) {
+            return EncryptionKey::from_bytes(key.as_bytes()).map(Some);
+        }
+        match std::env::var_os(KEY_FILE_ENV) {
+            Some(path) => EncryptionKey::from_bytes(&std::fs::read(path).map_err(FSError::Io)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+
+    /// An HMAC-SHA256 for hashing encrypted content, keyed with a subkey derived from this key by
+    /// HKDF, so the key itself is only ever used by the cipher.
+    fn hmac(&self) -> Hmac<Sha256> {
+        let mut subkey = [0; 32];
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(b"servefs content hash", &mut subkey)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        <Hmac<Sha256> as Mac>::new_from_slice(&subkey).expect("HMAC takes keys of any length")
+    }
+
+    /// Encrypts chunk `seq` of the content row with id `content`, returning the nonce followed by the
+    /// ciphertext.
+    fn seal(&self, content: i64, seq: i64, data: &[u8]) -> Result<Vec<u8>, FSError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self.cipher()
+            .encrypt(&nonce, Payload { msg: data, aad: &chunk_aad(content, seq) })
+            .map_err(|_| FSError::InvalidKey("encryption failed".to_string()))?;
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    fn open(&self, content: i64, seq: i64, data: &[u8]) -> Result<Vec<u8>, FSError> {
+        let invalid = || FSError::InvalidKey("the key doesn't match the data".to_string());
+        if data.len() < NONCE_SIZE {
+            return Err(invalid());
+        }
+        let (nonce, sealed) = data.split_at(NONCE_SIZE);
+        self.cipher()
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad: &chunk_aad(content, seq) })
+            .map_err(|_| invalid())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// A compression rule, as listed by [`FSConnection::compression_rules`]. It applies to every path
+/// starting with `prefix` that no longer prefix has a rule for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionRule {
+    pub prefix: String,
+    pub compression: Compression,
+}
+
+/// A file as listed by [`Directory::files`], [`Directory::contents`] or [`Directory::recurse`].
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub id: i64,
+    pub name: String,
+    /// Full path of the file, e.g. `/home/notes.txt`.
+    pub path: String,
+    pub ftype: FileType,
+    pub metadata: Metadata,
+}
+
+impl FileEntry {
+    fn from_row(row: &SqliteRow) -> Result<FileEntry, sqlx::Error> {
+        let name: String = row.try_get("name")?;
+        let directory: String = row.try_get("directory")?;
+        let ftype: String = row.try_get("type")?;
+        Ok(FileEntry {
+            id: row.try_get("id")?,
+            path: format!("{}{}", directory, name),
+            name,
+            ftype: FileType::from_str(&ftype).map_err(|_| sqlx::Error::ColumnDecode {
+                index: "type".to_string(),
+                source: format!("unknown file type {}", ftype).into(),
+            })?,
+            metadata: Metadata::from_row(row)?,
+        })
+    }
+
+    /// A handle to the file, with its id already cached.
+    pub fn file(&self) -> Result<File, FSError> {
+        let mut file = File::new(PathBuf::from(&self.path))?;
+        file.id = Some(self.id);
+        Ok(file)
+    }
+}
+
+/// A directory as listed by [`Directory::dirs`], [`Directory::contents`] or [`Directory::recurse`].
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub id: i64,
+    pub name: String,
+    /// Full path of the directory, ending in `/`, e.g. `/home/`.
+    pub path: String,
+    pub metadata: Metadata,
+}
+
+impl DirEntry {
+    fn from_row(row: &SqliteRow) -> Result<DirEntry, sqlx::Error> {
+        Ok(DirEntry {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            path: row.try_get("directory")?,
+            metadata: Metadata::from_row(row)?,
+        })
+    }
+
+    /// A handle to the directory, with its id already cached.
+    pub fn directory(&self) -> Result<Directory, FSError> {
+        let mut directory = Directory::new(PathBuf::from(&self.path))?;
+        directory.id = Some(self.id);
+        Ok(directory)
+    }
+}
+
+/// Limits on everything below a directory, set with [`Directory::set_quota`]. `None` leaves that
+/// limit off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quota {
+    /// Total size of the files in the subtree, as reported by their metadata.
+    pub max_bytes: Option<u64>,
+    /// Number of files and directories in the subtree, not counting the directory itself.
+    pub max_entries: Option<u64>,
+}
+
+/// What a directory's subtree currently holds, measured the same way as a [`Quota`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub entries: u64,
+}
+
+/// Fails with [`FSError::QuotaExceeded`] if `directory` or any of its ancestors is over its quota.
+/// Quotas that also cover `moved_from` are skipped, as moving within them doesn't change their usage.
+async fn check_quotas(directory: i64, moved_from: Option<i64>, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+    let dirs = &fs_conn.dir_table;
+    let quotas = QueryBuilder::new(format!(r#"
+            WITH RECURSIVE ancestors(id, parent) AS (
+                SELECT id, parent FROM {dirs} WHERE id=
+        "#))
+        .push_bind(directory)
+        .push(format!(r#"
+                UNION ALL
+                SELECT d.id, d.parent FROM {dirs} d JOIN ancestors a ON d.id = a.parent
+            ), previous(id, parent) AS (
+                SELECT id, parent FROM {dirs} WHERE id=
+        "#))
+        .push_bind(moved_from)
+        .push(format!(r#"
+                UNION ALL
+                SELECT d.id, d.parent FROM {dirs} d JOIN previous p ON d.id = p.parent
+            )
+            SELECT directory, max_bytes, max_entries FROM {}
+            WHERE directory IN (SELECT id FROM ancestors) AND directory NOT IN (SELECT id FROM previous)
+        "#, fs_conn.quota_table))
+        .build()
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(FSError::database)?;
+
+    for quota in quotas {
+        let id: i64 = quota.get("directory");
+        let usage = Directory::query_usage(id, conn, fs_conn).await.map_err(FSError::database)?;
+        let over_bytes = quota.get::<Option<i64>, &str>("max_bytes").is_some_and(|max| usage.bytes > max as u64);
+        let over_entries = quota.get::<Option<i64>, &str>("max_entries").is_some_and(|max| usage.entries > max as u64);
+        if over_bytes || over_entries {
+            let path = Directory::query_path(id, conn, fs_conn).await.map_err(FSError::database)?;
+            return Err(FSError::QuotaExceeded(path.unwrap_or_else(|| format!("directory {}", id))));
+        }
+    }
+    Ok(())
+}
+
+/// Whether an error from a full-text `MATCH` is down to the query, as opposed to the database.
+fn is_query_error(message: &str) -> bool {
+    ["fts5:", "no such column", "unterminated string", "unknown special query"].iter().any(|prefix| message.starts_with(prefix))
+}
+
+/// Settings key of the trash retention period, in seconds. A `NULL` value keeps the trash forever.
+const TRASH_RETENTION_KEY: &str = "trash_retention";
+/// Retention period used until [`FSConnection::set_trash_retention`] is called.
+pub const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+async fn query_trash_retention(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Option<Duration>, sqlx::Error> {
+    let row = QueryBuilder::new(format!("SELECT value FROM {} WHERE key=", fs_conn.setting_table))
+        .push_bind(TRASH_RETENTION_KEY)
+        .build()
+        .fetch_optional(conn)
+        .await?;
+    Ok(match row {
+        Some(row) => row.get::<Option<i64>, &str>("value").map(|secs| Duration::from_secs(secs as u64)),
+        None => Some(DEFAULT_TRASH_RETENTION),
+    })
+}
+
+/// Purges trash entries deleted longer ago than the retention period.
+async fn purge_expired_trash(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let retention = match query_trash_retention(conn, fs_conn).await? {
+        Some(retention) => retention,
+        None => return Ok(()),
+    };
+    // Everything in a container goes with it by cascade, the trash row included.
+    QueryBuilder::new(format!("DELETE FROM {} WHERE id IN (SELECT container FROM {} WHERE deleted <=", fs_conn.dir_table, fs_conn.trash_table))
+        .push_bind(now() - retention.as_secs() as i64)
+        .push(")")
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Adds a trash entry for `path` and returns its container: a directory with no parent, outside the
+/// tree, that the deleted entry is moved into. Containers are named after their id, as the names of
+/// parentless directories must be unique. The entry keeps its data, history and attributes there
+/// until it is restored or purged.
+async fn trash_container(path: &str, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<i64, sqlx::Error> {
+    purge_expired_trash(conn, fs_conn).await?;
+    let now = now();
+    let container = QueryBuilder::new(format!(r#"
+            INSERT INTO {dirs}(parent, name, created, modified, accessed)
+            VALUES(NULL, "trash-" || (SELECT MAX(id) + 1 FROM {dirs}),
+        "#, dirs = fs_conn.dir_table))
+        .push_bind(now)
+        .push(",")
+        .push_bind(now)
+        .push(",")
+        .push_bind(now)
+        .push(")")
+        .build()
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    QueryBuilder::new(format!("INSERT INTO {}(container, path, deleted) VALUES(", fs_conn.trash_table))
+        .push_bind(container)
+        .push(",")
+        .push_bind(path)
+        .push(",")
+        .push_bind(now)
+        .push(")")
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(container)
+}
+
+/// Order of the files returned by [`Directory::find`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortBy {
+    #[default]
+    Path,
+    Name,
+    Size,
+    Modified,
+}
+
+/// Filters for [`Directory::find`], built up from [`Find::new`]. Unset filters match every file;
+/// all of them are applied in the database.
+#[derive(Debug, Clone, Default)]
+pub struct Find {
+    name: Option<String>,
+    ftype: Option<FileType>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<SystemTime>,
+    modified_before: Option<SystemTime>,
+    max_depth: Option<u32>,
+    sort: SortBy,
+    descending: bool,
+    limit: Option<u32>,
+}
+
+impl Find {
+    pub fn new() -> Find {
+        Find::default()
+    }
+
+    /// Only files whose name matches `glob`, e.g. `*.md`. Matching is case sensitive.
+    pub fn name(mut self, glob: &str) -> Find {
+        self.name = Some(glob.to_string());
+        self
+    }
+
+    pub fn ftype(mut self, ftype: FileType) -> Find {
+        self.ftype = Some(ftype);
+        self
+    }
+
+    /// Only files of at least `size` bytes.
+    pub fn min_size(mut self, size: u64) -> Find {
+        self.min_size = Some(size);
+        self
+    }
+
+    /// Only files of at most `size` bytes.
+    pub fn max_size(mut self, size: u64) -> Find {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Only files modified at or after `time`.
+    pub fn modified_after(mut self, time: SystemTime) -> Find {
+        self.modified_after = Some(time);
+        self
+    }
+
+    /// Only files modified before `time`.
+    pub fn modified_before(mut self, time: SystemTime) -> Find {
+        self.modified_before = Some(time);
+        self
+    }
+
+    /// Only descend this many levels: 1 is the directory's own files, 2 adds its subdirectories' and so on.
+    pub fn max_depth(mut self, depth: u32) -> Find {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn sort(mut self, sort: SortBy, descending: bool) -> Find {
+        self.sort = sort;
+        self.descending = descending;
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Find {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// How [`Directory::import`] stores host files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImportMode {
+    /// Copy the data in, as text if it is UTF-8 and as a blob otherwise.
+    #[default]
+    Copy,
+    /// Make [`FileType::File`] entries that serve the host files from where they are.
+    Link,
+}
+
+/// Options for [`Directory::import`], built up from [`Import::new`]. Globs are matched against
+/// paths relative to the host directory, e.g. `notes/todo.md`, and `*` matches across `/`.
+#[derive(Debug, Clone, Default)]
+pub struct Import {
+    mode: ImportMode,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    dry_run: bool,
+}
+
+impl Import {
+    pub fn new() -> Import {
+        Import::default()
+    }
+
+    pub fn mode(mut self, mode: ImportMode) -> Import {
+        self.mode = mode;
+        self
+    }
+
+    /// Only import files matching `glob`; a file has to match one of the included globs. Directories
+    /// are then only made on the way to an included file.
+    pub fn include(mut self, glob: &str) -> Import {
+        self.include.push(glob.to_string());
+        self
+    }
+
+    /// Leave out files and directories matching `glob`, along with everything below such directories.
+    pub fn exclude(mut self, glob: &str) -> Import {
+        self.exclude.push(glob.to_string());
+        self
+    }
+
+    /// Only report what the import would make. Nothing is written, and copied files are read once
+    /// to tell text from blobs.
+    pub fn dry_run(mut self, dry_run: bool) -> Import {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// What [`Directory::import`] made, or would have made on a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Paths of the directories made, parents first.
+    pub dirs: Vec<String>,
+    pub files: Vec<ImportedFile>,
+    /// Host paths left out: excluded, not included, neither a regular file nor a directory, or not
+    /// named in UTF-8.
+    pub skipped: Vec<PathBuf>,
+}
+
+/// A file made by [`Directory::import`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedFile {
+    pub host: PathBuf,
+    pub path: String,
+    pub ftype: FileType,
+    /// Size of the host file.
+    pub size: u64,
+}
+
+/// Whether the host file at `path` holds valid UTF-8, checked a chunk at a time.
+async fn host_is_utf8(path: &Path) -> Result<bool, FSError> {
+    let mut file = tokio::fs::File::open(path).await.map_err(FSError::Io)?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    // Bytes of a character split across reads are carried to the front of the buffer.
+    let mut carried = 0;
+    loop {
+        let read = file.read(&mut buffer[carried..]).await.map_err(FSError::Io)?;
+        if read == 0 {
+            return Ok(carried == 0);
+        }
+        let filled = carried + read;
+        match std::str::from_utf8(&buffer[..filled]) {
+            Ok(_) => carried = 0,
+            Err(e) if e.error_len().is_none() => {
+                buffer.copy_within(e.valid_up_to()..filled, 0);
+                carried = filled - e.valid_up_to();
+            },
+            Err(_) => return Ok(false),
+        }
+    }
+}
+
+/// The file that the host file at `relative` under `host` is imported as, below the directory at
+/// `root`, and how it is reported. Copied files are read to tell text from blobs.
+async fn import_target(host: &Path, root: &Path, relative: &Path, mode: ImportMode) -> Result<(File, ImportedFile), FSError> {
+    let host_path = host.join(relative);
+    let directory = Directory::new(root.join(relative.parent().unwrap_or(Path::new(""))))?;
+    let file = directory.file(&relative.file_name().unwrap_or_default().to_string_lossy());
+    let size = tokio::fs::metadata(&host_path).await.map_err(FSError::Io)?.len();
+    let ftype = match mode {
+        ImportMode::Copy if host_is_utf8(&host_path).await? => FileType::Text,
+        ImportMode::Copy => FileType::Blob,
+        ImportMode::Link => FileType::File,
+    };
+    let imported = ImportedFile { host: host_path, path: file.path(), ftype, size };
+    Ok((file, imported))
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet, FSError> {
+    let mut set = GlobSetBuilder::new();
+    for glob in globs {
+        set.add(Glob::new(glob).map_err(|e| FSError::InvalidGlob(e.to_string()))?);
+    }
+    set.build().map_err(|e| FSError::InvalidGlob(e.to_string()))
+}
+
+/// What [`Directory::export`] writes for [`FileType::Exec`] files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportExec {
+    /// Write the command as an executable bash script.
+    #[default]
+    Script,
+    /// Run the command and write its output, as serving the file would.
+    Run,
+}
+
+/// Options for [`Directory::export`], built up from [`Export::new`].
+#[derive(Debug, Clone, Default)]
+pub struct Export {
+    exec: ExportExec,
+    preserve_metadata: bool,
+}
+
+impl Export {
+    pub fn new() -> Export {
+        Export::default()
+    }
+
+    pub fn exec(mut self, exec: ExportExec) -> Export {
+        self.exec = exec;
+        self
+    }
+
+    /// Carry permission bits and access and modification times over to the host. Ownership isn't,
+    /// as changing it needs privileges.
+    pub fn preserve_metadata(mut self, preserve: bool) -> Export {
+        self.preserve_metadata = preserve;
+        self
+    }
+}
+
+/// What [`Directory::export`] wrote.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportReport {
+    /// Host directories written to, parents first.
+    pub dirs: Vec<PathBuf>,
+    pub files: Vec<ExportedFile>,
+}
+
+/// A file written by [`Directory::export`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedFile {
+    pub path: String,
+    pub host: PathBuf,
+    pub ftype: FileType,
+    /// Bytes written to the host, or the length of the target for links.
+    pub size: u64,
+}
+
+/// Rewrites an absolute link target relative to the link's directory `directory`, so the exported
+/// symlink resolves inside the exported tree rather than against the host root. Relative targets
+/// are kept as they are.
+fn host_relative_target(directory: &str, target: &Path) -> PathBuf {
+    let Ok(rest) = target.strip_prefix("/") else {
+        return target.to_path_buf();
+    };
+    let from: Vec<&str> = directory.split('/').filter(|name| !name.is_empty()).collect();
+    let to: Vec<_> = rest.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| b.as_os_str() == **a).count();
+    let mut relative: PathBuf = std::iter::repeat_n("..", from.len() - common).collect();
+    relative.extend(&to[common..]);
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+/// Sets the permission bits and times of a host file or directory to those of `metadata`.
+fn set_host_metadata(host: &Path, mode: u32, metadata: &Metadata) -> std::io::Result<()> {
+    std::fs::set_permissions(host, std::fs::Permissions::from_mode(mode))?;
+    std::fs::File::open(host)?.set_times(FileTimes::new().set_accessed(metadata.accessed).set_modified(metadata.modified))
+}
+
+/// Permission bits kept in the `mode` column; file type bits are implied by the table.
+const MODE_MASK: u32 = 0o7777;
+/// How many links [`FSConnection::resolve_path`] follows before giving up with [`FSError::LinkLoop`].
+const MAX_LINK_HOPS: usize = 40;
+/// How stale an access time may get before a read refreshes it, in seconds.
+const RELATIME_WINDOW: i64 = 24 * 60 * 60;
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+fn from_timestamp(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+fn to_timestamp(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// POSIX-like metadata kept alongside every file and directory. Timestamps are stored with second
+/// precision. Directories always report a size of 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub created: SystemTime,
+    pub modified: SystemTime,
+    pub accessed: SystemTime,
+    /// Permission bits, e.g. `0o644`.
+    pub mode: u32,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Size of the stored data in bytes.
+    pub size: u64,
+}
+
+impl Metadata {
+    fn from_row(row: &SqliteRow) -> Result<Metadata, sqlx::Error> {
+        Ok(Metadata {
+            created: from_timestamp(row.try_get("created")?),
+            modified: from_timestamp(row.try_get("modified")?),
+            accessed: from_timestamp(row.try_get("accessed")?),
+            mode: row.try_get::<u32, &str>("mode")? & MODE_MASK,
+            uid: row.try_get("uid")?,
+            gid: row.try_get("gid")?,
+            size: row.try_get::<i64, &str>("size")? as u64,
+        })
+    }
+}
+
+/// An earlier version of a file, kept when the file was overwritten or restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// Numbered from 1, oldest first.
+    pub version: i64,
+    pub ftype: FileType,
+    pub size: u64,
+    /// When this version was written.
+    pub modified: SystemTime,
+}
+
+impl Version {
+    fn from_row(row: &SqliteRow) -> Result<Version, sqlx::Error> {
+        let ftype: String = row.try_get("type")?;
+        Ok(Version {
+            version: row.try_get("version")?,
+            ftype: FileType::from_str(&ftype).map_err(|_| sqlx::Error::ColumnDecode {
+                index: "type".to_string(),
+                source: format!("unknown file type {}", ftype).into(),
+            })?,
+            size: row.try_get::<i64, &str>("size")? as u64,
+            modified: from_timestamp(row.try_get("modified")?),
+        })
+    }
+}
+
+pub struct File {
+    pub name: String,
+    pub directory: Directory,
+    /// Set once the file has been looked up by id, see [`File::resolve`].
+    id: Option<i64>,
+}
+
+impl File {
+    fn path_to_str(path: &Path) ->  Result<String, FSError> {
+        match path.file_name() {
+            Some(name) => Ok(name.to_string_lossy().to_string()),
+            None => Err(FSError::PathIsNotAFile(path.display().to_string())),
+        }
+    }
+
+    pub fn new(path: PathBuf) -> Result<File, FSError>{
+        let name = File::path_to_str(&path)?;
+
+        let path = match path.absolutize_virtually("/") {
+            Ok(path) => path,
+            Err(_) => return Err(FSError::PathIsNotAFile(path.display().to_string())),
+        };
+
+        let directory = match path.parent() {
+            Some(directory) => Directory::new(directory.to_path_buf())?,
+            None => Directory::root(),
+        };
+
+        Ok(File{name, directory, id: None})
+    }
+
+    /// Full path of the file, e.g. `/home/notes.txt`.
+    pub fn path(&self) -> String {
+        format!("{}{}", self.directory.path, self.name)
+    }
+
+    pub async fn from_id(id: i64, fs_conn: &FSConnection) -> Result<File, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let row = QueryBuilder::new(format!(r#"
+                SELECT name,directory FROM {} WHERE id=
+            "#, fs_conn.file_table))
+            .push_bind(id)
+            .build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&format!("file {}", id))?;
+
+        Ok(File { name: row.get("name"), directory: Directory::query_from_id(row.get("directory"), &mut conn, fs_conn).await?, id: Some(id) })
+    }
+
+    async fn query_id(&self, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<i64, FSError> {
+        if let Some(id) = self.id {
+            return Ok(id);
+        }
+        let directory = self.directory.query_id(conn, fs_conn).await.at(&self.directory.path)?;
+        Ok(QueryBuilder::new(format!(r#"
+                SELECT id FROM {} WHERE directory=
+            "#, fs_conn.file_table))
+            .push_bind(directory)
+            .push("AND name=")
+            .push_bind(&self.name)
+            .build()
+            .fetch_one(conn)
+            .await
+            .at(&self.path())?
+            .get("id"))
+    }
+
+    /// Restricts a query on the file table to this file: by id if it's cached, otherwise by
+    /// directory and name, which saves looking the id up first.
+    async fn push_where(&self, query: &mut QueryBuilder<'_, Sqlite>, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+        match self.id {
+            Some(id) => {
+                query.push(" WHERE id=").push_bind(id);
+            },
+            None => {
+                let directory = self.directory.query_id(conn, fs_conn).await.at(&self.directory.path)?;
+                query.push(" WHERE directory=")
+                    .push_bind(directory)
+                    .push(" AND name=")
+                    .push_bind(self.name.clone());
+            },
+        }
+        Ok(())
+    }
+
+    pub async fn get_id(&self, fs_conn: &FSConnection) -> Result<i64, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        self.query_id(&mut conn, fs_conn).await
+    }
+
+    /// The file's id, if the handle has one cached.
+    pub fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    /// Looks up the ids of the file and its directory and keeps them on the handle, so later calls
+    /// skip resolving the path. The handle then follows the file through renames and moves; once the
+    /// file is deleted, the handle should be dropped.
+    pub async fn resolve(&mut self, fs_conn: &FSConnection) -> Result<i64, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        if self.directory.id.is_none() {
+            self.directory.id = Some(self.directory.query_id(&mut conn, fs_conn).await.at(&self.directory.path)?);
+        }
+        let id = self.query_id(&mut conn, fs_conn).await?;
+        self.id = Some(id);
+        Ok(id)
+    }
+
+    pub async fn exists(&self, fs_conn: &FSConnection) -> Result<bool, FSError> {
+        match self.get_id(fs_conn).await {
+            Ok(_) => Ok(true),
+            Err(FSError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn mk(&self, data:&str, ftype: &FileType, fs_conn: &FSConnection) -> Result<(), FSError> {
+        self.mk_bytes(data.as_bytes(), ftype, fs_conn).await
+    }
+
+    /// Makes the file. Fails with [`FSError::AlreadyExists`] if the name is taken, and with
+    /// [`FSError::InvalidType`] if `ftype` isn't registered.
+    pub async fn mk_bytes(&self, data: &[u8], ftype: &FileType, fs_conn: &FSConnection) -> Result<(), FSError> {
+        self.mk_stored(data, ftype, false, fs_conn).await
+    }
+
+    /// Like [`File::mk_bytes`], with the data read from `reader` and stored a chunk at a time, so it
+    /// never has to fit in memory.
+    pub async fn mk_from<R: AsyncRead + Unpin + Send>(&self, reader: R, ftype: &FileType, fs_conn: &FSConnection) -> Result<(), FSError> {
+        self.mk_stored(reader, ftype, false, fs_conn).await
+    }
+
+    /// Like [`File::mk_bytes`], with the data encrypted under the connection's key. Fails with
+    /// [`FSError::KeyRequired`] if there is none.
+    pub async fn mk_encrypted(&self, data: &[u8], ftype: &FileType, fs_conn: &FSConnection) -> Result<(), FSError> {
+        self.mk_stored(data, ftype, true, fs_conn).await
+    }
+
+    async fn mk_stored<R: AsyncRead + Unpin + Send>(&self, data: R, ftype: &FileType, encrypted: bool, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let path = self.path();
+        let now = now();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let directory = self.directory.query_id(&mut tx, fs_conn).await.at(&self.directory.path)?;
+            let format = ContentFormat { compression: query_compression(&path, &mut tx, fs_conn).await?, encrypted };
+            let (content, size) = stream_content(data, format, &mut tx, fs_conn).await?;
+            let id = QueryBuilder::new(format!(r#"
+                    INSERT INTO {}(name,type,content,directory,size,created,modified,accessed) VALUES(
+                "#, fs_conn.file_table))
+                .push_bind(&self.name)
+                .push(",")
+                .push_bind(ftype.to_string())
+                .push(",")
+                .push_bind(content)
+                .push(",")
+                .push_bind(directory)
+                .push(",")
+                .push_bind(size)
+                .push(",")
+                .push_bind(now)
+                .push(",")
+                .push_bind(now)
+                .push(",")
+                .push_bind(now)
+                .push(");")
+                .build()
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| FSError::from_sqlx_typed(e, &path, ftype))?
+                .last_insert_rowid();
+            index_compressed(Some(id), &mut tx, fs_conn).await?;
+            check_quotas(directory, None, &mut tx, fs_conn).await?;
+            log_change(ChangeKind::Create, &path, None, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Directory::touch(directory, now, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Ok(())
+        }.await;
+        finish(tx, result).await
+    }
+
+    pub async fn del(&self, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let path = self.path();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let directory = self.directory.query_id(&mut tx, fs_conn).await.at(&self.directory.path)?;
+            let container = trash_container(&path, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            let mut query = QueryBuilder::new(format!("UPDATE {} SET directory=", fs_conn.file_table));
+            query.push_bind(container);
+            self.push_where(&mut query, &mut tx, fs_conn).await?;
+            let changed = query.build()
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            affected(changed, &path)?;
+            log_change(ChangeKind::Delete, &path, None, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Directory::touch(directory, now(), &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Ok(())
+        }.await;
+        finish(tx, result).await
+    }
+
+    pub async fn rename(&mut self, name: &str, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let name = name.to_string();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let directory = self.directory.query_id(&mut tx, fs_conn).await.at(&self.directory.path)?;
+            let mut query = QueryBuilder::new(format!("UPDATE {} SET name=", fs_conn.file_table));
+            query.push_bind(&name);
+            self.push_where(&mut query, &mut tx, fs_conn).await?;
+            let changed = query.build()
+                .execute(&mut *tx)
+                .await
+                .at(&format!("{}{}", self.directory.path, name))?;
+            affected(changed, &self.path())?;
+            log_change(ChangeKind::Move, &format!("{}{}", self.directory.path, name), Some(&self.path()), &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Directory::touch(directory, now(), &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Ok(())
+        }.await;
+        finish(tx, result).await?;
+
+        self.name = name;
+        Ok(())
+    }
+
+    pub async fn mv(&mut self, directory: Directory, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let now = now();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let new_directory = directory.query_id(&mut tx, fs_conn).await.at(&directory.path)?;
+            let old_directory = self.directory.query_id(&mut tx, fs_conn).await.at(&self.directory.path)?;
+            let mut query = QueryBuilder::new(format!("UPDATE {} SET directory=", fs_conn.file_table));
+            query.push_bind(new_directory);
+            self.push_where(&mut query, &mut tx, fs_conn).await?;
+            let changed = query.build()
+                .execute(&mut *tx)
+                .await
+                .at(&format!("{}{}", directory.path, self.name))?;
+            affected(changed, &self.path())?;
+            log_change(ChangeKind::Move, &format!("{}{}", directory.path, self.name), Some(&self.path()), &mut tx, fs_conn).await.map_err(FSError::database)?;
+            check_quotas(new_directory, Some(old_directory), &mut tx, fs_conn).await?;
+            Directory::touch(old_directory, now, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Directory::touch(new_directory, now, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Ok(())
+        }.await;
+        finish(tx, result).await?;
+
+        self.directory = directory;
+        Ok(())
+    }
+
+    /// Reads the file as text. Fails with [`FSError::InvalidData`] if the data isn't UTF-8.
+    pub async fn read(&self, fs_conn: &FSConnection) -> Result<(String, String), FSError> {
+        let (data, ftype) = self.read_bytes(fs_conn).await?;
+        let data = String::from_utf8(data).map_err(|_| FSError::InvalidData(self.path()))?;
+        Ok((data, ftype))
+    }
+
+    /// Reads the file's data and type. Like `relatime`, the access time is only updated when it is
+    /// older than the modification time or more than a day old.
+    pub async fn read_bytes(&self, fs_conn: &FSConnection) -> Result<(Vec<u8>, String), FSError> {
+        let path = self.path();
+        let now = now();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut query = QueryBuilder::new(format!("SELECT id,content,type FROM {}", fs_conn.file_table));
+        self.push_where(&mut query, &mut conn, fs_conn).await?;
+        let row = query.build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&path)?;
+        let data = read_content(row.get("content"), &mut conn, fs_conn).await?;
+        File::touch_accessed(row.get("id"), now, &mut conn, fs_conn).await.map_err(FSError::database)?;
+
+        Ok((data, row.get("type")))
+    }
+
+    /// Marks the file accessed as [`File::read_bytes`] does and returns its type without loading
+    /// the data, for callers that go on to read it in pieces with [`File::read_at`]. Fails with
+    /// [`FSError::KeyRequired`] if the file is encrypted and the connection has no key.
+    pub async fn open(&self, fs_conn: &FSConnection) -> Result<FileType, FSError> {
+        let now = now();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT id, type, (SELECT encrypted FROM {contents} WHERE hash={files}.content) AS encrypted FROM {files}
+            "#, files = fs_conn.file_table, contents = fs_conn.content_table));
+        self.push_where(&mut query, &mut conn, fs_conn).await?;
+        let row = query.build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&self.path())?;
+        if row.get::<bool, &str>("encrypted") {
+            fs_conn.key()?;
+        }
+        File::touch_accessed(row.get("id"), now, &mut conn, fs_conn).await.map_err(FSError::database)?;
+
+        FileType::from_str(row.get("type"))
+    }
+
+    /// Whether the file's data is encrypted, see [`File::set_encrypted`].
+    pub async fn is_encrypted(&self, fs_conn: &FSConnection) -> Result<bool, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT (SELECT encrypted FROM {contents} WHERE hash={files}.content) AS encrypted FROM {files}
+            "#, files = fs_conn.file_table, contents = fs_conn.content_table));
+        self.push_where(&mut query, &mut conn, fs_conn).await?;
+        Ok(query.build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&self.path())?
+            .get("encrypted"))
+    }
+
+    /// How data written to the file is stored: compressed as the rules for its path say, and
+    /// encrypted if its current data is.
+    async fn write_format(&self, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<ContentFormat, FSError> {
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT (SELECT encrypted FROM {contents} WHERE hash={files}.content) AS encrypted FROM {files}
+            "#, files = fs_conn.file_table, contents = fs_conn.content_table));
+        self.push_where(&mut query, conn, fs_conn).await?;
+        let encrypted = query.build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&self.path())?
+            .get("encrypted");
+        Ok(ContentFormat { compression: query_compression(&self.path(), conn, fs_conn).await?, encrypted })
+    }
+
+    /// Encrypts or decrypts the file's data, and that of its earlier versions, with the connection's
+    /// key. Later writes to the file keep its data encrypted. Snapshots keep the data as it was when
+    /// they were taken. The data replaced is overwritten with zeros as it is deleted; see the version
+    /// 17 migration for the plaintext this can't reach.
+    pub async fn set_encrypted(&self, encrypted: bool, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let path = self.path();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        sqlx::query("PRAGMA secure_delete = ON").execute(&mut *conn).await.map_err(FSError::database)?;
+        let result = self.convert_encrypted(&path, encrypted, &mut conn, fs_conn).await;
+        // The connection goes back to the pool, so the setting mustn't outlive the conversion.
+        sqlx::query("PRAGMA secure_delete = OFF").execute(&mut *conn).await.map_err(FSError::database)?;
+        result
+    }
+
+    async fn convert_encrypted(&self, path: &str, encrypted: bool, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await?;
+            let contents = QueryBuilder::new(format!(r#"
+                    SELECT hash, compression, encrypted FROM {contents} WHERE hash IN (
+                        SELECT content FROM {files} WHERE id=
+                "#, contents = fs_conn.content_table, files = fs_conn.file_table))
+                .push_bind(id)
+                .push(format!("UNION SELECT content FROM {} WHERE file=", fs_conn.history_table))
+                .push_bind(id)
+                .push(")")
+                .build()
+                .fetch_all(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            for row in contents {
+                let from = ContentFormat::from_row(&row)?;
+                if from.encrypted == encrypted {
+                    continue;
+                }
+                let old: String = row.get("hash");
+                let new = convert_content(&old, from, ContentFormat { encrypted, ..from }, &mut tx, fs_conn).await?;
+                for (table, column) in [(&fs_conn.file_table, "id"), (&fs_conn.history_table, "file")] {
+                    QueryBuilder::new(format!("UPDATE {} SET content=", table))
+                        .push_bind(&new)
+                        .push(format!("WHERE {}=", column))
+                        .push_bind(id)
+                        .push("AND content=")
+                        .push_bind(&old)
+                        .build()
+                        .execute(&mut *tx)
+                        .await
+                        .map_err(FSError::database)?;
+                }
+            }
+            index_compressed(Some(id), &mut tx, fs_conn).await?;
+            log_change(ChangeKind::Update, path, None, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            Ok(())
+        }.await;
+        finish(tx, result).await
+    }
+
+    async fn touch_accessed(id: i64, now: i64, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+        QueryBuilder::new(format!("UPDATE {} SET accessed=", fs_conn.file_table))
+            .push_bind(now)
+            .push("WHERE id=")
+            .push_bind(id)
+            .push("AND (accessed <= modified OR accessed <")
+            .push_bind(now - RELATIME_WINDOW)
+            .push(")")
+            .build()
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Reads up to `len` bytes of the file's data from `offset`, loading only the chunks that cover
+    /// them. Fewer bytes come back when the range runs past the end of the file. Unlike
+    /// [`File::read_bytes`], this leaves the access time alone, as it is typically called many times
+    /// per read of the file.
+    pub async fn read_at(&self, offset: u64, len: usize, fs_conn: &FSConnection) -> Result<Vec<u8>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut query = QueryBuilder::new(format!("SELECT content FROM {}", fs_conn.file_table));
+        self.push_where(&mut query, &mut conn, fs_conn).await?;
+        let content: String = query.build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&self.path())?
+            .get("content");
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let stored = QueryBuilder::new(format!("SELECT id, compression, encrypted FROM {} WHERE hash=", fs_conn.content_table))
+            .push_bind(&content)
+            .build()
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        let format = ContentFormat::from_row(&stored)?;
+
+        let (start, end, chunk) = (offset as i64, offset as i64 + len as i64, CHUNK_SIZE as i64);
+        if format != ContentFormat::default() {
+            // Compressed or encrypted chunks have to be loaded whole and cut down once decoded.
+            let content: i64 = stored.get("id");
+            let chunks = QueryBuilder::new(format!("SELECT seq, data FROM {} WHERE content=", fs_conn.chunk_table))
+                .push_bind(content)
+                .push("AND seq BETWEEN")
+                .push_bind(start / chunk)
+                .push("AND")
+                .push_bind((end - 1) / chunk)
+                .push("ORDER BY seq")
+                .build()
+                .fetch_all(&mut *conn)
+                .await
+                .map_err(FSError::database)?;
+            let mut data = vec![];
+            for row in chunks {
+                let seq: i64 = row.get("seq");
+                let chunk_start = seq * chunk;
+                let decoded = format.decode(content, seq, row.get("data"), fs_conn)?;
+                let from = ((start - chunk_start).max(0) as usize).min(decoded.len());
+                let to = ((end - chunk_start) as usize).min(decoded.len());
+                data.extend_from_slice(&decoded[from..to]);
+            }
+            return Ok(data);
+        }
+
+        // Each chunk is cut down to the part of it inside [start, end) before leaving the database.
+        let chunks = QueryBuilder::new("SELECT substr(data, MAX(")
+            .push_bind(start)
+            .push("- seq *")
+            .push_bind(chunk)
+            .push(", 0) + 1, MIN(")
+            .push_bind(end)
+            .push("- seq *")
+            .push_bind(chunk)
+            .push(",")
+            .push_bind(chunk)
+            .push(") - MAX(")
+            .push_bind(start)
+            .push("- seq *")
+            .push_bind(chunk)
+            .push(format!(", 0)) AS data FROM {} WHERE content=", fs_conn.chunk_table))
+            .push_bind(stored.get::<i64, &str>("id"))
+            .push("AND seq BETWEEN")
+            .push_bind(start / chunk)
+            .push("AND")
+            .push_bind((end - 1) / chunk)
+            .push("ORDER BY seq")
+            .build()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        Ok(chunks.iter().flat_map(|row| row.get::<Vec<u8>, &str>("data")).collect())
+    }
+
+    pub async fn write(&mut self, data: &str, ftype: FileType, fs_conn: &FSConnection) -> Result<(), FSError> {
+        self.write_bytes(data.as_bytes(), ftype, fs_conn).await
+    }
+
+    /// Replaces the file's data and type. The previous data is kept as a [`Version`].
+    pub async fn write_bytes(&mut self, data: &[u8], ftype: FileType, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let format = self.write_format(&mut tx, fs_conn).await?;
+            let content = store_content(data, format, &mut tx, fs_conn).await?;
+            self.replace(&content, &ftype, data.len() as i64, true, &mut tx, fs_conn).await
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Like [`File::write_bytes`], with the data read from `reader` and stored a chunk at a time, so
+    /// it never has to fit in memory.
+    pub async fn write_from<R: AsyncRead + Unpin + Send>(&mut self, reader: R, ftype: FileType, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let format = self.write_format(&mut tx, fs_conn).await?;
+            let (content, size) = stream_content(reader, format, &mut tx, fs_conn).await?;
+            self.replace(&content, &ftype, size, true, &mut tx, fs_conn).await
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Overwrites the file's data from `offset` with `data`, extending the file if needed and filling
+    /// any gap past the old end with zeros. The type is kept. Only the chunks the write touches are
+    /// read and stored again, one at a time, so small writes to a large file stay cheap.
+    ///
+    /// With `version`, the old data is kept as a [`Version`] first, as with [`File::write_bytes`], and
+    /// the unchanged chunks are copied over to the new data. Without it, data no version or other file
+    /// shares is patched in place. A series of writes, such as through an open handle, should ask for
+    /// a version only on its first write, so the history gets one version per series rather than one
+    /// per call. Writing nothing changes nothing.
+    ///
+    /// The new data is keyed by the data it was patched from and the patch rather than by its own
+    /// hash, which would mean reading all of it. It is therefore never shared with identical data,
+    /// and takes storage of its own until the file is next written whole, e.g. with
+    /// [`File::write_bytes`].
+    pub async fn write_at(&mut self, offset: u64, data: &[u8], version: bool, fs_conn: &FSConnection) -> Result<(), FSError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await?;
+            let row = QueryBuilder::new(format!("SELECT content, type FROM {} WHERE id=", fs_conn.file_table))
+                .push_bind(id)
+                .build()
+                .fetch_one(&mut *tx)
+                .await
+                .at(&self.path())?;
+            let ftype = FileType::from_str(row.get("type"))?;
+            let old_hash: String = row.get("content");
+            let old = QueryBuilder::new(format!("SELECT id, size, refs, compression, encrypted FROM {} WHERE hash=", fs_conn.content_table))
+                .push_bind(&old_hash)
+                .build()
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            let (old_id, old_size, refs): (i64, i64, i64) = (old.get("id"), old.get("size"), old.get("refs"));
+            let old_format = ContentFormat::from_row(&old)?;
+            let format = ContentFormat { compression: query_compression(&self.path(), &mut tx, fs_conn).await?, ..old_format };
+
+            let (start, end, old_size) = (offset as usize, offset as usize + data.len(), old_size as usize);
+            let size = end.max(old_size);
+            let hash = format!("{}{}", PATCHED_CONTENT, format.hasher(fs_conn)?
+                .chain_update(&old_hash)
+                .chain_update(offset.to_le_bytes())
+                .chain_update(data)
+                .finalize());
+            let stored = QueryBuilder::new(format!("SELECT EXISTS(SELECT 1 FROM {} WHERE hash=", fs_conn.content_table))
+                .push_bind(&hash)
+                .push(") AS stored")
+                .build()
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(FSError::database)?
+                .get::<bool, &str>("stored");
+            if stored {
+                return self.replace(&hash, &ftype, size as i64, version, &mut tx, fs_conn).await;
+            }
+
+            // The chunks from the first one the write or the zero fill past the old end touches, to the
+            // last one the write touches. Chunks stored with another compression all have to be redone,
+            // as do encrypted chunks copied to another content row, which are sealed to the row's id.
+            let in_place = !version && refs == 1;
+            let first = if end <= old_size { start } else { start.min(old_size) } / CHUNK_SIZE;
+            let last = (end - 1) / CHUNK_SIZE;
+            let rewrite = if format == old_format && (in_place || !format.encrypted) {
+                first..=last
+            } else {
+                0..=(size - 1) / CHUNK_SIZE
+            };
+            let target = if in_place {
+                old_id
+            } else {
+                let target = insert_content(&hash, size as i64, format, &mut tx, fs_conn).await
+                    .map_err(FSError::database)?
+                    .ok_or_else(|| FSError::database(sqlx::Error::Protocol(format!("content {} appeared while being written", hash))))?;
+                QueryBuilder::new(format!("INSERT INTO {chunks}(content, seq, data) SELECT ", chunks = fs_conn.chunk_table))
+                    .push_bind(target)
+                    .push(format!(", seq, data FROM {} WHERE content=", fs_conn.chunk_table))
+                    .push_bind(old_id)
+                    .push("AND seq NOT BETWEEN")
+                    .push_bind(*rewrite.start() as i64)
+                    .push("AND")
+                    .push_bind(*rewrite.end() as i64)
+                    .build()
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(FSError::database)?;
+                target
+            };
+            for seq in rewrite {
+                let (chunk_start, chunk_end) = (seq * CHUNK_SIZE, ((seq + 1) * CHUNK_SIZE).min(size));
+                let mut chunk = match seq * CHUNK_SIZE < old_size {
+                    true => old_format.decode(old_id, seq as i64, query_chunk(old_id, seq as i64, &mut tx, fs_conn).await.map_err(FSError::database)?, fs_conn)?,
+                    false => vec![],
+                };
+                chunk.resize(chunk_end - chunk_start, 0);
+                let (from, to) = (start.max(chunk_start), end.min(chunk_end));
+                if from < to {
+                    chunk[from - chunk_start..to - chunk_start].copy_from_slice(&data[from - start..to - start]);
+                }
+                insert_chunk(target, seq as i64, &format.encode(target, seq as i64, &chunk, fs_conn)?, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            }
+
+            if in_place {
+                // The file is the content's only reference, which would go with the old key. An extra
+                // reference keeps it while the file moves to the new key, which only exists once the
+                // row is renamed, hence the deferred foreign keys.
+                for statement in [
+                    format!("UPDATE {} SET refs=refs+1 WHERE id={}", fs_conn.content_table, old_id),
+                    "PRAGMA defer_foreign_keys = ON".to_string(),
+                ] {
+                    sqlx::query(&statement).execute(&mut *tx).await.map_err(FSError::database)?;
+                }
+                QueryBuilder::new(format!("UPDATE {} SET content=", fs_conn.file_table))
+                    .push_bind(&hash)
+                    .push("WHERE id=")
+                    .push_bind(id)
+                    .build()
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(FSError::database)?;
+                QueryBuilder::new(format!("UPDATE {} SET hash=", fs_conn.content_table))
+                    .push_bind(&hash)
+                    .push(", size=")
+                    .push_bind(size as i64)
+                    .push(", compression=")
+                    .push_bind(format.compression.column())
+                    .push("WHERE id=")
+                    .push_bind(old_id)
+                    .build()
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(FSError::database)?;
+                sqlx::query("PRAGMA defer_foreign_keys = OFF").execute(&mut *tx).await.map_err(FSError::database)?;
+            }
+            self.replace(&hash, &ftype, size as i64, version, &mut tx, fs_conn).await
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Points the file at `content`, first moving what it pointed at into the history table if
+    /// `version` is set.
+    async fn replace(&self, content: &str, ftype: &FileType, size: i64, version: bool, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let path = self.path();
+        let id = self.query_id(conn, fs_conn).await?;
+        let current = QueryBuilder::new(format!("SELECT directory, size FROM {} WHERE id=", fs_conn.file_table))
+            .push_bind(id)
+            .build()
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        if version {
+            QueryBuilder::new(format!(r#"
+                    INSERT INTO {history}(file,version,type,content,size,modified)
+                    SELECT id, (SELECT COALESCE(MAX(version), 0) + 1 FROM {history} WHERE file={files}.id), type, content, size, modified
+                    FROM {files} WHERE id=
+                "#, history = fs_conn.history_table, files = fs_conn.file_table))
+                .push_bind(id)
+                .build()
+                .execute(&mut *conn)
+                .await
+                .map_err(FSError::database)?;
+        }
+
+        QueryBuilder::new(format!(r#"
+                UPDATE {} SET content=
+            "#,fs_conn.file_table))
+            .push_bind(content)
+            .push(", type=")
+            .push_bind(ftype.to_string())
+            .push(", size=")
+            .push_bind(size)
+            .push(", modified=")
+            .push_bind(now())
+            .push("WHERE id=")
+            .push_bind(id)
+            .build()
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| FSError::from_sqlx_typed(e, &path, ftype))?;
+        index_compressed(Some(id), &mut *conn, fs_conn).await?;
+        log_change(ChangeKind::Update, &path, None, &mut *conn, fs_conn).await.map_err(FSError::database)?;
+
+        // Shrinking a file is always allowed, even in a directory that is already over quota.
+        if size > current.get::<i64, &str>("size") {
+            check_quotas(current.get("directory"), None, conn, fs_conn).await?;
+        }
+        Ok(())
+    }
+
+    /// Earlier versions of the file, oldest first.
+    pub async fn history(&self, fs_conn: &FSConnection) -> Result<Vec<Version>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await?;
+        QueryBuilder::new(format!(r#"
+                SELECT version,type,size,modified FROM {} WHERE file=
+            "#, fs_conn.history_table))
+            .push_bind(id)
+            .push("ORDER BY version")
+            .build()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(Version::from_row)
+            .collect::<Result<Vec<Version>, sqlx::Error>>()
+            .map_err(FSError::database)
+    }
+
+    async fn query_version(&self, version: i64, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<SqliteRow, FSError> {
+        let id = self.query_id(conn, fs_conn).await?;
+        QueryBuilder::new(format!(r#"
+                SELECT content,type,size FROM {} WHERE file=
+            "#, fs_conn.history_table))
+            .push_bind(id)
+            .push("AND version=")
+            .push_bind(version)
+            .build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&format!("{} version {}", self.path(), version))
+    }
+
+    /// Reads the data and type of an earlier version of the file.
+    pub async fn read_version(&self, version: i64, fs_conn: &FSConnection) -> Result<(Vec<u8>, String), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let row = self.query_version(version, &mut conn, fs_conn).await?;
+        let data = read_content(row.get("content"), &mut conn, fs_conn).await?;
+        Ok((data, row.get("type")))
+    }
+
+    /// Makes an earlier version the current one. The data being replaced is kept as a new version,
+    /// so a restore can itself be undone.
+    pub async fn restore(&mut self, version: i64, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let row = self.query_version(version, &mut tx, fs_conn).await?;
+            let ftype: String = row.get("type");
+            let ftype = FileType::from_str(&ftype)?;
+            self.replace(row.get("content"), &ftype, row.get("size"), true, &mut tx, fs_conn).await
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Makes the file a link to `target`. Relative targets are resolved from the link's directory.
+    pub async fn mk_link(&self, target: &Path, fs_conn: &FSConnection) -> Result<(), FSError> {
+        self.mk_bytes(target.to_string_lossy().as_bytes(), &FileType::Link, fs_conn).await
+    }
+
+    /// The target of a link, as it was stored. Fails with [`FSError::NotALink`] for other file types.
+    pub async fn read_link(&self, fs_conn: &FSConnection) -> Result<PathBuf, FSError> {
+        let (data, ftype) = self.read_bytes(fs_conn).await?;
+        if ftype != FileType::Link.to_string() {
+            return Err(FSError::NotALink(self.path()));
+        }
+        Ok(PathBuf::from(String::from_utf8_lossy(&data).to_string()))
+    }
+
+    pub async fn metadata(&self, fs_conn: &FSConnection) -> Result<Metadata, FSError> {
+        let path = self.path();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut query = QueryBuilder::new(format!("SELECT size,created,modified,accessed,mode,uid,gid FROM {}", fs_conn.file_table));
+        self.push_where(&mut query, &mut conn, fs_conn).await?;
+        let row = query.build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&path)?;
+        Metadata::from_row(&row).map_err(FSError::database)
+    }
+
+    /// The file's type and metadata in one query. Unlike [`File::open`], this leaves the access time
+    /// alone and works on encrypted files without a key, so it suits listings.
+    pub async fn stat(&self, fs_conn: &FSConnection) -> Result<(FileType, Metadata), FSError> {
+        let path = self.path();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut query = QueryBuilder::new(format!("SELECT type,size,created,modified,accessed,mode,uid,gid FROM {}", fs_conn.file_table));
+        self.push_where(&mut query, &mut conn, fs_conn).await?;
+        let row = query.build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&path)?;
+        Ok((FileType::from_str(row.get("type"))?, Metadata::from_row(&row).map_err(FSError::database)?))
+    }
+
+    /// Sets the permission bits reported for the file, e.g. `0o600`.
+    pub async fn set_mode(&self, mode: u32, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let mut query = QueryBuilder::new(format!("UPDATE {} SET mode=", fs_conn.file_table));
+            query.push_bind(mode & MODE_MASK);
+            self.push_where(&mut query, &mut tx, fs_conn).await?;
+            let changed = query.build()
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            affected(changed, &self.path())?;
+            log_change(ChangeKind::Update, &self.path(), None, &mut tx, fs_conn).await.map_err(FSError::database)
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Sets the owning user and group. `None` leaves ownership to whoever serves the file.
+    pub async fn set_owner(&self, uid: Option<u32>, gid: Option<u32>, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let mut query = QueryBuilder::new(format!("UPDATE {} SET uid=", fs_conn.file_table));
+            query.push_bind(uid)
+                .push(", gid=")
+                .push_bind(gid);
+            self.push_where(&mut query, &mut tx, fs_conn).await?;
+            let changed = query.build()
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            affected(changed, &self.path())?;
+            log_change(ChangeKind::Update, &self.path(), None, &mut tx, fs_conn).await.map_err(FSError::database)
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// The value of attribute `key`, or `None` if it isn't set.
+    pub async fn attr(&self, key: &str, fs_conn: &FSConnection) -> Result<Option<Vec<u8>>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await?;
+        get_attr(&fs_conn.file_attr_table, id, key, &mut conn).await
+    }
+
+    pub async fn attrs(&self, fs_conn: &FSConnection) -> Result<BTreeMap<String, Vec<u8>>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await?;
+        get_attrs(&fs_conn.file_attr_table, id, &mut conn).await
+    }
+
+    /// Sets attribute `key`, replacing any value it already has.
+    pub async fn set_attr(&self, key: &str, value: &[u8], fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await?;
+            set_attr(&fs_conn.file_attr_table, id, key, value, &mut tx).await?;
+            log_change(ChangeKind::Update, &self.path(), None, &mut tx, fs_conn).await.map_err(FSError::database)
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Removes attribute `key`. Fails with [`FSError::NotFound`] if it isn't set.
+    pub async fn remove_attr(&self, key: &str, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await?;
+            remove_attr(&fs_conn.file_attr_table, id, key, &self.path(), &mut tx).await?;
+            log_change(ChangeKind::Update, &self.path(), None, &mut tx, fs_conn).await.map_err(FSError::database)
+        }.await;
+        finish(tx, result).await
+    }
+}
+
+pub struct Directory {
+    pub path: String,
+    /// Set once the directory has been looked up by id, see [`Directory::resolve`].
+    id: Option<i64>,
+}
+
+impl Directory {
+    fn path_to_str(path: PathBuf) -> Result<String, FSError> {
+        match path.absolutize_virtually("/") {
+            Ok(path) => {
+                let path = path.display().to_string();
+                if path.ends_with('/') {
+                    Ok(path)
+                } else {
+                    Ok(format!("{}/", path))
+                }
+            },
+            Err(_) => Err(FSError::PathIsNotADir(path.display().to_string())),
+        }
+    }
+
+    pub fn new(path: PathBuf) -> Result<Directory, FSError>{
+        Ok(Directory{path: Directory::path_to_str(path)?, id: None})
+    }
+
+    /// The names of each directory on the path from the root, excluding the root itself.
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.path.split('/').filter(|name| !name.is_empty())
+    }
+
+    fn parent(&self) -> Option<Directory> {
+        let path = PathBuf::from_str(&self.path).ok()?;
+        path.parent().and_then(|parent| Directory::new(parent.to_path_buf()).ok())
+    }
+
+    fn name(&self) -> &str {
+        self.names().last().unwrap_or("")
+    }
+
+    async fn query_path(id: i64, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Option<String>, sqlx::Error> {
+        let rows = QueryBuilder::new(format!(r#"
+                WITH RECURSIVE ancestors(id, parent, name, depth) AS (
+                    SELECT id, parent, name, 0 FROM {} WHERE id=
+            "#, fs_conn.dir_table))
+            .push_bind(id)
+            .push(format!(r#"
+                    UNION ALL
+                    SELECT d.id, d.parent, d.name, a.depth + 1 FROM {} d JOIN ancestors a ON d.id = a.parent
+                )
+                SELECT parent, name FROM ancestors ORDER BY depth DESC
+            "#, fs_conn.dir_table))
+            .build()
+            .fetch_all(conn)
+            .await?;
+
+        let mut path = String::from("/");
+        for (i, row) in rows.iter().enumerate() {
+            let name: String = row.get("name");
+            if i == 0 {
+                // Only the root directory has no parent and no name.
+                if row.get::<Option<i64>, &str>("parent").is_some() || !name.is_empty() {
+                    return Ok(None);
+                }
+                continue;
+            }
+            path.push_str(&name);
+            path.push('/');
+        }
+        Ok(if rows.is_empty() { None } else { Some(path) })
+    }
+
+    async fn query_from_id(id: i64, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Directory, FSError> {
+        match Directory::query_path(id, conn, fs_conn).await.map_err(FSError::database)? {
+            Some(path) => Ok(Directory{path, id: Some(id)}),
+            None => Err(FSError::NotFound(format!("directory {}", id))),
+        }
+    }
+
+    pub async fn from_id(id: i64, fs_conn: &FSConnection) -> Result<Directory, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        Directory::query_from_id(id, &mut conn, fs_conn).await
+    }
+
+    pub fn root() -> Directory {
+        Directory { path: "/".to_string(), id: None }
+    }
+
+    async fn query_root_id(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<i64, sqlx::Error> {
+        Ok(QueryBuilder::new(format!(r#"
+                SELECT id FROM {} WHERE parent IS NULL AND name=""
+            "#, fs_conn.dir_table))
+            .build()
+            .fetch_one(conn)
+            .await?
+            .get("id"))
+    }
+
+    async fn query_child_id(parent: i64, name: &str, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<i64, sqlx::Error> {
+        Ok(QueryBuilder::new(format!(r#"
+                SELECT id FROM {} WHERE parent=
+            "#, fs_conn.dir_table))
+            .push_bind(parent)
+            .push("AND name=")
+            .push_bind(name)
+            .build()
+            .fetch_one(conn)
+            .await?
+            .get("id"))
+    }
+
+    async fn query_id(&self, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<i64, sqlx::Error> {
+        if let Some(id) = self.id {
+            return Ok(id);
+        }
+        let mut id = Directory::query_root_id(conn, fs_conn).await?;
+        for name in self.names() {
+            id = Directory::query_child_id(id, name, conn, fs_conn).await?;
+        }
+        Ok(id)
+    }
+
+    pub async fn get_id(&self, fs_conn: &FSConnection) -> Result<i64, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        self.query_id(&mut conn, fs_conn).await.at(&self.path)
+    }
+
+    /// The directory's id, if the handle has one cached.
+    pub fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    /// Looks up the directory's id and keeps it on the handle, so later calls, and files made with
+    /// [`Directory::file`], skip resolving the path. The handle then follows the directory through
+    /// moves; once the directory is deleted, the handle should be dropped.
+    pub async fn resolve(&mut self, fs_conn: &FSConnection) -> Result<i64, FSError> {
+        let id = self.get_id(fs_conn).await?;
+        self.id = Some(id);
+        Ok(id)
+    }
+
+    pub async fn exists(&self, fs_conn: &FSConnection) -> Result<bool, FSError> {
+        match self.get_id(fs_conn).await {
+            Ok(_) => Ok(true),
+            Err(FSError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Makes the directory. Its parent must already exist.
+    pub async fn mk(&self, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let now = now();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let parent = match self.parent() {
+                Some(parent) => Some(parent.query_id(&mut tx, fs_conn).await.at(&parent.path)?),
+                None => None,
+            };
+            QueryBuilder::new(format!(r#"
+                    INSERT INTO {}(parent, name, created, modified, accessed) VALUES(
+                "#, fs_conn.dir_table))
+                .push_bind(parent)
+                .push(",")
+                .push_bind(self.name())
+                .push(",")
+                .push_bind(now)
+                .push(",")
+                .push_bind(now)
+                .push(",")
+                .push_bind(now)
+                .push(");")
+                .build()
+                .execute(&mut *tx)
+                .await
+                .at(&self.path)?;
+            log_change(ChangeKind::Create, &self.path, None, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            if let Some(parent) = parent {
+                check_quotas(parent, None, &mut tx, fs_conn).await?;
+                Directory::touch(parent, now, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            }
+            Ok(())
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Bumps the modification time of the directory with `id`, as happens when an entry in it is
+    /// added, removed or renamed.
+    async fn touch(id: i64, now: i64, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+        QueryBuilder::new(format!("UPDATE {} SET modified=", fs_conn.dir_table))
+            .push_bind(now)
+            .push("WHERE id=")
+            .push_bind(id)
+            .build()
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn metadata(&self, fs_conn: &FSConnection) -> Result<Metadata, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        let row = QueryBuilder::new(format!(r#"
+                SELECT 0 AS size,created,modified,accessed,mode,uid,gid FROM {} WHERE id=
+            "#, fs_conn.dir_table))
+            .push_bind(id)
+            .build()
+            .fetch_one(&mut *conn)
+            .await
+            .at(&self.path)?;
+        Metadata::from_row(&row).map_err(FSError::database)
+    }
+
+    /// Sets the permission bits reported for the directory, e.g. `0o755`.
+    pub async fn set_mode(&self, mode: u32, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await.at(&self.path)?;
+            QueryBuilder::new(format!("UPDATE {} SET mode=", fs_conn.dir_table))
+                .push_bind(mode & MODE_MASK)
+                .push("WHERE id=")
+                .push_bind(id)
+                .build()
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            log_change(ChangeKind::Update, &self.path, None, &mut tx, fs_conn).await.map_err(FSError::database)
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Sets the owning user and group. `None` leaves ownership to whoever serves the directory.
+    pub async fn set_owner(&self, uid: Option<u32>, gid: Option<u32>, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await.at(&self.path)?;
+            QueryBuilder::new(format!("UPDATE {} SET uid=", fs_conn.dir_table))
+                .push_bind(uid)
+                .push(", gid=")
+                .push_bind(gid)
+                .push("WHERE id=")
+                .push_bind(id)
+                .build()
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            log_change(ChangeKind::Update, &self.path, None, &mut tx, fs_conn).await.map_err(FSError::database)
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// The value of attribute `key`, or `None` if it isn't set.
+    pub async fn attr(&self, key: &str, fs_conn: &FSConnection) -> Result<Option<Vec<u8>>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        get_attr(&fs_conn.dir_attr_table, id, key, &mut conn).await
+    }
+
+    pub async fn attrs(&self, fs_conn: &FSConnection) -> Result<BTreeMap<String, Vec<u8>>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        get_attrs(&fs_conn.dir_attr_table, id, &mut conn).await
+    }
+
+    /// Sets attribute `key`, replacing any value it already has.
+    pub async fn set_attr(&self, key: &str, value: &[u8], fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await.at(&self.path)?;
+            set_attr(&fs_conn.dir_attr_table, id, key, value, &mut tx).await?;
+            log_change(ChangeKind::Update, &self.path, None, &mut tx, fs_conn).await.map_err(FSError::database)
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Removes attribute `key`. Fails with [`FSError::NotFound`] if it isn't set.
+    pub async fn remove_attr(&self, key: &str, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await.at(&self.path)?;
+            remove_attr(&fs_conn.dir_attr_table, id, key, &self.path, &mut tx).await?;
+            log_change(ChangeKind::Update, &self.path, None, &mut tx, fs_conn).await.map_err(FSError::database)
+        }.await;
+        finish(tx, result).await
+    }
+
+    async fn query_usage(id: i64, conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<Usage, sqlx::Error> {
+        let row = QueryBuilder::new("WITH RECURSIVE tree(id) AS (SELECT ")
+            .push_bind(id)
+            .push(format!(r#"
+                    UNION ALL
+                    SELECT d.id FROM {dirs} d JOIN tree t ON d.parent = t.id
+                )
+                SELECT (SELECT COALESCE(SUM(size), 0) FROM {files} WHERE directory IN (SELECT id FROM tree)) AS bytes,
+                    (SELECT COUNT(*) FROM {files} WHERE directory IN (SELECT id FROM tree)) + (SELECT COUNT(*) - 1 FROM tree) AS entries
+            "#, dirs = fs_conn.dir_table, files = fs_conn.file_table))
+            .build()
+            .fetch_one(conn)
+            .await?;
+        Ok(Usage {
+            bytes: row.get::<i64, &str>("bytes") as u64,
+            entries: row.get::<i64, &str>("entries") as u64,
+        })
+    }
+
+    pub async fn usage(&self, fs_conn: &FSConnection) -> Result<Usage, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        Directory::query_usage(id, &mut conn, fs_conn).await.map_err(FSError::database)
+    }
+
+    /// The quota set on this directory, if any. Quotas on its ancestors apply too but aren't included.
+    pub async fn quota(&self, fs_conn: &FSConnection) -> Result<Option<Quota>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        Ok(QueryBuilder::new(format!("SELECT max_bytes, max_entries FROM {} WHERE directory=", fs_conn.quota_table))
+            .push_bind(id)
+            .build()
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .map(|row| Quota {
+                max_bytes: row.get::<Option<i64>, &str>("max_bytes").map(|max| max as u64),
+                max_entries: row.get::<Option<i64>, &str>("max_entries").map(|max| max as u64),
+            }))
+    }
+
+    /// Sets the quota on this directory, replacing any it had. Making, writing or moving files and
+    /// directories that would take the subtree over it fails with [`FSError::QuotaExceeded`]; what is
+    /// already there when the quota is set is left alone.
+    pub async fn set_quota(&self, quota: &Quota, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        QueryBuilder::new(format!("INSERT INTO {}(directory, max_bytes, max_entries) VALUES(", fs_conn.quota_table))
+            .push_bind(id)
+            .push(",")
+            .push_bind(quota.max_bytes.map(|max| max as i64))
+            .push(",")
+            .push_bind(quota.max_entries.map(|max| max as i64))
+            .push(") ON CONFLICT(directory) DO UPDATE SET max_bytes=excluded.max_bytes, max_entries=excluded.max_entries")
+            .build()
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        Ok(())
+    }
+
+    /// Removes the quota on this directory. Fails with [`FSError::NotFound`] if it has none.
+    pub async fn remove_quota(&self, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        let changed = QueryBuilder::new(format!("DELETE FROM {} WHERE directory=", fs_conn.quota_table))
+            .push_bind(id)
+            .build()
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        affected(changed, &format!("quota on {}", self.path))
+    }
+
+    /// Rewrites the stored data of the files below this directory, and of their earlier versions,
+    /// with the compression their paths now call for. Data shared with other files follows the rules
+    /// for whichever path comes first. Returns how many contents were rewritten.
+    pub async fn recompress(&self, fs_conn: &FSConnection) -> Result<u64, FSError> {
+        let (files, _) = self.recurse(fs_conn).await?;
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let mut seen = HashSet::new();
+            let mut rewritten = 0;
+            for file in &files {
+                let compression = query_compression(&file.path, &mut tx, fs_conn).await?;
+                let contents = QueryBuilder::new(format!(r#"
+                        SELECT id, compression, encrypted FROM {contents} WHERE hash IN (
+                            SELECT content FROM {files} WHERE id=
+                    "#, contents = fs_conn.content_table, files = fs_conn.file_table))
+                    .push_bind(file.id)
+                    .push(format!("UNION SELECT content FROM {} WHERE file=", fs_conn.history_table))
+                    .push_bind(file.id)
+                    .push(")")
+                    .build()
+                    .fetch_all(&mut *tx)
+                    .await
+                    .map_err(FSError::database)?;
+                for row in contents {
+                    let id: i64 = row.get("id");
+                    let current = ContentFormat::from_row(&row)?;
+                    if seen.insert(id) && current.compression != compression {
+                        recompress_content(id, current, compression, &mut tx, fs_conn).await?;
+                        rewritten += 1;
+                    }
+                }
+            }
+            Ok(rewritten)
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Moves the directory to the trash. A non-recursive delete fails with [`FSError::NotEmpty`] if the
+    /// directory has any files or subdirectories; a recursive delete trashes the whole subtree as one entry.
+    pub async fn del(&self, recursive: bool, fs_conn: &FSConnection) -> Result<(), FSError> {
+        if self.path == "/" {
+            return Err(FSError::CannotDeleteRoot);
+        }
+
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await.at(&self.path)?;
+
+            if !recursive {
+                let not_empty = QueryBuilder::new(format!(r#"
+                        SELECT EXISTS(SELECT 1 FROM {} WHERE directory=
+                    "#, fs_conn.file_table))
+                    .push_bind(id)
+                    .push(format!(") OR EXISTS(SELECT 1 FROM {} WHERE parent=", fs_conn.dir_table))
+                    .push_bind(id)
+                    .push(") AS not_empty")
+                    .build()
+                    .fetch_one(&mut *tx)
+                    .await
+                    .map_err(FSError::database)?
+                    .get::<bool, &str>("not_empty");
+                if not_empty {
+                    return Err(FSError::NotEmpty(self.path.clone()));
+                }
+            }
+
+            // Subdirectories and files go with it.
+            let container = trash_container(&self.path, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            QueryBuilder::new(format!("UPDATE {} SET parent=", fs_conn.dir_table))
+                .push_bind(container)
+                .push("WHERE id=")
+                .push_bind(id)
+                .build()
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            log_change(ChangeKind::Delete, &self.path, None, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            if let Some(parent) = self.parent() {
+                let parent = parent.query_id(&mut tx, fs_conn).await.at(&parent.path)?;
+                Directory::touch(parent, now(), &mut tx, fs_conn).await.map_err(FSError::database)?;
+            }
+
+            Ok(())
+        }.await;
+        finish(tx, result).await
+    }
+
+    /// Moves the directory, and everything in it, to `path`. The new parent must exist and must not
+    /// be inside the directory being moved.
+    pub async fn mv(&mut self, path: &Directory, fs_conn: &FSConnection) -> Result<(), FSError> {
+        let parent = match path.parent() {
+            Some(parent) => parent,
+            None => return Err(FSError::MoveIntoSelf(path.path.clone())),
+        };
+        if path.path.starts_with(&self.path) {
+            return Err(FSError::MoveIntoSelf(path.path.clone()));
+        }
+
+        let now = now();
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let id = self.query_id(&mut tx, fs_conn).await.at(&self.path)?;
+            let parent = parent.query_id(&mut tx, fs_conn).await.at(&parent.path)?;
+            let old_parent = match self.parent() {
+                Some(old_parent) => Some(old_parent.query_id(&mut tx, fs_conn).await.at(&old_parent.path)?),
+                None => None,
+            };
+            QueryBuilder::new(format!("UPDATE {} SET parent=",fs_conn.dir_table))
+                .push_bind(parent)
+                .push(", name=")
+                .push_bind(path.name())
+                .push("WHERE id=")
+                .push_bind(id)
+                .build()
+                .execute(&mut *tx)
+                .await
+                .at(&path.path)?;
+            log_change(ChangeKind::Move, &path.path, Some(&self.path), &mut tx, fs_conn).await.map_err(FSError::database)?;
+            check_quotas(parent, old_parent, &mut tx, fs_conn).await?;
+            for dir in old_parent.into_iter().chain([parent]) {
+                Directory::touch(dir, now, &mut tx, fs_conn).await.map_err(FSError::database)?;
+            }
+            Ok(())
+        }.await;
+        finish(tx, result).await?;
+
+        self.path = path.path.clone();
+        Ok(())
+    }
+
+    pub fn rename(&self, name: &str) -> Result<PathBuf, FSError> {
+        let mut path = match PathBuf::from_str(&self.path) {
+            Ok(path) => path,
+            Err(_) => return Err(FSError::PathIsNotADir(self.path.clone())),
+        };
+        let new_name = match PathBuf::from_str(name) {
+            Ok(path) => path,
+            Err(_) => return Err(FSError::PathIsNotADir(name.to_string())),
+        };
+        path.pop();
+        path.push(new_name);
+
+        Ok(path)
+    }
+
+    pub async fn files(&self, fs_conn: &FSConnection) -> Result<Vec<FileEntry>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        QueryBuilder::new("SELECT id, name, type, size, created, modified, accessed, mode, uid, gid, ")
+            .push_bind(&self.path)
+            .push(format!(" AS directory FROM {} WHERE directory=", fs_conn.file_table))
+            .push_bind(id)
+            .build()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(FileEntry::from_row)
+            .collect::<Result<Vec<FileEntry>, sqlx::Error>>()
+            .map_err(FSError::database)
+    }
+
+    pub async fn dirs(&self, fs_conn: &FSConnection) -> Result<Vec<DirEntry>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        QueryBuilder::new("SELECT id, name, 0 AS size, created, modified, accessed, mode, uid, gid, ")
+            .push_bind(&self.path)
+            .push(format!(r#" || name || "/" AS directory FROM {} WHERE parent="#, fs_conn.dir_table))
+            .push_bind(id)
+            .build()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(DirEntry::from_row)
+            .collect::<Result<Vec<DirEntry>, sqlx::Error>>()
+            .map_err(FSError::database)
+    }
+
+    pub async fn contents(&self, fs_conn: &FSConnection) -> Result<(Vec<FileEntry>, Vec<DirEntry>), FSError>  {
+        Ok((self.files(fs_conn).await?, self.dirs(fs_conn).await?))
+    }
+
+    /// Lists every file and directory below this one, at any depth.
+    pub async fn recurse(&self, fs_conn: &FSConnection) -> Result<(Vec<FileEntry>, Vec<DirEntry>), FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        let tree = |query: &str| {
+            let mut builder = QueryBuilder::new("WITH RECURSIVE tree(id, name, directory) AS (SELECT ");
+            builder.push_bind(id)
+                .push(",")
+                .push_bind(self.name().to_string())
+                .push(",")
+                .push_bind(self.path.clone())
+                .push(format!(r#"
+                        UNION ALL
+                        SELECT d.id, d.name, t.directory || d.name || "/" FROM {} d JOIN tree t ON d.parent = t.id
+                    )
+                "#, fs_conn.dir_table))
+                .push(query);
+            builder
+        };
+
+        let files = tree(&format!("SELECT f.id, f.name, f.type, f.size, f.created, f.modified, f.accessed, f.mode, f.uid, f.gid, t.directory FROM {} f JOIN tree t ON f.directory = t.id", fs_conn.file_table))
+            .build()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(FileEntry::from_row)
+            .collect::<Result<Vec<FileEntry>, sqlx::Error>>()
+            .map_err(FSError::database)?;
+        let dirs = tree(&format!(r#"
+                SELECT t.id, t.name, t.directory, 0 AS size, d.created, d.modified, d.accessed, d.mode, d.uid, d.gid
+                FROM tree t JOIN {} d ON d.id = t.id WHERE t.id!=
+            "#, fs_conn.dir_table))
+            .push_bind(id)
+            .build()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(DirEntry::from_row)
+            .collect::<Result<Vec<DirEntry>, sqlx::Error>>()
+            .map_err(FSError::database)?;
+        Ok((files, dirs))
+    }
+
+    /// Lists the files below this directory that pass every filter in `find`.
+    pub async fn find(&self, find: &Find, fs_conn: &FSConnection) -> Result<Vec<FileEntry>, FSError> {
+        let mut conn = fs_conn.acquire().await.map_err(FSError::database)?;
+        let id = self.query_id(&mut conn, fs_conn).await.at(&self.path)?;
+        let max_depth = find.max_depth.map(i64::from).unwrap_or(i64::MAX);
+
+        // Directories at depth d hold files at depth d + 1, so the walk stops a level early.
+        let mut query = QueryBuilder::new("WITH RECURSIVE tree(id, directory, depth) AS (SELECT ");
+        query.push_bind(id)
+            .push(",")
+            .push_bind(&self.path)
+            .push(format!(r#", 0
+                    UNION ALL
+                    SELECT d.id, t.directory || d.name || "/", t.depth + 1 FROM {} d JOIN tree t ON d.parent = t.id WHERE t.depth + 1 <
+                "#, fs_conn.dir_table))
+            .push_bind(max_depth)
+            .push(format!(r#")
+                SELECT f.id, f.name, f.type, f.size, f.created, f.modified, f.accessed, f.mode, f.uid, f.gid, t.directory
+                FROM {} f JOIN tree t ON f.directory = t.id WHERE t.depth <
+            "#, fs_conn.file_table))
+            .push_bind(max_depth);
+        if let Some(name) = &find.name {
+            query.push(" AND f.name GLOB ").push_bind(name);
+        }
+        if let Some(ftype) = &find.ftype {
+            query.push(" AND f.type = ").push_bind(ftype.to_string());
+        }
+        if let Some(size) = find.min_size {
+            query.push(" AND f.size >= ").push_bind(size as i64);
+        }
+        if let Some(size) = find.max_size {
+            query.push(" AND f.size <= ").push_bind(size as i64);
+        }
+        if let Some(time) = find.modified_after {
+            query.push(" AND f.modified >= ").push_bind(to_timestamp(time));
+        }
+        if let Some(time) = find.modified_before {
+            query.push(" AND f.modified < ").push_bind(to_timestamp(time));
+        }
+        let order = if find.descending { "DESC" } else { "ASC" };
+        query.push(match find.sort {
+            SortBy::Path => format!(" ORDER BY t.directory || f.name {}", order),
+            SortBy::Name => format!(" ORDER BY f.name {}, t.directory || f.name", order),
+            SortBy::Size => format!(" ORDER BY f.size {}, t.directory || f.name", order),
+            SortBy::Modified => format!(" ORDER BY f.modified {}, t.directory || f.name", order),
+        });
+        if let Some(limit) = find.limit {
+            query.push(" LIMIT ").push_bind(limit);
+        }
+
+        query.build()
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(FileEntry::from_row)
+            .collect::<Result<Vec<FileEntry>, sqlx::Error>>()
+            .map_err(FSError::database)
+    }
+
+    /// Copies or links the tree under the host directory `host` into this directory, in one
+    /// transaction. The directory is made if it doesn't exist, but its parent must. Existing
+    /// directories are merged into, while a file that already exists fails the whole import with
+    /// [`FSError::AlreadyExists`]. Host symlinks are skipped rather than followed. Fails with
+    /// [`FSError::NestedTransaction`] if `fs_conn` is a transaction.
+    pub async fn import(&self, host: &Path, import: &Import, fs_conn: &FSConnection) -> Result<ImportReport, FSError> {
+        let (include, exclude) = (glob_set(&import.include)?, glob_set(&import.exclude)?);
+        let host = tokio::fs::canonicalize(host).await.map_err(FSError::Io)?;
+        if host.to_str().is_none() {
+            return Err(FSError::InvalidData(host.display().to_string()));
+        }
+
+        // Walk the host tree before opening the transaction, so it isn't held across the walk.
+        let mut report = ImportReport::default();
+        let (mut dirs, mut files) = (vec![], vec![]);
+        let mut pending = vec![PathBuf::new()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(host.join(&dir)).await.map_err(FSError::Io)?;
+            while let Some(entry) = entries.next_entry().await.map_err(FSError::Io)? {
+                let relative = dir.join(entry.file_name());
+                let kind = entry.file_type().await.map_err(FSError::Io)?;
+                if entry.file_name().to_str().is_none() || exclude.is_match(&relative) {
+                    report.skipped.push(entry.path());
+                } else if kind.is_dir() {
+                    dirs.push(relative.clone());
+                    pending.push(relative);
+                } else if kind.is_file() && (import.include.is_empty() || include.is_match(&relative)) {
+                    files.push(relative);
+                } else {
+                    report.skipped.push(entry.path());
+                }
+            }
+        }
+        if !import.include.is_empty() {
+            let needed: HashSet<&Path> = files.iter().flat_map(|file| file.ancestors().skip(1)).collect();
+            dirs.retain(|dir| needed.contains(dir.as_path()));
+        }
+        dirs.sort();
+        files.sort();
+        report.skipped.sort();
+
+        let root = PathBuf::from(&self.path);
+        if import.dry_run {
+            // Nothing is made, so only entries that already exist, or a missing parent, can fail it.
+            if !self.exists(fs_conn).await? {
+                if let Some(parent) = self.parent() {
+                    if !parent.exists(fs_conn).await? {
+                        return Err(FSError::NotFound(parent.path));
+                    }
+                }
+                report.dirs.push(self.path.clone());
+            }
+            for relative in &dirs {
+                let dir = Directory::new(root.join(relative))?;
+                if !dir.exists(fs_conn).await? {
+                    report.dirs.push(dir.path);
+                }
+            }
+            for relative in &files {
+                let (file, imported) = import_target(&host, &root, relative, import.mode).await?;
+                if file.exists(fs_conn).await? {
+                    return Err(FSError::AlreadyExists(imported.path));
+                }
+                report.files.push(imported);
+            }
+            return Ok(report);
+        }
+
+        let tx = fs_conn.transaction().await?;
+        let result = async {
+            if !self.exists(&tx).await? {
+                self.mk(&tx).await?;
+                report.dirs.push(self.path.clone());
+            }
+            for relative in &dirs {
+                let dir = Directory::new(root.join(relative))?;
+                if !dir.exists(&tx).await? {
+                    dir.mk(&tx).await?;
+                    report.dirs.push(dir.path);
+                }
+            }
+            for relative in &files {
+                let (file, imported) = import_target(&host, &root, relative, import.mode).await?;
+                match import.mode {
+                    // The host file is read a second time to store it, so it never has to fit in memory.
+                    ImportMode::Copy => {
+                        let host_file = tokio::fs::File::open(&imported.host).await.map_err(FSError::Io)?;
+                        file.mk_from(host_file, &imported.ftype, &tx).await?;
+                    },
+                    ImportMode::Link => file.mk(&imported.host.to_string_lossy(), &FileType::File, &tx).await?,
+                }
+                report.files.push(imported);
+            }
+            Ok(())
+        }.await;
+        match result {
+            Ok(()) => tx.commit().await?,
+            Err(e) => {
+                tx.rollback().await?;
+                return Err(e);
+            },
+        }
+        Ok(report)
+    }
+
+    /// Writes the tree under this directory to the host directory `host`, making it if needed and
+    /// overwriting files already there. Text and blobs are written out, [`FileType::File`] targets
+    /// are copied, links become host symlinks and custom files are rendered by their handler.
+    /// Absolute link targets are made relative to the link, so they resolve inside the export.
+    pub async fn export(&self, host: &Path, export: &Export, fs_conn: &FSConnection) -> Result<ExportReport, FSError> {
+        let (mut files, mut dirs) = self.recurse(fs_conn).await?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        dirs.sort_by(|a, b| a.path.cmp(&b.path));
+        let host_path = |path: &str| host.join(path.strip_prefix(self.path.as_str()).unwrap_or(path));
+
+        let mut report = ExportReport::default();
+        tokio::fs::create_dir_all(host).await.map_err(FSError::Io)?;
+        for dir in &dirs {
+            let target = host_path(&dir.path);
+            tokio::fs::create_dir_all(&target).await.map_err(FSError::Io)?;
+            report.dirs.push(target);
+        }
+        for entry in files {
+            let target = host_path(&entry.path);
+            let file = File::new(PathBuf::from(&entry.path))?;
+            let mut mode = entry.metadata.mode;
+            let size = match &entry.ftype {
+                FileType::Text | FileType::Blob => {
+                    let mut out = tokio::fs::File::create(&target).await.map_err(FSError::Io)?;
+                    let mut offset = 0;
+                    loop {
+                        let data = file.read_at(offset, CHUNK_SIZE, fs_conn).await?;
+                        if data.is_empty() {
+                            break;
+                        }
+                        out.write_all(&data).await.map_err(FSError::Io)?;
+                        offset += data.len() as u64;
+                    }
+                    out.flush().await.map_err(FSError::Io)?;
+                    offset
+                },
+                FileType::File => {
+                    let (source, _) = file.read(fs_conn).await?;
+                    tokio::fs::copy(source, &target).await.map_err(FSError::Io)?
+                },
+                FileType::Link => {
+                    let link = host_relative_target(&file.directory.path, &file.read_link(fs_conn).await?);
+                    if tokio::fs::symlink_metadata(&target).await.is_ok() {
+                        tokio::fs::remove_file(&target).await.map_err(FSError::Io)?;
+                    }
+                    tokio::fs::symlink(&link, &target).await.map_err(FSError::Io)?;
+                    report.files.push(ExportedFile { path: entry.path, host: target, ftype: entry.ftype, size: link.as_os_str().len() as u64 });
+                    // A symlink has no permissions or times of its own to set.
+                    continue;
+                },
+                FileType::Exec => {
+                    let (command, _) = file.read(fs_conn).await?;
+                    let data = match export.exec {
+                        ExportExec::Script => {
+                            mode |= 0o111;
+                            format!("#!/usr/bin/env bash\n{}\n", command.trim_end()).into_bytes()
+                        },
+                        ExportExec::Run => run_handler(&command, &entry.path, &[]).await?,
+                    };
+                    tokio::fs::write(&target, &data).await.map_err(FSError::Io)?;
+                    if export.exec == ExportExec::Script && !export.preserve_metadata {
+                        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755)).map_err(FSError::Io)?;
+                    }
+                    data.len() as u64
+                },
+                FileType::Custom(_) => {
+                    let (data, _) = file.read_bytes(fs_conn).await?;
+                    let data = fs_conn.render_custom(&entry.ftype, &entry.path, &data).await?;
+                    tokio::fs::write(&target, &data).await.map_err(FSError::Io)?;
+                    data.len() as u64
+                },
+            };
+            if export.preserve_metadata {
+                set_host_metadata(&target, mode, &entry.metadata).map_err(FSError::Io)?;
+            }
+            report.files.push(ExportedFile { path: entry.path, host: target, ftype: entry.ftype, size });
+        }
+
+        // Directories last and deepest first, as writing into them bumps their modification time.
+        if export.preserve_metadata {
+            for dir in dirs.iter().rev() {
+                set_host_metadata(&host_path(&dir.path), dir.metadata.mode, &dir.metadata).map_err(FSError::Io)?;
+            }
+            let metadata = self.metadata(fs_conn).await?;
+            set_host_metadata(host, metadata.mode, &metadata).map_err(FSError::Io)?;
+        }
+        Ok(report)
+    }
+
+    pub fn file(&self, name: &str) -> File  {
+        File{name: name.to_string(), directory: Directory { path: self.path.clone(), id: self.id }, id: None}
+    }
+
+    pub fn dir(&self, name: &str) -> Result<Directory, FSError>  {
+        let mut path = PathBuf::from_str(&self.path).map_err(|_| FSError::PathIsNotADir(self.path.to_string()))?;
+        path.push(name);
+        Directory::new(path)
+    }
+
+}
+
+/// A connection checked out for a single operation: either a fresh pool connection or the
+/// connection held by an open [`FSTransaction`].
+enum FSConn<'c> {
+    Pool(PoolConnection<Sqlite>),
+    Transaction(MutexGuard<'c, Transaction<'static, Sqlite>>),
+}
+
+impl Deref for FSConn<'_> {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            FSConn::Pool(conn) => conn,
+            FSConn::Transaction(tx) => tx,
+        }
+    }
+}
+
+impl DerefMut for FSConn<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            FSConn::Pool(conn) => conn,
+            FSConn::Transaction(tx) => tx,
+        }
+    }
+}
+
+/// A group of `File` and `Directory` operations that commit or roll back together.
+///
+/// Derefs to an [`FSConnection`], so it can be passed anywhere a connection is expected.
+/// Dropping it without calling [`FSTransaction::commit`] rolls everything back.
+pub struct FSTransaction {
+    fs_conn: FSConnection,
+}
+
+impl FSTransaction {
+    fn take(self) -> Transaction<'static, Sqlite> {
+        self.fs_conn.tx
+            .expect("FSTransaction always holds a transaction")
+            .into_inner()
+    }
+
+    pub async fn commit(self) -> Result<(), FSError> {
+        self.take().commit().await.map_err(FSError::database)
+    }
+
+    pub async fn rollback(self) -> Result<(), FSError> {
+        self.take().rollback().await.map_err(FSError::database)
+    }
+}
+
+impl Deref for FSTransaction {
+    type Target = FSConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.fs_conn
+    }
+}
+
+pub struct FSConnection {
+    pool: SqlitePool,
+    tx: Option<Mutex<Transaction<'static, Sqlite>>>,
+    pub file_table: String,
+    pub dir_table: String,
+    pub file_type_table: String,
+    pub content_table: String,
+    pub chunk_table: String,
+    pub compression_table: String,
+    pub history_table: String,
+    pub file_attr_table: String,
+    pub dir_attr_table: String,
+    pub search_table: String,
+    pub quota_table: String,
+    pub trash_table: String,
+    pub setting_table: String,
+    pub snapshot_table: String,
+    pub snapshot_dir_table: String,
+    pub snapshot_file_table: String,
+    pub snapshot_file_type_table: String,
+    pub snapshot_file_attr_table: String,
+    pub snapshot_dir_attr_table: String,
+    pub snapshot_quota_table: String,
+    pub snapshot_trash_table: String,
+    pub change_table: String,
+    pub schema_table: String,
+    key: Option<EncryptionKey>,
+}
+
+impl FSConnection {
+    async fn find_tables(conn: &mut SqliteConnection, dir_table: &str, file_table: &str, file_type_table: &str) -> Result<Vec<String>, sqlx::Error> {
+        let found_tables: Vec<String> = QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND (name=
+            "#)
+            .push_bind(file_table)
+            .push(" OR name=")
+            .push_bind(dir_table)
+            .push(" OR name=")
+            .push_bind(file_type_table)
+            .push(")")
+            .build()
+            .fetch_all(conn)
+            .await?
+            .iter()
+            .map(|row| {row.get(0)})
+            .collect();
+        Ok(found_tables)
+    }
+
+    fn create_table_names(table_prefix: &str) -> (String, String, String) {
+        let file_table = format!("{}{}", table_prefix, "files");
+        let dir_table = format!("{}{}", table_prefix, "dirs");
+        let file_type_table = format!("{}{}", table_prefix, "file_types");
+
+        (file_table, dir_table, file_type_table)
+    }
+
+    pub async fn new(filename: &str, table_prefix: &str, create_new: bool) -> Result<FSConnection, FSError> {
+        let mut options = SqliteConnectOptions::from_str(filename).map_err(FSError::database)?
+            .create_if_missing(create_new)
+            .foreign_keys(true)
+            .journal_mode(SqliteJournalMode::Wal);
+
+        options.disable_statement_logging();
+
+        let pool = SqlitePool::connect_with(options).await.map_err(FSError::database)?;
+        let (file_table, dir_table, file_type_table) = FSConnection::create_table_names(table_prefix);
+
+        let content_table = format!("{}{}", table_prefix, "contents");
+        let chunk_table = format!("{}{}", table_prefix, "chunks");
+        let compression_table = format!("{}{}", table_prefix, "compression");
+        let history_table = format!("{}{}", table_prefix, "history");
+        let file_attr_table = format!("{}{}", table_prefix, "file_attrs");
+        let dir_attr_table = format!("{}{}", table_prefix, "dir_attrs");
+        let search_table = format!("{}{}", table_prefix, "search");
+        let quota_table = format!("{}{}", table_prefix, "quotas");
+        let trash_table = format!("{}{}", table_prefix, "trash");
+        let setting_table = format!("{}{}", table_prefix, "settings");
+        let snapshot_table = format!("{}{}", table_prefix, "snapshots");
+        let snapshot_dir_table = format!("{}{}", table_prefix, "snapshot_dirs");
+        let snapshot_file_table = format!("{}{}", table_prefix, "snapshot_files");
+        let snapshot_file_type_table = format!("{}{}", table_prefix, "snapshot_file_types");
+        let snapshot_file_attr_table = format!("{}{}", table_prefix, "snapshot_file_attrs");
+        let snapshot_dir_attr_table = format!("{}{}", table_prefix, "snapshot_dir_attrs");
+        let snapshot_quota_table = format!("{}{}", table_prefix, "snapshot_quotas");
+        let snapshot_trash_table = format!("{}{}", table_prefix, "snapshot_trash");
+        let change_table = format!("{}{}", table_prefix, "changes");
+        let schema_table = format!("{}{}", table_prefix, "schema");
+        let fs_conn = FSConnection {
+            pool, tx: None,
+            file_table, dir_table, file_type_table, content_table, chunk_table, history_table, file_attr_table, dir_attr_table, search_table, quota_table,
+            trash_table, setting_table, compression_table,
+            snapshot_table, snapshot_dir_table, snapshot_file_table, snapshot_file_type_table, snapshot_file_attr_table, snapshot_dir_attr_table,
+            snapshot_quota_table, snapshot_trash_table, change_table,
+            schema_table,
+            key: None,
+        };
+
+        let mut conn = fs_conn.pool.acquire().await.map_err(FSError::database)?.detach();
+        let migrated = migrations::migrate(&mut conn, &fs_conn).await;
+        conn.close().await.map_err(FSError::database)?;
+        migrated?;
+
+        Ok(fs_conn)
+    }
+
+    async fn acquire(&self) -> Result<FSConn<'_>, sqlx::Error> {
+        match &self.tx {
+            Some(tx) => Ok(FSConn::Transaction(tx.lock().await)),
+            None => Ok(FSConn::Pool(self.pool.acquire().await?)),
+        }
+    }
+
+    /// Sets the key for reading and writing encrypted files. Without one, opening an encrypted file
+    /// fails with [`FSError::KeyRequired`].
+    pub fn set_key(&mut self, key: Option<EncryptionKey>) {
+        self.key = key;
+    }
 
-        Ok(File { name: row.get("name"), directory: Directory::from_id(row.get("directory"), &fs_conn).await? })
+    fn key(&self) -> Result<&EncryptionKey, FSError> {
+        self.key.as_ref().ok_or(FSError::KeyRequired)
     }
 
-    pub async fn get_id(&self, fs_conn: &FSConnection) -> Result<i64, sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        Ok(QueryBuilder::new(format!(r#"
-                SELECT id FROM {} WHERE directory=
-            "#, fs_conn.file_table))
-            .push_bind(&self.directory.get_id(&fs_conn).await?)
-            .push("AND name=")
-            .push_bind(&self.name)
-            .build()
-            .fetch_one(&mut conn)
-            .await?
-            .get("id"))
+    /// Starts a transaction. Operations given the returned handle only take effect once it is committed.
+    pub async fn transaction(&self) -> Result<FSTransaction, FSError> {
+        if self.tx.is_some() {
+            return Err(FSError::NestedTransaction);
+        }
+        let tx = self.pool.begin().await.map_err(FSError::database)?;
+        Ok(FSTransaction {
+            fs_conn: FSConnection {
+                pool: self.pool.clone(),
+                tx: Some(Mutex::new(tx)),
+                file_table: self.file_table.clone(),
+                dir_table: self.dir_table.clone(),
+                file_type_table: self.file_type_table.clone(),
+                content_table: self.content_table.clone(),
+                chunk_table: self.chunk_table.clone(),
+                compression_table: self.compression_table.clone(),
+                history_table: self.history_table.clone(),
+                file_attr_table: self.file_attr_table.clone(),
+                dir_attr_table: self.dir_attr_table.clone(),
+                search_table: self.search_table.clone(),
+                quota_table: self.quota_table.clone(),
+                trash_table: self.trash_table.clone(),
+                setting_table: self.setting_table.clone(),
+                snapshot_table: self.snapshot_table.clone(),
+                snapshot_dir_table: self.snapshot_dir_table.clone(),
+                snapshot_file_table: self.snapshot_file_table.clone(),
+                snapshot_file_type_table: self.snapshot_file_type_table.clone(),
+                snapshot_file_attr_table: self.snapshot_file_attr_table.clone(),
+                snapshot_dir_attr_table: self.snapshot_dir_attr_table.clone(),
+                snapshot_quota_table: self.snapshot_quota_table.clone(),
+                snapshot_trash_table: self.snapshot_trash_table.clone(),
+                change_table: self.change_table.clone(),
+                schema_table: self.schema_table.clone(),
+                key: self.key.clone(),
+            }
+        })
     }
 
-    pub async fn exists(&self, fs_conn: &FSConnection) -> Result<bool, sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        let id = match self.directory.get_id(&fs_conn).await {
-            Ok(id) => id,
-            Err(e) => match e {
-                sqlx::Error::RowNotFound => return Ok(false),
-                _ => return Err(e),
+    /// Finds the directory or file at `path`, following links along the way, including a link at
+    /// the end of the path. A directory wins over a file of the same name.
+    pub async fn resolve_path(&self, path: PathBuf) -> Result<FSType, FSError> {
+        let not_found = || FSError::NotFound(path.display().to_string());
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let root_id = Directory::query_root_id(&mut conn, self).await.map_err(FSError::database)?;
+
+        let mut pending = FSConnection::path_names(&Directory::root(), &path)?;
+        let mut dir = Directory::root();
+        let mut id = root_id;
+        let mut hops = 0;
+        while let Some(name) = pending.pop_front() {
+            match Directory::query_child_id(id, &name, &mut conn, self).await {
+                Ok(child) => {
+                    dir = dir.dir(&name)?;
+                    id = child;
+                    continue;
+                },
+                Err(sqlx::Error::RowNotFound) => (),
+                Err(e) => return Err(FSError::database(e)),
             }
-        };
-        Ok(QueryBuilder::new(format!(r#"
-                SELECT * FROM {} WHERE directory=
-            "#, fs_conn.file_table))
-            .push_bind(&id)
-            .push("AND name=")
-            .push_bind(&self.name)
-            .build()
-            .fetch_optional(&mut conn)
-            .await?
-            .is_some())
+
+            let row = QueryBuilder::new(format!("SELECT id, type, content FROM {} WHERE directory=", self.file_table))
+                .push_bind(id)
+                .push("AND name=")
+                .push_bind(&name)
+                .build()
+                .fetch_optional(&mut *conn)
+                .await
+                .map_err(FSError::database)?
+                .ok_or_else(not_found)?;
+
+            if row.get::<String, &str>("type") == FileType::Link.to_string() {
+                hops += 1;
+                if hops > MAX_LINK_HOPS {
+                    return Err(FSError::LinkLoop(path.display().to_string()));
+                }
+                let target = read_content(row.get("content"), &mut conn, self).await?;
+                let target = PathBuf::from(String::from_utf8_lossy(&target).to_string());
+                let mut target = FSConnection::path_names(&dir, &target)?;
+                target.append(&mut pending);
+                pending = target;
+                dir = Directory::root();
+                id = root_id;
+            } else if pending.is_empty() {
+                let mut file = Directory { path: dir.path, id: Some(id) }.file(&name);
+                file.id = Some(row.get("id"));
+                return Ok(FSType::File(file));
+            } else {
+                return Err(not_found());
+            }
+        }
+
+        Ok(FSType::Directory(Directory { path: dir.path, id: Some(id) }))
     }
 
-    pub async fn mk(&self, data:&str, ftype: &FileType, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        QueryBuilder::new(format!(r#"
-                INSERT INTO {}(name,type,data,directory) VALUES(
-            "#, fs_conn.file_table))
-            .push_bind(&self.name)
-            .push(",")
-            .push_bind(&ftype.to_string())
-            .push(",")
-            .push_bind(&data)
+    /// Registers a custom file type rendered by `handler`, or replaces the handler of one that is
+    /// already registered. Built-in types can't be redefined.
+    pub async fn register_file_type(&self, ftype: &FileType, handler: &str) -> Result<(), FSError> {
+        if ftype.is_builtin() {
+            return Err(FSError::BuiltinType(ftype.to_string()));
+        }
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("INSERT INTO {}(type, handler) VALUES(", self.file_type_table))
+            .push_bind(ftype.to_string())
             .push(",")
-            .push_bind(&self.directory.get_id(&fs_conn).await?)
-            .push(");")
+            .push_bind(handler)
+            .push(") ON CONFLICT(type) DO UPDATE SET handler=excluded.handler")
             .build()
-            .execute(&mut conn)
-            .await?;
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
         Ok(())
     }
 
-    pub async fn del(&self, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        QueryBuilder::new(format!(r#"
-                DELETE FROM {} where directory=
-            "#, fs_conn.file_table))
-            .push_bind(&self.directory.get_id(&fs_conn).await?)
-            .push("AND name=")
-            .push_bind(&self.name)
+    /// Removes a custom file type. Fails while any file still has that type.
+    pub async fn unregister_file_type(&self, ftype: &FileType) -> Result<(), FSError> {
+        if ftype.is_builtin() {
+            return Err(FSError::BuiltinType(ftype.to_string()));
+        }
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let deleted = QueryBuilder::new(format!("DELETE FROM {} WHERE type=", self.file_type_table))
+            .push_bind(ftype.to_string())
             .build()
-            .execute(&mut conn)
-            .await?;
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(ref db) if db.code().as_deref() == Some(SQLITE_CONSTRAINT_FOREIGNKEY) => {
+                    FSError::TypeInUse(ftype.to_string())
+                },
+                e => FSError::database(e),
+            })?
+            .rows_affected();
+        if deleted == 0 {
+            return Err(FSError::InvalidType(ftype.to_string()));
+        }
         Ok(())
     }
 
-    pub async fn rename(&mut self, name: &str, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
-        let name = name.to_string();
-        let mut conn = fs_conn.pool.acquire().await?;
-        QueryBuilder::new(format!(r#"
-                UPDATE {} SET name=
-            "#,fs_conn.file_table))
-            .push_bind(&name)
-            .push("WHERE directory=")
-            .push_bind(&self.directory.get_id(&fs_conn).await?)
-            .push("AND name=")
-            .push_bind(&self.name)
+    pub async fn file_types(&self) -> Result<Vec<FileTypeEntry>, FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("SELECT type, handler FROM {} ORDER BY type", self.file_type_table))
             .build()
-            .execute(&mut conn)
-            .await?;
-        
-            self.name = name;
-        Ok(())
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(|row| Ok(FileTypeEntry {
+                ftype: FileType::from_str(row.get("type"))?,
+                handler: row.get("handler"),
+            }))
+            .collect()
     }
 
-    pub async fn mv(&mut self, directory: Directory, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        QueryBuilder::new(format!(r#"
-                UPDATE {} SET directory=
-            "#,fs_conn.file_table))
-            .push_bind(&directory.get_id(&fs_conn).await?)
-            .push("WHERE directory=")
-            .push_bind(&self.directory.get_id(&fs_conn).await?)
-            .push("AND name=")
-            .push_bind(&self.name)
+    /// The handler registered for a custom file type. Built-in types have none.
+    pub async fn file_type_handler(&self, ftype: &FileType) -> Result<Option<String>, FSError> {
+        if ftype.is_builtin() {
+            return Ok(None);
+        }
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("SELECT handler FROM {} WHERE type=", self.file_type_table))
+            .push_bind(ftype.to_string())
             .build()
-            .execute(&mut conn)
-            .await?;
-        
-            self.directory = directory;
-        Ok(())
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .map(|row| row.get("handler"))
+            .ok_or_else(|| FSError::InvalidType(ftype.to_string()))
     }
 
-    pub async fn read(&self, fs_conn: &FSConnection) -> Result<(String, String), sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        let row = QueryBuilder::new(format!(r#"
-                SELECT data,type FROM {} WHERE directory=
-            "#, fs_conn.file_table))
-            .push_bind(&self.directory.get_id(&fs_conn).await?)
-            .push("AND name=")
-            .push_bind(&self.name)
-            .build()
-            .fetch_one(&mut conn)
-            .await?;
-
-        Ok((row.try_get("data")?, row.try_get("type")?))
+    /// Renders a custom-typed file through its registered handler.
+    pub async fn render_custom(&self, ftype: &FileType, path: &str, data: &[u8]) -> Result<Vec<u8>, FSError> {
+        match self.file_type_handler(ftype).await? {
+            Some(handler) => run_handler(&handler, path, data).await,
+            None => Err(FSError::InvalidType(ftype.to_string())),
+        }
     }
 
-    pub async fn write(&mut self, data: &str, ftype: FileType, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        QueryBuilder::new(format!(r#"
-                UPDATE {} SET data=
-            "#,fs_conn.file_table))
-            .push_bind(&data)
-            .push(", type=")
-            .push_bind(&ftype.to_string())
-            .push("WHERE directory=")
-            .push_bind(&self.directory.get_id(&fs_conn).await?)
-            .push("AND name=")
-            .push_bind(&self.name)
+    async fn query_snapshot_id(&self, name: &str, conn: &mut SqliteConnection) -> Result<i64, FSError> {
+        Ok(QueryBuilder::new(format!("SELECT id FROM {} WHERE name=", self.snapshot_table))
+            .push_bind(name)
             .build()
-            .execute(&mut conn)
-            .await?;
-        Ok(())
+            .fetch_one(conn)
+            .await
+            .at(&format!("snapshot {}", name))?
+            .get("id"))
     }
-}
-
-pub struct Directory {
-    pub path: String,
-    id: Option<i64>,
-}
 
-impl Directory {
-    fn path_to_str(path: PathBuf) -> Result<String, FSError> {
-        match path.absolutize_virtually("/") {
-            Ok(path) => {
-                let path = path.display().to_string();
-                if path.ends_with('/') {
-                    Ok(path)
-                } else {
-                    Ok(format!("{}/", path))
-                }
-            },
-            Err(_) => Err(FSError::PathIsNotADir(path.display().to_string())),
-        }        
-    }
+    /// Saves the directories, files, their attributes and quotas, the trash and the file types as they
+    /// are now under `name`. Data is shared with the live tree, so a snapshot only costs the rows that describe it.
+    pub async fn create_snapshot(&self, name: &str) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let snapshot = QueryBuilder::new(format!("INSERT INTO {}(name, created) VALUES(", self.snapshot_table))
+                .push_bind(name)
+                .push(",")
+                .push_bind(now())
+                .push(")")
+                .build()
+                .execute(&mut *tx)
+                .await
+                .at(&format!("snapshot {}", name))?
+                .last_insert_rowid();
 
-    pub fn new(path: PathBuf) -> Result<Directory, FSError>{
-        Ok(Directory{path: Directory::path_to_str(path)?, id: None})
+            for (into, from, columns) in [
+                (&self.snapshot_file_type_table, &self.file_type_table, SNAPSHOT_FILE_TYPE_COLUMNS),
+                (&self.snapshot_dir_table, &self.dir_table, SNAPSHOT_DIR_COLUMNS),
+                (&self.snapshot_file_table, &self.file_table, SNAPSHOT_FILE_COLUMNS),
+                (&self.snapshot_dir_attr_table, &self.dir_attr_table, SNAPSHOT_ATTR_COLUMNS),
+                (&self.snapshot_file_attr_table, &self.file_attr_table, SNAPSHOT_ATTR_COLUMNS),
+                (&self.snapshot_quota_table, &self.quota_table, SNAPSHOT_QUOTA_COLUMNS),
+                (&self.snapshot_trash_table, &self.trash_table, SNAPSHOT_TRASH_COLUMNS),
+            ] {
+                QueryBuilder::new(format!("INSERT INTO {into}(snapshot,{columns}) SELECT "))
+                    .push_bind(snapshot)
+                    .push(format!(",{columns} FROM {from}"))
+                    .build()
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(FSError::database)?;
+            }
+            Ok(())
+        }.await;
+        finish(tx, result).await
     }
 
-    pub async fn from_id(id: i64, fs_conn: &FSConnection) -> Result<Directory, FSError> {
-        let mut conn = fs_conn.pool.acquire().await.map_err(|e| FSError::SqlX(e))?;
-        match QueryBuilder::new(format!(r#"
-                SELECT directory FROM {} WHERE id=
-            "#, fs_conn.dir_table))
-            .push_bind(&id)
+    /// Saved snapshots, oldest first.
+    pub async fn snapshots(&self) -> Result<Vec<Snapshot>, FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        Ok(QueryBuilder::new(format!("SELECT name, created FROM {} ORDER BY created, id", self.snapshot_table))
             .build()
-            .fetch_optional(&mut conn)
-            .await.map_err(|e| FSError::SqlX(e))? {
-                Some(row) => Ok(Directory{path: row.get("directory"), id: None}),
-                None => Err(FSError::DoesNotExist(format!("Directory {}", id))),
-            }
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(|row| Snapshot {
+                name: row.get("name"),
+                created: from_timestamp(row.get("created")),
+            })
+            .collect())
     }
 
-    pub fn root() -> Directory {
-        Directory { path: "/".to_string(), id: None }
-    }
+    /// Puts the tree back the way it was when the snapshot was made. Everything made since is
+    /// removed. Files that are in the snapshot keep their history; that of the rest goes with them.
+    pub async fn restore_snapshot(&self, name: &str) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let snapshot = self.query_snapshot_id(name, &mut tx).await?;
 
-    pub async fn get_id(&self, fs_conn: &FSConnection) -> Result<i64, sqlx::Error> {
-        if let Some(id) = self.id {
-            return Ok(id);
-        } else {
-            let mut conn = fs_conn.pool.acquire().await?;
-            Ok(QueryBuilder::new(format!(r#"
-                    SELECT id FROM {} WHERE directory=
-                "#, fs_conn.dir_table))
-                .push_bind(&self.path)
+            // History goes with the files by cascade, so it is put aside first. Its content gets an
+            // extra reference meanwhile, or the triggers would drop the content with the last row.
+            // Directories reference their parents, which may be inserted after them.
+            QueryBuilder::new(format!(r#"
+                    CREATE TEMP TABLE {history}_kept AS SELECT * FROM {history};
+                    UPDATE {contents} SET refs=refs+(SELECT COUNT(*) FROM {history}_kept WHERE content=hash)
+                        WHERE hash IN (SELECT content FROM {history}_kept);
+                    PRAGMA defer_foreign_keys = ON;
+                    DELETE FROM {files};
+                    DELETE FROM {dirs};
+                    DELETE FROM {file_types};
+                "#, history = self.history_table, contents = self.content_table, files = self.file_table,
+                    dirs = self.dir_table, file_types = self.file_type_table))
                 .build()
-                .fetch_one(&mut conn)
-                .await?
-                .get("id"))
-        }
-    }
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
 
-    pub async fn exists(&self, fs_conn: &FSConnection) -> Result<bool, sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-       Ok(QueryBuilder::new(format!(r#"
-                SELECT * FROM {} WHERE directory=
-            "#, fs_conn.dir_table))
-            .push_bind(&self.path)
-            .build()
-            .fetch_optional(&mut conn)
-            .await?
-            .is_some())
+            for (into, from, columns) in [
+                (&self.file_type_table, &self.snapshot_file_type_table, SNAPSHOT_FILE_TYPE_COLUMNS),
+                (&self.dir_table, &self.snapshot_dir_table, SNAPSHOT_DIR_COLUMNS),
+                (&self.file_table, &self.snapshot_file_table, SNAPSHOT_FILE_COLUMNS),
+                (&self.dir_attr_table, &self.snapshot_dir_attr_table, SNAPSHOT_ATTR_COLUMNS),
+                (&self.file_attr_table, &self.snapshot_file_attr_table, SNAPSHOT_ATTR_COLUMNS),
+                (&self.quota_table, &self.snapshot_quota_table, SNAPSHOT_QUOTA_COLUMNS),
+                (&self.trash_table, &self.snapshot_trash_table, SNAPSHOT_TRASH_COLUMNS),
+            ] {
+                QueryBuilder::new(format!("INSERT INTO {into}({columns}) SELECT {columns} FROM {from} WHERE snapshot="))
+                    .push_bind(snapshot)
+                    .build()
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(FSError::database)?;
+            }
+            QueryBuilder::new(format!(r#"
+                    INSERT INTO {history} SELECT * FROM {history}_kept WHERE file IN (SELECT id FROM {files});
+                    UPDATE {contents} SET refs=refs-(SELECT COUNT(*) FROM {history}_kept WHERE content=hash)
+                        WHERE hash IN (SELECT content FROM {history}_kept);
+                    DELETE FROM {contents} WHERE refs=0 AND hash IN (SELECT content FROM {history}_kept);
+                    DROP TABLE {history}_kept;
+                "#, history = self.history_table, contents = self.content_table, files = self.file_table))
+                .build()
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
+            index_compressed(None, &mut tx, self).await?;
+            log_change(ChangeKind::Update, "/", None, &mut tx, self).await.map_err(FSError::database)?;
+            Ok(())
+        }.await;
+        finish(tx, result).await
     }
 
-    pub async fn mk(&self, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        QueryBuilder::new(format!(r#"
-                INSERT INTO {}(directory) VALUES(
-            "#, fs_conn.dir_table))
-            .push_bind(&self.path)
-            .push(");")
+    pub async fn delete_snapshot(&self, name: &str) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let changed = QueryBuilder::new(format!("DELETE FROM {} WHERE name=", self.snapshot_table))
+            .push_bind(name)
             .build()
-            .execute(&mut conn)
-            .await?;
-        Ok(())
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        affected(changed, &format!("snapshot {}", name))
     }
 
-    // Make recursion
-    pub async fn del(&self, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        QueryBuilder::new(format!(r#"
-                DELETE FROM {} where directory=
-            "#, fs_conn.dir_table))
-            .push_bind(&self.path)
+    /// Searches the names and data of text files, best matches first. `query` uses the FTS5 query
+    /// syntax, e.g. `budget AND 2023` or `"exact phrase"`; malformed queries fail with
+    /// [`FSError::InvalidQuery`].
+    pub async fn search(&self, query: &str, limit: u32) -> Result<Vec<SearchResult>, FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let rows = QueryBuilder::new(format!(r#"
+                WITH RECURSIVE trashed(id) AS (
+                    SELECT container FROM {trash}
+                    UNION ALL
+                    SELECT d.id FROM {dirs} d JOIN trashed t ON d.parent = t.id
+                )
+                SELECT {files}.name AS name, directory, snippet({search}, 1, "[", "]", "...", 16) AS snippet, rank
+                FROM {search} JOIN {files} ON {files}.id = {search}.rowid
+                WHERE {search} MATCH
+            "#, files = self.file_table, search = self.search_table, trash = self.trash_table, dirs = self.dir_table))
+            .push_bind(query)
+            .push("AND directory NOT IN (SELECT id FROM trashed) ORDER BY rank LIMIT")
+            .push_bind(limit)
             .build()
-            .execute(&mut conn)
-            .await?;
-        Ok(())
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::Database(e) if is_query_error(e.message()) => FSError::InvalidQuery(e.message().to_string()),
+                e => FSError::database(e),
+            })?;
+
+        let mut results = vec![];
+        for row in rows {
+            let directory = Directory::query_from_id(row.get("directory"), &mut conn, self).await?;
+            results.push(SearchResult {
+                path: format!("{}{}", directory.path, row.get::<String, &str>("name")),
+                snippet: row.get("snippet"),
+                rank: row.get("rank"),
+            });
+        }
+        Ok(results)
     }
 
-    pub async fn mv(&mut self, path: &Directory, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
-        let path = path.path.clone();
-        let mut conn = fs_conn.pool.acquire().await?;
-        QueryBuilder::new(format!("UPDATE {} SET directory=(",fs_conn.dir_table))
-            .push_bind(&path)
-            .push(format!(r#" || substr(directory, {})) WHERE directory LIKE "#, self.path.len()+1))
-            .push_bind(format!("{}%", self.path))
+    /// Deleted files and directories, most recently deleted first. Entries past the retention period
+    /// are purged before listing.
+    pub async fn trash(&self) -> Result<Vec<TrashEntry>, FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        purge_expired_trash(&mut conn, self).await.map_err(FSError::database)?;
+        Ok(QueryBuilder::new(format!("SELECT id, path, deleted FROM {} ORDER BY deleted DESC, id DESC", self.trash_table))
             .build()
-            .execute(&mut conn)
-            .await?;
-        
-            self.path = path;
-        Ok(())
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(|row| TrashEntry {
+                id: row.get("id"),
+                path: row.get("path"),
+                deleted: from_timestamp(row.get("deleted")),
+            })
+            .collect())
     }
 
-    pub fn rename(&self, name: &str) -> Result<PathBuf, FSError> {
-        let mut path = match PathBuf::from_str(&self.path) {
-            Ok(path) => path,
-            Err(_) => Err(FSError::PathIsNotADir(self.path.clone()))?,
-        };
-        let new_name = match PathBuf::from_str(&name) {
-            Ok(path) => path,
-            Err(_) => Err(FSError::PathIsNotADir(name.to_string()))?,
-        };
-        path.pop();
-        path.push(new_name);
+    /// Puts a trash entry back where it was deleted from. Fails with [`FSError::AlreadyExists`] if
+    /// something has taken its place, or [`FSError::NotFound`] if its parent directory is gone.
+    pub async fn restore_trash(&self, id: i64) -> Result<(), FSError> {
+        let now = now();
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+        let result = async {
+            let row = QueryBuilder::new(format!("SELECT container, path FROM {} WHERE id=", self.trash_table))
+                .push_bind(id)
+                .build()
+                .fetch_one(&mut *tx)
+                .await
+                .at(&format!("trash entry {}", id))?;
+            let container: i64 = row.get("container");
+            let path: String = row.get("path");
 
-        Ok(path)
-    }
+            let parent = PathBuf::from(&path).parent().map(Path::to_path_buf).ok_or_else(|| FSError::NotFound(path.clone()))?;
+            let parent = Directory::new(parent)?;
+            let parent = parent.query_id(&mut tx, self).await.at(&parent.path)?;
+            let (table, column) = if path.ends_with('/') {
+                (&self.dir_table, "parent")
+            } else {
+                (&self.file_table, "directory")
+            };
+            QueryBuilder::new(format!("UPDATE {table} SET {column}="))
+                .push_bind(parent)
+                .push(format!("WHERE {column}="))
+                .push_bind(container)
+                .build()
+                .execute(&mut *tx)
+                .await
+                .at(&path)?;
+            QueryBuilder::new(format!("DELETE FROM {} WHERE id=", self.dir_table))
+                .push_bind(container)
+                .build()
+                .execute(&mut *tx)
+                .await
+                .map_err(FSError::database)?;
 
-    pub async fn files(&self, fs_conn: &FSConnection) -> Result<Vec<SqliteRow>, sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        Ok(QueryBuilder::new(format!(r#"
-                SELECT * FROM {} WHERE directory=
-            "#, fs_conn.file_table))
-            .push_bind(&self.get_id(&fs_conn).await?)
-            .build()
-            .fetch_all(&mut conn)
-            .await?)
+            log_change(ChangeKind::Create, &path, None, &mut tx, self).await.map_err(FSError::database)?;
+            check_quotas(parent, None, &mut tx, self).await?;
+            Directory::touch(parent, now, &mut tx, self).await.map_err(FSError::database)?;
+            Ok(())
+        }.await;
+        finish(tx, result).await
     }
 
-    pub async fn dirs(&self, fs_conn: &FSConnection) -> Result<Vec<SqliteRow>, sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        Ok(QueryBuilder::new(format!(r#"
-                SELECT * FROM {} WHERE directory LIKE
-            "#, fs_conn.dir_table))
-            .push_bind(&format!("{}%/", self.path))
-            .push("AND directory NOT LIKE")
-            .push_bind(&format!("{}%/%/", self.path))
+    /// Permanently deletes a trash entry and everything in it.
+    pub async fn purge_trash(&self, id: i64) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let changed = QueryBuilder::new(format!("DELETE FROM {} WHERE id IN (SELECT container FROM {} WHERE id=", self.dir_table, self.trash_table))
+            .push_bind(id)
+            .push(")")
             .build()
-            .fetch_all(&mut conn)
-            .await?)
-    }
-
-    pub async fn contents(&self, fs_conn: &FSConnection) -> Result<(Vec<SqliteRow>, Vec<SqliteRow>), sqlx::Error>  {
-        Ok((self.files(fs_conn).await?, self.dirs(fs_conn).await?))
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        affected(changed, &format!("trash entry {}", id))
     }
 
-    pub async fn recurse(&self, fs_conn: &FSConnection) -> Result<(Vec<SqliteRow>, Vec<SqliteRow>), sqlx::Error> {
-        let mut conn = fs_conn.pool.acquire().await?;
-        Ok((QueryBuilder::new(format!(r#"
-                SELECT id,name,type,{}.directory FROM {},{} WHERE {}.directory={}.id AND {}.directory LIKE 
-            "#, fs_conn.dir_table, fs_conn.dir_table, fs_conn.file_table, fs_conn.file_table, fs_conn.dir_table, fs_conn.dir_table))
-            .push_bind(format!("{}%", &self.path))
-            .build()
-            .fetch_all(&mut conn)
-            .await?,
-            QueryBuilder::new(format!(r#"
-                SELECT id,directory FROM {} WHERE directory LIKE 
-            "#, fs_conn.dir_table))
-            .push_bind(format!("{}%", &self.path))
-            .push("AND directory!=")
-            .push_bind(&self.path)
+    /// Permanently deletes everything in the trash.
+    pub async fn empty_trash(&self) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("DELETE FROM {} WHERE id IN (SELECT container FROM {})", self.dir_table, self.trash_table))
             .build()
-            .fetch_all(&mut conn)
-            .await?,))
-    }
-
-    pub fn file(&self, name: &str) -> File  {
-        File{name: name.to_string(), directory: Directory { path: self.path.clone(), id: self.id.clone() }}
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        Ok(())
     }
 
-    pub fn dir(&self, name: &str) -> Result<Directory, FSError>  {
-        let mut path = PathBuf::from_str(&self.path).map_err(|_| FSError::PathIsNotADir(self.path.to_string()))?;
-        path.push(name);
-        Directory::new(path)
+    /// How long deleted entries are kept before being purged, or `None` to keep them until purged by hand.
+    pub async fn trash_retention(&self) -> Result<Option<Duration>, FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        query_trash_retention(&mut conn, self).await.map_err(FSError::database)
     }
 
-}
-
-pub struct FSConnection {
-    pool: SqlitePool,
-    pub file_table: String,
-    pub dir_table: String,
-    pub file_type_table: String,
-}
-
-impl FSConnection {
-    async fn create_file_type_table(conn: &mut PoolConnection<Sqlite>, file_type_table: &str) -> Result<(), sqlx::Error>{
-        QueryBuilder::new(format!(r#"
-                CREATE TABLE {} (type TEXT PRIMARY KEY NOT NULL);
-                INSERT INTO {} VALUES("file");
-                INSERT INTO {} VALUES("text");
-                INSERT INTO {} VALUES("exec");
-            "#, file_type_table, file_type_table, file_type_table, file_type_table))
+    /// Sets the trash retention period. Expired entries are purged the next time something is
+    /// deleted or the trash is listed.
+    pub async fn set_trash_retention(&self, retention: Option<Duration>) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("INSERT INTO {}(key, value) VALUES(", self.setting_table))
+            .push_bind(TRASH_RETENTION_KEY)
+            .push(",")
+            .push_bind(retention.map(|retention| retention.as_secs() as i64))
+            .push(") ON CONFLICT(key) DO UPDATE SET value=excluded.value")
             .build()
-            .execute(conn)
-            .await?;
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
         Ok(())
     }
 
-    async fn create_dir_table(conn: &mut PoolConnection<Sqlite>, dir_table: &str) -> Result<(), sqlx::Error>{
-        QueryBuilder::new(format!(r#"
-                CREATE TABLE {} (id INTEGER PRIMARY KEY NOT NULL CHECK(id > 0) UNIQUE,
-                    directory TEXT NOT NULL CHECK(directory != "" AND (directory = "/" OR directory LIKE "/%/")),
-                    CONSTRAINT unq UNIQUE(directory));
-                INSERT INTO {}(directory) VALUES("/");
-            "#, dir_table, dir_table))
+    /// The compression rules, by prefix.
+    pub async fn compression_rules(&self) -> Result<Vec<CompressionRule>, FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("SELECT prefix, algorithm FROM {} ORDER BY prefix", self.compression_table))
             .build()
-            .execute(conn)
-            .await?;
-        Ok(())
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .iter()
+            .map(|row| Ok(CompressionRule {
+                prefix: row.get("prefix"),
+                compression: Compression::from_str(row.get("algorithm"))?,
+            }))
+            .collect()
     }
 
-    async fn create_file_table(conn: &mut PoolConnection<Sqlite>, dir_table: &str, file_table: &str, file_type_table: &str) -> Result<(), sqlx::Error>{
-        QueryBuilder::new(format!(r#"
-                CREATE TABLE {} (id INTEGER PRIMARY KEY check(id > 0), name TEXT NOT NULL, type TEXT NOT NULL, data TEXT NOT NULL, directory INTEGER NOT NULL, 
-                    FOREIGN KEY(directory) REFERENCES {}(id) ON DELETE CASCADE ON UPDATE CASCADE, 
-                    FOREIGN KEY(type) REFERENCES {}(type) ON DELETE RESTRICT ON UPDATE RESTRICT, 
-                    CONSTRAINT unq UNIQUE(name, directory));
-            "#, file_table, dir_table, file_type_table))
+    /// Compresses data written to paths starting with `prefix`, e.g. `/logs/` or a single file's path,
+    /// with `compression`. The longest matching prefix wins, so [`Compression::None`] can exclude part
+    /// of a compressed tree. Data that is already stored is left as it is until
+    /// [`Directory::recompress`] is run.
+    pub async fn set_compression(&self, prefix: &str, compression: Compression) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("INSERT INTO {}(prefix, algorithm) VALUES(", self.compression_table))
+            .push_bind(prefix)
+            .push(",")
+            .push_bind(compression.to_string())
+            .push(") ON CONFLICT(prefix) DO UPDATE SET algorithm=excluded.algorithm")
             .build()
-            .execute(conn)
-            .await?;
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
         Ok(())
     }
-    
-    async fn find_tables(conn: &mut PoolConnection<Sqlite>, dir_table: &str, file_table: &str, file_type_table: &str) -> Result<Vec<String>, sqlx::Error> {
-        let found_tables: Vec<String> = QueryBuilder::new(r#"
-                SELECT name FROM sqlite_master WHERE type="table" AND (name=
-            "#)
-            .push_bind(&file_table)
-            .push(" OR name=")
-            .push_bind(&dir_table)
-            .push(" OR name=")
-            .push_bind(&file_type_table)
-            .push(")")
+
+    /// Removes the compression rule for `prefix`. Fails with [`FSError::NotFound`] if it has none.
+    pub async fn remove_compression(&self, prefix: &str) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        let changed = QueryBuilder::new(format!("DELETE FROM {} WHERE prefix=", self.compression_table))
+            .push_bind(prefix)
             .build()
-            .fetch_all(conn)
-            .await?
-            .iter()
-            .map(|row| {row.get(0)})
-            .collect();
-        Ok(found_tables)
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        affected(changed, &format!("compression rule {}", prefix))
     }
 
-    fn create_table_names(table_prefix: &str) -> (String, String, String) {
-        let file_table = format!("{}{}", table_prefix, "files");
-        let dir_table = format!("{}{}", table_prefix, "dirs");
-        let file_type_table = format!("{}{}", table_prefix, "file_types");
-
-        (file_table, dir_table, file_type_table)
+    /// Up to `limit` logged changes after `seq`, oldest first. Pass 0 to start from the beginning of the log.
+    pub async fn changes_since(&self, seq: i64, limit: u32) -> Result<Vec<Change>, FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        query_changes(&self.change_table, seq, limit as i64, &mut conn).await
     }
 
-    pub async fn new(filename: &str, table_prefix: &str, create_new: bool) -> Result<FSConnection, sqlx::Error> {
-        let mut options = SqliteConnectOptions::from_str(filename)?
-            .create_if_missing(create_new)
-            .foreign_keys(true)
-            .journal_mode(SqliteJournalMode::Wal);
-
-        options.disable_statement_logging();
-
-        let pool = SqlitePool::connect_with(options).await?;
-        let (file_table, dir_table, file_type_table) = FSConnection::create_table_names(table_prefix);
-
-        let mut conn = pool.acquire().await?;
-        let found_tables = FSConnection::find_tables(&mut conn, &dir_table, &file_table, &file_type_table).await?;
-        
-        if !found_tables.contains(&file_type_table) {
-            FSConnection::create_file_type_table(&mut conn, &file_type_table).await?;
-        }
-        if !found_tables.contains(&dir_table) {
-            FSConnection::create_dir_table(&mut conn, &dir_table).await?;
-        }
-        if !found_tables.contains(&file_table) {
-            FSConnection::create_file_table(&mut conn, &dir_table, &file_table, &file_type_table).await?;
-        }
+    /// The sequence number of the latest change, or 0 if nothing has been logged.
+    pub async fn last_change(&self) -> Result<i64, FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        Ok(QueryBuilder::new(format!("SELECT COALESCE(MAX(seq), 0) AS seq FROM {}", self.change_table))
+            .build()
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(FSError::database)?
+            .get("seq"))
+    }
 
-        Ok(FSConnection { pool, file_table, dir_table, file_type_table })
+    /// Drops logged changes up to and including `seq`. Sequence numbers are never reused, so
+    /// subscribers past `seq` are unaffected.
+    pub async fn prune_changes(&self, seq: i64) -> Result<(), FSError> {
+        let mut conn = self.acquire().await.map_err(FSError::database)?;
+        QueryBuilder::new(format!("DELETE FROM {} WHERE seq <=", self.change_table))
+            .push_bind(seq)
+            .build()
+            .execute(&mut *conn)
+            .await
+            .map_err(FSError::database)?;
+        Ok(())
     }
 
-    pub async fn resolve_path(&self, path: PathBuf) -> Result<FSType, FSError> {
-        match Directory::new(path.clone()) {
-            Ok(dir) => {
-                if match dir.exists(&self).await {
-                    Ok(found) => found,
-                    Err(e) => Err(FSError::SqlX(e))?,
-                } {
-                    return Ok(FSType::Directory(dir))
-                }        
-            },
-            _ => (),
-        }
-        match File::new(path.clone()) {
-            Ok(file) => {
-                if match file.exists(&self).await {
-                    Ok(found) => found,
-                    Err(e) => Err(FSError::SqlX(e))?,
-                } {
-                    return Ok(FSType::File(file))
-                }
-            },
-            _ => (),
+    /// Follows the change log from after `seq`, polling the database every `interval` when idle.
+    pub fn subscribe(&self, seq: i64, interval: Duration) -> Subscription {
+        Subscription {
+            pool: self.pool.clone(),
+            change_table: self.change_table.clone(),
+            last: seq,
+            interval,
+            pending: VecDeque::new(),
         }
+    }
 
-        Err(FSError::DoesNotExist(path.display().to_string()))
+    /// The names along `path` from the root, with `path` taken relative to `dir` if it isn't absolute.
+    fn path_names(dir: &Directory, path: &Path) -> Result<VecDeque<String>, FSError> {
+        let path = PathBuf::from(&dir.path).join(path);
+        let absolute = path.absolutize_virtually("/")
+            .map_err(|_| FSError::NotFound(path.display().to_string()))?;
+        Ok(absolute.iter()
+            .skip(1)
+            .map(|name| name.to_string_lossy().to_string())
+            .collect())
     }
 }
 
@@ -547,33 +4024,34 @@ impl FSConnection {
 #[cfg(test)]
 mod tests {
 
-    use std::{str::FromStr, path::PathBuf};
+    use std::{str::FromStr, path::{Path, PathBuf}, time::{UNIX_EPOCH, Duration}, os::unix::fs::PermissionsExt};
 
-    use sqlx::{sqlite::{SqliteConnectOptions, SqliteJournalMode}, SqlitePool, Row};
+    use sqlx::{sqlite::{SqliteConnectOptions, SqliteJournalMode}, SqlitePool, Row, QueryBuilder};
 
-    use crate::{FSConnection, File, FileType, Directory, FSType};
+    use crate::{FSConnection, File, FileType, Directory, FSType, FSError, FileEntry, Find, SortBy, Quota, Usage, ChangeKind, Compression, CompressionRule, EncryptionKey, Import, ImportMode, Export, ExportExec, DEFAULT_TRASH_RETENTION, CHUNK_SIZE, SCHEMA_VERSION};
 
-    async fn remove_test_db() {
-        match tokio::fs::remove_file("./test.db").await {
-            Ok(_) => (),
-            Err(_) => (),
-        }
-        match tokio::fs::remove_file("./test.db-shm").await {
-            Ok(_) => (),
-            Err(_) => (),
-        }
-        match tokio::fs::remove_file("./test.db-wal").await {
-            Ok(_) => (),
-            Err(_) => (),
-        }
+    async fn remove_test_db(name: &str) {
+        let _ = tokio::fs::remove_file(format!("./{}", name)).await;
+        let _ = tokio::fs::remove_file(format!("./{}-shm", name)).await;
+        let _ = tokio::fs::remove_file(format!("./{}-wal", name)).await;
+    }
+
+    async fn content_refs(fs_conn: &FSConnection) -> Vec<i64> {
+        sqlx::query(&format!("SELECT refs FROM {} ORDER BY refs", fs_conn.content_table))
+            .fetch_all(&fs_conn.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("refs"))
+            .collect()
     }
 
     #[tokio::test]
     async fn test_fs_connection() {
-        remove_test_db().await;
+        remove_test_db("test_fs_connection.db").await;
 
         {
-            let fs_conn = FSConnection::new("sqlite://test.db", "servefs_", true).await.unwrap();
+            let fs_conn = FSConnection::new("sqlite://test_fs_connection.db", "servefs_", true).await.unwrap();
 
             let file = File::new(PathBuf::from_str("/file").unwrap()).unwrap();
             file.mk("data", &FileType::Text, &fs_conn).await.unwrap();
@@ -587,7 +4065,7 @@ mod tests {
             assert!(matches!(fs_conn.resolve_path(PathBuf::from_str("/h").unwrap()).await.unwrap(), FSType::Directory(_)));
         }
 
-        let options = SqliteConnectOptions::from_str("sqlite://test.db").unwrap()
+        let options = SqliteConnectOptions::from_str("sqlite://test_fs_connection.db").unwrap()
             .foreign_keys(true)
             .journal_mode(SqliteJournalMode::Wal);
 
@@ -602,14 +4080,81 @@ mod tests {
         assert!(found_tables.contains(&file_table));
 
 
-        remove_test_db().await;
+        remove_test_db("test_fs_connection.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_migrations() {
+        remove_test_db("test_migrations.db").await;
+
+        let options = SqliteConnectOptions::from_str("sqlite://test_migrations.db").unwrap()
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query(r#"
+                CREATE TABLE servefs_file_types (type TEXT PRIMARY KEY NOT NULL);
+                INSERT INTO servefs_file_types VALUES("file");
+                INSERT INTO servefs_file_types VALUES("text");
+                INSERT INTO servefs_file_types VALUES("exec");
+                CREATE TABLE servefs_dirs (id INTEGER PRIMARY KEY NOT NULL CHECK(id > 0) UNIQUE,
+                    directory TEXT NOT NULL CHECK(directory != "" AND (directory = "/" OR directory LIKE "/%/")),
+                    CONSTRAINT unq UNIQUE(directory));
+                INSERT INTO servefs_dirs(directory) VALUES("/");
+                CREATE TABLE servefs_files (id INTEGER PRIMARY KEY check(id > 0), name TEXT NOT NULL, type TEXT NOT NULL, data TEXT NOT NULL, directory INTEGER NOT NULL,
+                    FOREIGN KEY(directory) REFERENCES servefs_dirs(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                    FOREIGN KEY(type) REFERENCES servefs_file_types(type) ON DELETE RESTRICT ON UPDATE RESTRICT,
+                    CONSTRAINT unq UNIQUE(name, directory));
+                INSERT INTO servefs_dirs(directory) VALUES("/a/b/");
+                INSERT INTO servefs_files(name,type,data,directory) VALUES("legacy", "text", "old data", 1);
+                INSERT INTO servefs_files(name,type,data,directory) VALUES("nested", "text", "nested data", 2);
+                INSERT INTO servefs_files(name,type,data,directory) VALUES("copy", "text", "old data", 2);
+            "#)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        {
+            let fs_conn = FSConnection::new("sqlite://test_migrations.db", "servefs_", true).await.unwrap();
+            let file = File::new(PathBuf::from_str("/legacy").unwrap()).unwrap();
+            let (data, ftype) = file.read(&fs_conn).await.unwrap();
+            assert_eq!(data, "old data");
+            assert_eq!(ftype, FileType::Text.to_string());
+            assert_eq!(file.metadata(&fs_conn).await.unwrap().size, 8);
+
+            let blob = File::new(PathBuf::from_str("/blob").unwrap()).unwrap();
+            blob.mk_bytes(&[0xff, 0x00], &FileType::Blob, &fs_conn).await.unwrap();
+
+            let nested = File::new(PathBuf::from_str("/a/b/nested").unwrap()).unwrap();
+            assert_eq!(nested.read(&fs_conn).await.unwrap().0, "nested data");
+            assert_eq!(nested.directory.get_id(&fs_conn).await.unwrap(), 2);
+            assert!(Directory::new(PathBuf::from_str("/a/").unwrap()).unwrap().exists(&fs_conn).await.unwrap());
+            assert_eq!(content_refs(&fs_conn).await, vec![1, 1, 2]);
+        }
+
+        let version: i64 = sqlx::query("SELECT version FROM servefs_schema")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("version");
+        assert_eq!(version, SCHEMA_VERSION);
+
+        sqlx::query("UPDATE servefs_schema SET version = version + 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            FSConnection::new("sqlite://test_migrations.db", "servefs_", true).await,
+            Err(FSError::SchemaTooNew(_))
+        ));
+
+        remove_test_db("test_migrations.db").await;
     }
 
     #[tokio::test]
     async fn test_file() {
-        remove_test_db().await;
+        remove_test_db("test_file.db").await;
 
-        let fs_conn = FSConnection::new("sqlite://test.db", "servefs_", true).await.unwrap();
+        let fs_conn = FSConnection::new("sqlite://test_file.db", "servefs_", true).await.unwrap();
         let mut file = File::new(PathBuf::from_str("/file").unwrap()).unwrap();
         
         assert!(!file.exists(&fs_conn).await.unwrap());
@@ -641,14 +4186,140 @@ mod tests {
         file.del(&fs_conn).await.unwrap();
         assert!(!file.exists(&fs_conn).await.unwrap());
 
-        remove_test_db().await;
+        remove_test_db("test_file.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_blob() {
+        remove_test_db("test_blob.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_blob.db", "servefs_", true).await.unwrap();
+        let mut file = File::new(PathBuf::from_str("/image.png").unwrap()).unwrap();
+        let data: Vec<u8> = vec![0x89, 0x50, 0x4e, 0x47, 0x00, 0xff, 0xfe];
+
+        file.mk_bytes(&data, &FileType::Blob, &fs_conn).await.unwrap();
+        let (read, ftype) = file.read_bytes(&fs_conn).await.unwrap();
+        assert_eq!(read, data);
+        assert_eq!(ftype, FileType::Blob.to_string());
+        assert!(file.read(&fs_conn).await.is_err());
+
+        file.write_bytes(&data[4..], FileType::Blob, &fs_conn).await.unwrap();
+        let (read, _) = file.read_bytes(&fs_conn).await.unwrap();
+        assert_eq!(read, data[4..].to_vec());
+
+        file.write("text", FileType::Text, &fs_conn).await.unwrap();
+        let (read, ftype) = file.read(&fs_conn).await.unwrap();
+        assert_eq!(read, "text");
+        assert_eq!(ftype, FileType::Text.to_string());
+
+        remove_test_db("test_blob.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        remove_test_db("test_transaction.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_transaction.db", "servefs_", true).await.unwrap();
+        let dir = Directory::new(PathBuf::from_str("/h/").unwrap()).unwrap();
+        let file = File::new(PathBuf::from_str("/h/file").unwrap()).unwrap();
+
+        let tx = fs_conn.transaction().await.unwrap();
+        dir.mk(&tx).await.unwrap();
+        file.mk("data", &FileType::Text, &tx).await.unwrap();
+        assert!(file.exists(&tx).await.unwrap());
+        assert!(matches!(tx.transaction().await, Err(FSError::NestedTransaction)));
+        tx.rollback().await.unwrap();
+        assert!(!dir.exists(&fs_conn).await.unwrap());
+        assert!(!file.exists(&fs_conn).await.unwrap());
+
+        {
+            let tx = fs_conn.transaction().await.unwrap();
+            dir.mk(&tx).await.unwrap();
+        }
+        assert!(!dir.exists(&fs_conn).await.unwrap());
+
+        let tx = fs_conn.transaction().await.unwrap();
+        dir.mk(&tx).await.unwrap();
+        file.mk("data", &FileType::Text, &tx).await.unwrap();
+        tx.commit().await.unwrap();
+        assert!(dir.exists(&fs_conn).await.unwrap());
+        assert_eq!(file.read(&fs_conn).await.unwrap().0, "data");
+
+        remove_test_db("test_transaction.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_directory_delete() {
+        remove_test_db("test_directory_delete.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_directory_delete.db", "servefs_", true).await.unwrap();
+        let dir = Directory::new(PathBuf::from_str("/h/").unwrap()).unwrap();
+        let sub = Directory::new(PathBuf::from_str("/h/a/").unwrap()).unwrap();
+        let deep = Directory::new(PathBuf::from_str("/h/a/b/").unwrap()).unwrap();
+        let sibling = Directory::new(PathBuf::from_str("/ha/").unwrap()).unwrap();
+        let file = File::new(PathBuf::from_str("/h/a/b/file").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        sub.mk(&fs_conn).await.unwrap();
+        deep.mk(&fs_conn).await.unwrap();
+        sibling.mk(&fs_conn).await.unwrap();
+        file.mk("data", &FileType::Text, &fs_conn).await.unwrap();
+
+        assert!(matches!(dir.del(false, &fs_conn).await, Err(FSError::NotEmpty(_))));
+        assert!(matches!(deep.del(false, &fs_conn).await, Err(FSError::NotEmpty(_))));
+        assert!(matches!(Directory::root().del(true, &fs_conn).await, Err(FSError::CannotDeleteRoot)));
+        assert!(dir.exists(&fs_conn).await.unwrap());
+
+        dir.del(true, &fs_conn).await.unwrap();
+        assert!(!dir.exists(&fs_conn).await.unwrap());
+        assert!(!sub.exists(&fs_conn).await.unwrap());
+        assert!(!deep.exists(&fs_conn).await.unwrap());
+        assert!(!file.exists(&fs_conn).await.unwrap());
+        assert!(sibling.exists(&fs_conn).await.unwrap());
+
+        sibling.del(false, &fs_conn).await.unwrap();
+        assert!(!sibling.exists(&fs_conn).await.unwrap());
+        assert!(matches!(sibling.del(false, &fs_conn).await, Err(FSError::NotFound(_))));
+
+        remove_test_db("test_directory_delete.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_directory_names() {
+        remove_test_db("test_directory_names.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_directory_names.db", "servefs_", true).await.unwrap();
+        let percent = Directory::new(PathBuf::from_str("/50%/").unwrap()).unwrap();
+        let underscore = Directory::new(PathBuf::from_str("/a_b/").unwrap()).unwrap();
+        let lookalike = Directory::new(PathBuf::from_str("/50x/").unwrap()).unwrap();
+        percent.mk(&fs_conn).await.unwrap();
+        underscore.mk(&fs_conn).await.unwrap();
+        lookalike.mk(&fs_conn).await.unwrap();
+        percent.dir("inner").unwrap().mk(&fs_conn).await.unwrap();
+
+        let dirs: Vec<String> = percent.dirs(&fs_conn).await.unwrap().into_iter().map(|d| d.path).collect();
+        assert_eq!(dirs, vec!["/50%/inner/".to_string()]);
+        assert!(!Directory::new(PathBuf::from_str("/axb/").unwrap()).unwrap().exists(&fs_conn).await.unwrap());
+
+        let mut moved = Directory::new(PathBuf::from_str("/50%/").unwrap()).unwrap();
+        moved.mv(&underscore.dir("50%").unwrap(), &fs_conn).await.unwrap();
+        assert!(Directory::new(PathBuf::from_str("/a_b/50%/inner/").unwrap()).unwrap().exists(&fs_conn).await.unwrap());
+        assert!(lookalike.exists(&fs_conn).await.unwrap());
+        assert!(matches!(
+            Directory::new(PathBuf::from_str(&underscore.path).unwrap()).unwrap().mv(&moved.dir("inner").unwrap(), &fs_conn).await,
+            Err(FSError::MoveIntoSelf(_))
+        ));
+
+        let id = moved.get_id(&fs_conn).await.unwrap();
+        assert_eq!(Directory::from_id(id, &fs_conn).await.unwrap().path, "/a_b/50%/");
+
+        remove_test_db("test_directory_names.db").await;
     }
 
     #[tokio::test]
     async fn test_directory() {
-        remove_test_db().await;
+        remove_test_db("test_directory.db").await;
 
-        let fs_conn = FSConnection::new("sqlite://test.db", "servefs_", true).await.unwrap();
+        let fs_conn = FSConnection::new("sqlite://test_directory.db", "servefs_", true).await.unwrap();
         let mut dir = Directory::new(PathBuf::from_str("/h/").unwrap()).unwrap();
         let sub_a = Directory::new(PathBuf::from_str("/h/a").unwrap()).unwrap();
         let sub_b = Directory::new(PathBuf::from_str("/h/b").unwrap()).unwrap();
@@ -688,9 +4359,13 @@ mod tests {
 
         let files = dir.files(&fs_conn).await.unwrap();
         assert_eq!(files.len(), 1);
-        assert_eq!(files[0].get::<String, &str>("name"), "file");
+        assert_eq!(files[0].name, "file");
+        assert_eq!(files[0].path, "/home/file");
+        assert_eq!(files[0].ftype, FileType::Text);
+        assert_eq!(files[0].metadata.size, 4);
+        assert_eq!(files[0].metadata.mode, 0o644);
 
-        let dirs: Vec<String>= dir.dirs(&fs_conn).await.unwrap().iter().map(|r| r.get("directory")).collect();
+        let dirs: Vec<String>= dir.dirs(&fs_conn).await.unwrap().into_iter().map(|d| d.path).collect();
         assert_eq!(dirs.len(), 2);
         assert!(dirs.contains(&"/home/a/".to_string()));
         assert!(dirs.contains(&"/home/b/".to_string()));
@@ -699,14 +4374,965 @@ mod tests {
         assert_eq!(all.0.len(), 2);
         assert_eq!(all.1.len(), 2);
 
-        let files: Vec<String>= all.0.iter().map(|r| r.get("name")).collect();
-        assert!(files.contains(&"file".to_string()));
-        assert!(files.contains(&"file_a".to_string()));
+        let files: Vec<String>= all.0.iter().map(|f| f.path.clone()).collect();
+        assert!(files.contains(&"/home/file".to_string()));
+        assert!(files.contains(&"/home/a/file_a".to_string()));
 
-        let dirs: Vec<String>= all.1.iter().map(|r| r.get("directory")).collect();
+        let dirs: Vec<String>= all.1.iter().map(|d| d.path.clone()).collect();
         assert!(dirs.contains(&"/home/a/".to_string()));
         assert!(dirs.contains(&"/home/b/".to_string()));
 
-        remove_test_db().await;
+        remove_test_db("test_directory.db").await;
+    }
+    #[tokio::test]
+    async fn test_metadata() {
+        remove_test_db("test_metadata.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_metadata.db", "servefs_", true).await.unwrap();
+        let dir = Directory::new(PathBuf::from_str("/m/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        let meta = dir.metadata(&fs_conn).await.unwrap();
+        assert_eq!(meta.mode, 0o755);
+        assert_eq!(meta.size, 0);
+        assert_eq!(meta.uid, None);
+
+        // Backdate everything so updates are visible at second precision.
+        QueryBuilder::new("UPDATE servefs_dirs SET created=1, modified=1, accessed=1;").build().execute(&fs_conn.pool).await.unwrap();
+
+        let mut file = dir.file("f");
+        file.mk("abc", &FileType::Text, &fs_conn).await.unwrap();
+        let meta = file.metadata(&fs_conn).await.unwrap();
+        assert_eq!(meta.size, 3);
+        assert_eq!(meta.mode, 0o644);
+        assert_eq!(meta.created, meta.modified);
+        assert!(dir.metadata(&fs_conn).await.unwrap().modified > UNIX_EPOCH + Duration::from_secs(1));
+
+        QueryBuilder::new("UPDATE servefs_files SET created=1, modified=1, accessed=1;").build().execute(&fs_conn.pool).await.unwrap();
+        file.write("abcdef", FileType::Text, &fs_conn).await.unwrap();
+        let meta = file.metadata(&fs_conn).await.unwrap();
+        assert_eq!(meta.size, 6);
+        assert_eq!(meta.created, UNIX_EPOCH + Duration::from_secs(1));
+        assert!(meta.modified > meta.created);
+        assert_eq!(meta.accessed, meta.created);
+
+        file.read(&fs_conn).await.unwrap();
+        assert!(file.metadata(&fs_conn).await.unwrap().accessed >= meta.modified);
+
+        file.set_mode(0o100600, &fs_conn).await.unwrap();
+        file.set_owner(Some(1000), Some(100), &fs_conn).await.unwrap();
+        let meta = file.metadata(&fs_conn).await.unwrap();
+        assert_eq!(meta.mode, 0o600);
+        assert_eq!((meta.uid, meta.gid), (Some(1000), Some(100)));
+        assert_eq!(dir.files(&fs_conn).await.unwrap()[0].metadata, meta);
+
+        remove_test_db("test_metadata.db").await;
+    }
+    #[tokio::test]
+    async fn test_links() {
+        remove_test_db("test_links.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_links.db", "servefs_", true).await.unwrap();
+        let logs = Directory::new(PathBuf::from_str("/logs/").unwrap()).unwrap();
+        logs.mk(&fs_conn).await.unwrap();
+        logs.file("2026-10-17.log").mk("today", &FileType::Text, &fs_conn).await.unwrap();
+
+        let latest = File::new(PathBuf::from_str("/latest.log").unwrap()).unwrap();
+        latest.mk_link(&PathBuf::from_str("/logs/2026-10-17.log").unwrap(), &fs_conn).await.unwrap();
+        assert_eq!(latest.read_link(&fs_conn).await.unwrap(), PathBuf::from_str("/logs/2026-10-17.log").unwrap());
+        match fs_conn.resolve_path(PathBuf::from_str("/latest.log").unwrap()).await.unwrap() {
+            FSType::File(file) => assert_eq!(file.read(&fs_conn).await.unwrap().0, "today"),
+            FSType::Directory(_) => panic!("expected a file"),
+        }
+
+        // Relative targets, and links in the middle of a path.
+        logs.file("current").mk_link(&PathBuf::from_str(".").unwrap(), &fs_conn).await.unwrap();
+        File::new(PathBuf::from_str("/l").unwrap()).unwrap().mk_link(&PathBuf::from_str("logs/current").unwrap(), &fs_conn).await.unwrap();
+        match fs_conn.resolve_path(PathBuf::from_str("/l/2026-10-17.log").unwrap()).await.unwrap() {
+            FSType::File(file) => assert_eq!(file.directory.path, "/logs/"),
+            FSType::Directory(_) => panic!("expected a file"),
+        }
+        assert!(matches!(fs_conn.resolve_path(PathBuf::from_str("/l").unwrap()).await, Ok(FSType::Directory(dir)) if dir.path == "/logs/"));
+
+        File::new(PathBuf::from_str("/a").unwrap()).unwrap().mk_link(&PathBuf::from_str("/b").unwrap(), &fs_conn).await.unwrap();
+        File::new(PathBuf::from_str("/b").unwrap()).unwrap().mk_link(&PathBuf::from_str("/a").unwrap(), &fs_conn).await.unwrap();
+        assert!(matches!(fs_conn.resolve_path(PathBuf::from_str("/a").unwrap()).await, Err(FSError::LinkLoop(_))));
+        assert!(matches!(fs_conn.resolve_path(PathBuf::from_str("/latest.log/x").unwrap()).await, Err(FSError::NotFound(_))));
+        assert!(matches!(logs.file("2026-10-17.log").read_link(&fs_conn).await, Err(FSError::NotALink(_))));
+
+        remove_test_db("test_links.db").await;
+    }
+    #[tokio::test]
+    async fn test_file_types() {
+        remove_test_db("test_file_types.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_file_types.db", "servefs_", true).await.unwrap();
+        let upper = FileType::from_str("upper").unwrap();
+        assert_eq!(upper, FileType::Custom("upper".to_string()));
+        assert!(matches!(FileType::from_str("not a type"), Err(FSError::InvalidType(_))));
+
+        let file = File::new(PathBuf::from_str("/shout").unwrap()).unwrap();
+        assert!(file.mk("hello", &upper, &fs_conn).await.is_err());
+
+        fs_conn.register_file_type(&upper, "tr a-z A-Z").await.unwrap();
+        assert!(matches!(fs_conn.register_file_type(&FileType::Text, "cat").await, Err(FSError::BuiltinType(_))));
+        file.mk("hello", &upper, &fs_conn).await.unwrap();
+
+        let types = fs_conn.file_types().await.unwrap();
+        assert!(types.iter().any(|t| t.ftype == FileType::Text && t.handler.is_none()));
+        assert!(types.iter().any(|t| t.ftype == upper && t.handler.as_deref() == Some("tr a-z A-Z")));
+
+        let (data, ftype) = file.read_bytes(&fs_conn).await.unwrap();
+        let ftype = FileType::from_str(&ftype).unwrap();
+        assert_eq!(fs_conn.render_custom(&ftype, "/shout", &data).await.unwrap(), b"HELLO");
+
+        fs_conn.register_file_type(&upper, "printf %s \"$SERVEFS_PATH\"").await.unwrap();
+        assert_eq!(fs_conn.render_custom(&ftype, "/shout", &data).await.unwrap(), b"/shout");
+
+        assert!(fs_conn.unregister_file_type(&upper).await.is_err());
+        file.del(&fs_conn).await.unwrap();
+        assert!(fs_conn.unregister_file_type(&upper).await.is_err());
+        fs_conn.empty_trash().await.unwrap();
+        fs_conn.unregister_file_type(&upper).await.unwrap();
+        assert!(matches!(fs_conn.file_type_handler(&upper).await, Err(FSError::InvalidType(_))));
+
+        remove_test_db("test_file_types.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        remove_test_db("test_errors.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_errors.db", "servefs_", true).await.unwrap();
+        let mut file = File::new(PathBuf::from_str("/missing/file").unwrap()).unwrap();
+        assert!(matches!(file.read(&fs_conn).await, Err(FSError::NotFound(path)) if path == "/missing/"));
+        assert!(matches!(file.mk("data", &FileType::Text, &fs_conn).await, Err(FSError::NotFound(path)) if path == "/missing/"));
+
+        let dir = Directory::new(PathBuf::from_str("/missing/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        let err = file.read(&fs_conn).await.unwrap_err();
+        assert!(matches!(&err, FSError::NotFound(path) if path == "/missing/file"));
+        assert_eq!(err.to_string(), "/missing/file does not exist");
+        assert!(matches!(file.write("data", FileType::Text, &fs_conn).await, Err(FSError::NotFound(_))));
+        assert!(matches!(file.del(&fs_conn).await, Err(FSError::NotFound(_))));
+
+        file.mk("data", &FileType::Text, &fs_conn).await.unwrap();
+        assert!(matches!(file.mk("data", &FileType::Text, &fs_conn).await, Err(FSError::AlreadyExists(path)) if path == "/missing/file"));
+        assert!(matches!(dir.mk(&fs_conn).await, Err(FSError::AlreadyExists(path)) if path == "/missing/"));
+        assert!(matches!(dir.file("other").mk("data", &FileType::from_str("unregistered").unwrap(), &fs_conn).await, Err(FSError::InvalidType(_))));
+        dir.file("other").mk("data", &FileType::Text, &fs_conn).await.unwrap();
+        assert!(matches!(file.rename("other", &fs_conn).await, Err(FSError::AlreadyExists(path)) if path == "/missing/other"));
+        assert!(matches!(dir.del(false, &fs_conn).await, Err(FSError::NotEmpty(_))));
+
+        file.write_bytes(&[0xff], FileType::Blob, &fs_conn).await.unwrap();
+        assert!(matches!(file.read(&fs_conn).await, Err(FSError::InvalidData(_))));
+
+        remove_test_db("test_errors.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_contents() {
+        remove_test_db("test_contents.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_contents.db", "servefs_", true).await.unwrap();
+        let dir = Directory::new(PathBuf::from_str("/copies/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        let mut first = dir.file("first");
+        let second = dir.file("second");
+        first.mk("shared", &FileType::Text, &fs_conn).await.unwrap();
+        second.mk("shared", &FileType::Text, &fs_conn).await.unwrap();
+        assert_eq!(content_refs(&fs_conn).await, vec![2]);
+
+        // The overwritten data is still referenced by first's history.
+        first.write("changed", FileType::Text, &fs_conn).await.unwrap();
+        assert_eq!(content_refs(&fs_conn).await, vec![1, 2]);
+        assert_eq!(second.read(&fs_conn).await.unwrap().0, "shared");
+
+        first.write("shared", FileType::Text, &fs_conn).await.unwrap();
+        assert_eq!(content_refs(&fs_conn).await, vec![1, 3]);
+
+        // Trashed files hold on to their data until purged.
+        first.del(&fs_conn).await.unwrap();
+        assert_eq!(content_refs(&fs_conn).await, vec![1, 3]);
+        fs_conn.empty_trash().await.unwrap();
+        assert_eq!(content_refs(&fs_conn).await, vec![1]);
+        assert_eq!(second.read(&fs_conn).await.unwrap().0, "shared");
+
+        dir.del(true, &fs_conn).await.unwrap();
+        fs_conn.empty_trash().await.unwrap();
+        assert!(content_refs(&fs_conn).await.is_empty());
+
+        remove_test_db("test_contents.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        remove_test_db("test_history.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_history.db", "servefs_", true).await.unwrap();
+        let mut file = File::new(PathBuf::from_str("/notes").unwrap()).unwrap();
+        file.mk("first", &FileType::Text, &fs_conn).await.unwrap();
+        assert!(file.history(&fs_conn).await.unwrap().is_empty());
+
+        file.write("second", FileType::Text, &fs_conn).await.unwrap();
+        file.write_bytes(&[0xff, 0x00], FileType::Blob, &fs_conn).await.unwrap();
+        let history = file.history(&fs_conn).await.unwrap();
+        assert_eq!(history.iter().map(|v| v.version).collect::<Vec<i64>>(), vec![1, 2]);
+        assert_eq!(history[0].ftype, FileType::Text);
+        assert_eq!(history[0].size, 5);
+        assert_eq!(history[1].size, 6);
+
+        let (data, ftype) = file.read_version(1, &fs_conn).await.unwrap();
+        assert_eq!(data, b"first");
+        assert_eq!(ftype, FileType::Text.to_string());
+        assert!(matches!(file.read_version(3, &fs_conn).await, Err(FSError::NotFound(path)) if path == "/notes version 3"));
+
+        file.restore(1, &fs_conn).await.unwrap();
+        assert_eq!(file.read(&fs_conn).await.unwrap(), ("first".to_string(), FileType::Text.to_string()));
+        assert_eq!(file.metadata(&fs_conn).await.unwrap().size, 5);
+        let history = file.history(&fs_conn).await.unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[2].ftype, FileType::Blob);
+        assert_eq!(file.read_version(3, &fs_conn).await.unwrap().0, vec![0xff, 0x00]);
+
+        let mut moved = file.directory.file("moved");
+        file.rename("moved", &fs_conn).await.unwrap();
+        assert_eq!(moved.history(&fs_conn).await.unwrap().len(), 3);
+        assert!(matches!(moved.restore(9, &fs_conn).await, Err(FSError::NotFound(_))));
+
+        moved.del(&fs_conn).await.unwrap();
+        fs_conn.empty_trash().await.unwrap();
+        assert_eq!(content_refs(&fs_conn).await, Vec::<i64>::new());
+
+        remove_test_db("test_history.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        remove_test_db("test_snapshots.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_snapshots.db", "servefs_", true).await.unwrap();
+        let mut dir = Directory::new(PathBuf::from_str("/docs/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        let mut file = dir.file("plan");
+        file.mk("original", &FileType::Text, &fs_conn).await.unwrap();
+        let upper = FileType::from_str("upper").unwrap();
+        fs_conn.register_file_type(&upper, "tr a-z A-Z").await.unwrap();
+
+        fs_conn.create_snapshot("before").await.unwrap();
+        assert!(matches!(fs_conn.create_snapshot("before").await, Err(FSError::AlreadyExists(_))));
+        let snapshots = fs_conn.snapshots().await.unwrap();
+        assert_eq!(snapshots.iter().map(|s| s.name.as_str()).collect::<Vec<&str>>(), vec!["before"]);
+
+        file.write("rewritten", FileType::Text, &fs_conn).await.unwrap();
+        Directory::new(PathBuf::from_str("/docs/new/").unwrap()).unwrap().mk(&fs_conn).await.unwrap();
+        dir.mv(&Directory::new(PathBuf::from_str("/moved/").unwrap()).unwrap(), &fs_conn).await.unwrap();
+        Directory::new(PathBuf::from_str("/docs/").unwrap()).unwrap().mk(&fs_conn).await.unwrap();
+        fs_conn.unregister_file_type(&upper).await.unwrap();
+
+        fs_conn.restore_snapshot("before").await.unwrap();
+        let file = File::new(PathBuf::from_str("/docs/plan").unwrap()).unwrap();
+        assert_eq!(file.read(&fs_conn).await.unwrap().0, "original");
+        assert_eq!(file.history(&fs_conn).await.unwrap().len(), 1);
+        assert_eq!(file.read_version(1, &fs_conn).await.unwrap().0, b"original");
+        assert!(!Directory::new(PathBuf::from_str("/moved/").unwrap()).unwrap().exists(&fs_conn).await.unwrap());
+        assert!(!Directory::new(PathBuf::from_str("/docs/new/").unwrap()).unwrap().exists(&fs_conn).await.unwrap());
+        assert_eq!(fs_conn.file_type_handler(&upper).await.unwrap(), Some("tr a-z A-Z".to_string()));
+        assert!(matches!(fs_conn.restore_snapshot("missing").await, Err(FSError::NotFound(name)) if name == "snapshot missing"));
+
+        // The snapshot still holds "original" after the live file is gone.
+        file.del(&fs_conn).await.unwrap();
+        fs_conn.empty_trash().await.unwrap();
+        assert_eq!(content_refs(&fs_conn).await, vec![1]);
+        fs_conn.delete_snapshot("before").await.unwrap();
+        assert!(content_refs(&fs_conn).await.is_empty());
+        assert!(fs_conn.snapshots().await.unwrap().is_empty());
+        assert!(matches!(fs_conn.delete_snapshot("before").await, Err(FSError::NotFound(_))));
+
+        remove_test_db("test_snapshots.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_attrs() {
+        remove_test_db("test_attrs.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_attrs.db", "servefs_", true).await.unwrap();
+        let dir = Directory::new(PathBuf::from_str("/team/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        let mut file = dir.file("readme");
+        file.mk("hello", &FileType::Text, &fs_conn).await.unwrap();
+
+        assert_eq!(file.attr("owner", &fs_conn).await.unwrap(), None);
+        file.set_attr("owner", b"infra", &fs_conn).await.unwrap();
+        file.set_attr("tags", b"docs,public", &fs_conn).await.unwrap();
+        file.set_attr("owner", b"platform", &fs_conn).await.unwrap();
+        assert_eq!(file.attr("owner", &fs_conn).await.unwrap(), Some(b"platform".to_vec()));
+        assert_eq!(file.attrs(&fs_conn).await.unwrap().keys().collect::<Vec<&String>>(), vec!["owner", "tags"]);
+
+        dir.set_attr("description", b"team docs", &fs_conn).await.unwrap();
+        assert_eq!(dir.attrs(&fs_conn).await.unwrap().len(), 1);
+        assert_eq!(Directory::root().attrs(&fs_conn).await.unwrap().len(), 0);
+
+        // Attributes stay with the entry when it is renamed.
+        file.rename("intro", &fs_conn).await.unwrap();
+        assert_eq!(file.attrs(&fs_conn).await.unwrap().len(), 2);
+        file.remove_attr("tags", &fs_conn).await.unwrap();
+        assert!(matches!(file.remove_attr("tags", &fs_conn).await, Err(FSError::NotFound(path)) if path == "/team/intro attribute tags"));
+
+        fs_conn.create_snapshot("attrs").await.unwrap();
+        file.set_attr("owner", b"someone else", &fs_conn).await.unwrap();
+        dir.del(true, &fs_conn).await.unwrap();
+        assert!(matches!(file.attrs(&fs_conn).await, Err(FSError::NotFound(_))));
+        fs_conn.restore_snapshot("attrs").await.unwrap();
+        assert_eq!(file.attr("owner", &fs_conn).await.unwrap(), Some(b"platform".to_vec()));
+        assert_eq!(dir.attr("description", &fs_conn).await.unwrap(), Some(b"team docs".to_vec()));
+
+        remove_test_db("test_attrs.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        remove_test_db("test_search.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_search.db", "servefs_", true).await.unwrap();
+        let dir = Directory::new(PathBuf::from_str("/notes/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        let mut budget = dir.file("budget");
+        budget.mk("the budget for the offsite is tight", &FileType::Text, &fs_conn).await.unwrap();
+        dir.file("menu").mk("pizza and salad", &FileType::Text, &fs_conn).await.unwrap();
+        dir.file("script").mk("echo budget", &FileType::Exec, &fs_conn).await.unwrap();
+
+        let results = fs_conn.search("budget", 10).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, "/notes/budget");
+        assert!(results[0].snippet.contains("[budget]"));
+        assert_eq!(fs_conn.search("menu", 10).await.unwrap()[0].path, "/notes/menu");
+
+        budget.write("no more money talk", FileType::Text, &fs_conn).await.unwrap();
+        // Names are indexed too, so the file still matches by its name.
+        let results = fs_conn.search("budget", 10).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].snippet, "no more money talk");
+        assert_eq!(fs_conn.search("offsite", 10).await.unwrap().len(), 0);
+        assert_eq!(fs_conn.search("money", 10).await.unwrap()[0].path, "/notes/budget");
+
+        budget.rename("finance", &fs_conn).await.unwrap();
+        assert_eq!(fs_conn.search("money", 10).await.unwrap()[0].path, "/notes/finance");
+        budget.write_bytes(b"money", FileType::Blob, &fs_conn).await.unwrap();
+        assert!(fs_conn.search("money", 10).await.unwrap().is_empty());
+        budget.restore(1, &fs_conn).await.unwrap();
+        assert_eq!(fs_conn.search("offsite", 10).await.unwrap()[0].path, "/notes/finance");
+
+        dir.del(true, &fs_conn).await.unwrap();
+        assert!(fs_conn.search("pizza", 10).await.unwrap().is_empty());
+        assert!(matches!(fs_conn.search("\"unbalanced", 10).await, Err(FSError::InvalidQuery(_))));
+        assert!(matches!(fs_conn.search("nosuch:column", 10).await, Err(FSError::InvalidQuery(_))));
+        assert!(matches!(fs_conn.search("dangling AND", 10).await, Err(FSError::InvalidQuery(_))));
+
+        // Anything else wrong with the database isn't blamed on the query.
+        sqlx::query(&format!("DROP TABLE {}", fs_conn.search_table)).execute(&fs_conn.pool).await.unwrap();
+        assert!(matches!(fs_conn.search("pizza", 10).await, Err(FSError::Database(_))));
+
+        remove_test_db("test_search.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_find() {
+        remove_test_db("test_find.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_find.db", "servefs_", true).await.unwrap();
+        let docs = Directory::new(PathBuf::from_str("/docs/").unwrap()).unwrap();
+        let deep = Directory::new(PathBuf::from_str("/docs/a/b/").unwrap()).unwrap();
+        for dir in ["/docs/", "/docs/a/", "/docs/a/b/", "/other/"] {
+            Directory::new(PathBuf::from_str(dir).unwrap()).unwrap().mk(&fs_conn).await.unwrap();
+        }
+        docs.file("readme.md").mk("hello", &FileType::Text, &fs_conn).await.unwrap();
+        docs.file("run.sh").mk("echo hi", &FileType::Exec, &fs_conn).await.unwrap();
+        deep.file("notes.md").mk("a longer note", &FileType::Text, &fs_conn).await.unwrap();
+        Directory::new(PathBuf::from_str("/other/").unwrap()).unwrap()
+            .file("skip.md").mk("outside", &FileType::Text, &fs_conn).await.unwrap();
+
+        let paths = |files: Vec<FileEntry>| files.into_iter().map(|f| f.path).collect::<Vec<String>>();
+        assert_eq!(paths(docs.find(&Find::new(), &fs_conn).await.unwrap()),
+            vec!["/docs/a/b/notes.md", "/docs/readme.md", "/docs/run.sh"]);
+        assert_eq!(paths(docs.find(&Find::new().name("*.md"), &fs_conn).await.unwrap()),
+            vec!["/docs/a/b/notes.md", "/docs/readme.md"]);
+        assert_eq!(paths(docs.find(&Find::new().ftype(FileType::Exec), &fs_conn).await.unwrap()),
+            vec!["/docs/run.sh"]);
+        assert_eq!(paths(docs.find(&Find::new().min_size(6).max_size(7), &fs_conn).await.unwrap()),
+            vec!["/docs/run.sh"]);
+        assert_eq!(paths(docs.find(&Find::new().max_depth(1), &fs_conn).await.unwrap()),
+            vec!["/docs/readme.md", "/docs/run.sh"]);
+        assert_eq!(paths(docs.find(&Find::new().max_depth(2), &fs_conn).await.unwrap()).len(), 2);
+        assert_eq!(paths(docs.find(&Find::new().max_depth(3), &fs_conn).await.unwrap()).len(), 3);
+        assert!(docs.find(&Find::new().max_depth(0), &fs_conn).await.unwrap().is_empty());
+        assert_eq!(paths(docs.find(&Find::new().sort(SortBy::Size, true).limit(2), &fs_conn).await.unwrap()),
+            vec!["/docs/a/b/notes.md", "/docs/run.sh"]);
+        assert_eq!(paths(docs.find(&Find::new().sort(SortBy::Name, false), &fs_conn).await.unwrap()),
+            vec!["/docs/a/b/notes.md", "/docs/readme.md", "/docs/run.sh"]);
+
+        let now = std::time::SystemTime::now();
+        assert!(docs.find(&Find::new().modified_after(now + Duration::from_secs(60)), &fs_conn).await.unwrap().is_empty());
+        assert_eq!(docs.find(&Find::new().modified_before(now + Duration::from_secs(60)), &fs_conn).await.unwrap().len(), 3);
+        assert_eq!(Directory::root().find(&Find::new().name("*.md"), &fs_conn).await.unwrap().len(), 3);
+
+        remove_test_db("test_find.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_quotas() {
+        remove_test_db("test_quotas.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_quotas.db", "servefs_", true).await.unwrap();
+        let team = Directory::new(PathBuf::from_str("/team/").unwrap()).unwrap();
+        let nested = Directory::new(PathBuf::from_str("/team/nested/").unwrap()).unwrap();
+        team.mk(&fs_conn).await.unwrap();
+        nested.mk(&fs_conn).await.unwrap();
+        assert_eq!(team.quota(&fs_conn).await.unwrap(), None);
+        team.set_quota(&Quota { max_bytes: Some(10), max_entries: Some(3) }, &fs_conn).await.unwrap();
+        assert_eq!(team.quota(&fs_conn).await.unwrap(), Some(Quota { max_bytes: Some(10), max_entries: Some(3) }));
+
+        let mut file = nested.file("a");
+        file.mk("12345", &FileType::Text, &fs_conn).await.unwrap();
+        assert_eq!(team.usage(&fs_conn).await.unwrap(), Usage { bytes: 5, entries: 2 });
+        assert!(matches!(
+            nested.file("b").mk("123456", &FileType::Text, &fs_conn).await,
+            Err(FSError::QuotaExceeded(path)) if path == "/team/"
+        ));
+        assert!(!nested.file("b").exists(&fs_conn).await.unwrap());
+        assert!(matches!(file.write("12345678901", FileType::Text, &fs_conn).await, Err(FSError::QuotaExceeded(_))));
+        assert_eq!(file.read(&fs_conn).await.unwrap().0, "12345");
+        file.write("1234567890", FileType::Text, &fs_conn).await.unwrap();
+
+        // Entries: nested, a and one more.
+        team.file("b").mk("", &FileType::Text, &fs_conn).await.unwrap();
+        assert!(matches!(team.dir("c").unwrap().mk(&fs_conn).await, Err(FSError::QuotaExceeded(_))));
+
+        // Moving within the quota is fine, moving in from outside counts.
+        let mut outside = Directory::root().file("outside");
+        outside.mk("x", &FileType::Text, &fs_conn).await.unwrap();
+        assert!(matches!(outside.mv(Directory::new(PathBuf::from_str("/team/").unwrap()).unwrap(), &fs_conn).await, Err(FSError::QuotaExceeded(_))));
+        let mut b = team.file("b");
+        b.mv(Directory::new(PathBuf::from_str("/team/nested/").unwrap()).unwrap(), &fs_conn).await.unwrap();
+
+        // Lowering the quota leaves existing data alone but still allows shrinking.
+        team.set_quota(&Quota { max_bytes: Some(4), max_entries: None }, &fs_conn).await.unwrap();
+        file.write("123", FileType::Text, &fs_conn).await.unwrap();
+        assert!(matches!(file.restore(1, &fs_conn).await, Err(FSError::QuotaExceeded(_))));
+
+        team.remove_quota(&fs_conn).await.unwrap();
+        assert!(matches!(team.remove_quota(&fs_conn).await, Err(FSError::NotFound(_))));
+        outside.mv(Directory::new(PathBuf::from_str("/team/").unwrap()).unwrap(), &fs_conn).await.unwrap();
+
+        remove_test_db("test_quotas.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_changes() {
+        remove_test_db("test_changes.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_changes.db", "servefs_", true).await.unwrap();
+        assert_eq!(fs_conn.last_change().await.unwrap(), 0);
+        let mut subscription = fs_conn.subscribe(0, Duration::from_millis(10));
+
+        let mut dir = Directory::new(PathBuf::from_str("/docs/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        let mut file = dir.file("a");
+        file.mk("1", &FileType::Text, &fs_conn).await.unwrap();
+        file.write("2", FileType::Text, &fs_conn).await.unwrap();
+        file.set_attr("tag", b"x", &fs_conn).await.unwrap();
+        file.rename("b", &fs_conn).await.unwrap();
+        dir.mv(&Directory::new(PathBuf::from_str("/notes/").unwrap()).unwrap(), &fs_conn).await.unwrap();
+
+        // Rolled back changes are never logged.
+        let tx = fs_conn.transaction().await.unwrap();
+        Directory::root().file("gone").mk("", &FileType::Text, &tx).await.unwrap();
+        tx.rollback().await.unwrap();
+        dir.del(true, &fs_conn).await.unwrap();
+
+        let changes = fs_conn.changes_since(0, 100).await.unwrap();
+        let summary: Vec<_> = changes.iter()
+            .map(|c| (c.kind, c.path.as_str(), c.old_path.as_deref()))
+            .collect();
+        assert_eq!(summary, vec![
+            (ChangeKind::Create, "/docs/", None),
+            (ChangeKind::Create, "/docs/a", None),
+            (ChangeKind::Update, "/docs/a", None),
+            (ChangeKind::Update, "/docs/a", None),
+            (ChangeKind::Move, "/docs/b", Some("/docs/a")),
+            (ChangeKind::Move, "/notes/", Some("/docs/")),
+            (ChangeKind::Delete, "/notes/", None),
+        ]);
+        assert_eq!(fs_conn.last_change().await.unwrap(), changes[6].seq);
+        assert_eq!(fs_conn.changes_since(changes[4].seq, 1).await.unwrap(), vec![changes[5].clone()]);
+
+        // Other connections to the same database see the same log.
+        let other = FSConnection::new("sqlite://test_changes.db", "servefs_", false).await.unwrap();
+        for change in &changes {
+            assert_eq!(&subscription.next().await.unwrap(), change);
+        }
+        Directory::root().file("late").mk("", &FileType::Text, &other).await.unwrap();
+        let late = subscription.next().await.unwrap();
+        assert_eq!((late.kind, late.path.as_str()), (ChangeKind::Create, "/late"));
+        assert_eq!(subscription.last_seq(), late.seq);
+
+        fs_conn.prune_changes(changes[6].seq).await.unwrap();
+        assert_eq!(fs_conn.changes_since(0, 100).await.unwrap(), vec![late]);
+
+        remove_test_db("test_changes.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_trash() {
+        remove_test_db("test_trash.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_trash.db", "servefs_", true).await.unwrap();
+        let dir = Directory::new(PathBuf::from_str("/docs/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        let file = dir.file("plan");
+        file.mk("budget", &FileType::Text, &fs_conn).await.unwrap();
+        file.set_attr("owner", b"infra", &fs_conn).await.unwrap();
+        let sub = dir.dir("sub").unwrap();
+        sub.mk(&fs_conn).await.unwrap();
+        sub.file("notes").mk("budget", &FileType::Text, &fs_conn).await.unwrap();
+        dir.set_quota(&Quota { max_bytes: Some(6), max_entries: None }, &fs_conn).await.unwrap();
+
+        file.del(&fs_conn).await.unwrap();
+        sub.del(true, &fs_conn).await.unwrap();
+        assert!(!file.exists(&fs_conn).await.unwrap());
+        assert!(!sub.exists(&fs_conn).await.unwrap());
+        assert_eq!(dir.usage(&fs_conn).await.unwrap(), Usage { bytes: 0, entries: 0 });
+        assert!(fs_conn.search("budget", 10).await.unwrap().is_empty());
+
+        let trash = fs_conn.trash().await.unwrap();
+        assert_eq!(trash.iter().map(|t| (t.path.as_str(), t.is_dir())).collect::<Vec<_>>(), vec![("/docs/sub/", true), ("/docs/plan", false)]);
+
+        // Restoring puts the entry back with its data and attributes, if nothing has taken its place.
+        file.mk("other", &FileType::Text, &fs_conn).await.unwrap();
+        assert!(matches!(fs_conn.restore_trash(trash[1].id).await, Err(FSError::AlreadyExists(path)) if path == "/docs/plan"));
+        file.del(&fs_conn).await.unwrap();
+        fs_conn.restore_trash(trash[1].id).await.unwrap();
+        assert_eq!(file.read(&fs_conn).await.unwrap().0, "budget");
+        assert_eq!(file.attr("owner", &fs_conn).await.unwrap(), Some(b"infra".to_vec()));
+        assert!(matches!(fs_conn.restore_trash(trash[0].id).await, Err(FSError::QuotaExceeded(_))));
+        dir.remove_quota(&fs_conn).await.unwrap();
+        fs_conn.restore_trash(trash[0].id).await.unwrap();
+        assert_eq!(sub.file("notes").read(&fs_conn).await.unwrap().0, "budget");
+        assert_eq!(fs_conn.search("budget", 10).await.unwrap().len(), 2);
+        assert!(matches!(fs_conn.restore_trash(trash[0].id).await, Err(FSError::NotFound(_))));
+
+        let other = fs_conn.trash().await.unwrap();
+        assert_eq!(other.len(), 1);
+        fs_conn.purge_trash(other[0].id).await.unwrap();
+        assert!(matches!(fs_conn.purge_trash(other[0].id).await, Err(FSError::NotFound(_))));
+
+        // A zero retention period purges everything already in the trash.
+        assert_eq!(fs_conn.trash_retention().await.unwrap(), Some(DEFAULT_TRASH_RETENTION));
+        sub.del(true, &fs_conn).await.unwrap();
+        fs_conn.set_trash_retention(Some(Duration::ZERO)).await.unwrap();
+        assert!(fs_conn.trash().await.unwrap().is_empty());
+        fs_conn.set_trash_retention(None).await.unwrap();
+        file.del(&fs_conn).await.unwrap();
+        assert_eq!(fs_conn.trash().await.unwrap().len(), 1);
+        assert_eq!(fs_conn.trash_retention().await.unwrap(), None);
+
+        remove_test_db("test_trash.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_ids() {
+        remove_test_db("test_ids.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_ids.db", "servefs_", true).await.unwrap();
+        let mut dir = Directory::new(PathBuf::from_str("/docs/").unwrap()).unwrap();
+        dir.mk(&fs_conn).await.unwrap();
+        dir.file("plan").mk("draft", &FileType::Text, &fs_conn).await.unwrap();
+        assert_eq!(dir.id(), None);
+
+        let dir_id = dir.resolve(&fs_conn).await.unwrap();
+        assert_eq!(dir.id(), Some(dir_id));
+        let mut file = dir.file("plan");
+        assert_eq!(file.directory.id(), Some(dir_id));
+        let file_id = file.resolve(&fs_conn).await.unwrap();
+        assert_eq!(file.id(), Some(file_id));
+        assert!(matches!(dir.file("missing").resolve(&fs_conn).await, Err(FSError::NotFound(_))));
+
+        // Handles made from ids or listings come with them cached.
+        assert_eq!(File::from_id(file_id, &fs_conn).await.unwrap().id(), Some(file_id));
+        assert_eq!(File::from_id(file_id, &fs_conn).await.unwrap().directory.id(), Some(dir_id));
+        assert_eq!(Directory::from_id(dir_id, &fs_conn).await.unwrap().id(), Some(dir_id));
+        assert_eq!(dir.files(&fs_conn).await.unwrap()[0].file().unwrap().id(), Some(file_id));
+        match fs_conn.resolve_path(PathBuf::from_str("/docs/plan").unwrap()).await.unwrap() {
+            FSType::File(file) => assert_eq!((file.id(), file.directory.id()), (Some(file_id), Some(dir_id))),
+            FSType::Directory(_) => panic!("expected a file"),
+        }
+
+        // A resolved handle keeps following the file when it is renamed or moved.
+        file.rename("final", &fs_conn).await.unwrap();
+        dir.mv(&Directory::new(PathBuf::from_str("/archive/").unwrap()).unwrap(), &fs_conn).await.unwrap();
+        assert_eq!(dir.id(), Some(dir_id));
+        file.set_mode(0o600, &fs_conn).await.unwrap();
+        assert_eq!(file.read(&fs_conn).await.unwrap().0, "draft");
+        assert_eq!(file.metadata(&fs_conn).await.unwrap().mode, 0o600);
+        assert_eq!(File::new(PathBuf::from_str("/archive/final").unwrap()).unwrap().get_id(&fs_conn).await.unwrap(), file_id);
+
+        // Renaming, moving and deleting go by the cached id too, even once the handle's name is stale.
+        File::new(PathBuf::from_str("/archive/final").unwrap()).unwrap().rename("shipped", &fs_conn).await.unwrap();
+        file.rename("kept", &fs_conn).await.unwrap();
+        assert_eq!(File::new(PathBuf::from_str("/archive/kept").unwrap()).unwrap().get_id(&fs_conn).await.unwrap(), file_id);
+        let out = Directory::new(PathBuf::from_str("/out/").unwrap()).unwrap();
+        out.mk(&fs_conn).await.unwrap();
+        file.mv(Directory::new(PathBuf::from_str("/out/").unwrap()).unwrap(), &fs_conn).await.unwrap();
+        assert_eq!(out.file("kept").get_id(&fs_conn).await.unwrap(), file_id);
+        file.del(&fs_conn).await.unwrap();
+        assert!(!out.file("kept").exists(&fs_conn).await.unwrap());
+
+        remove_test_db("test_ids.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_chunks() {
+        remove_test_db("test_chunks.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_chunks.db", "servefs_", true).await.unwrap();
+        let chunk_count = || async {
+            sqlx::query(&format!("SELECT COUNT(*) AS chunks FROM {}", fs_conn.chunk_table))
+                .fetch_one(&fs_conn.pool)
+                .await
+                .unwrap()
+                .get::<i64, &str>("chunks")
+        };
+        let data: Vec<u8> = (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+        let mut file = Directory::root().file("large");
+        file.mk("", &FileType::Blob, &fs_conn).await.unwrap();
+        file.write_from(&data[..], FileType::Blob, &fs_conn).await.unwrap();
+        assert_eq!(chunk_count().await, 3);
+        assert_eq!(file.read_bytes(&fs_conn).await.unwrap().0, data);
+        assert_eq!(file.metadata(&fs_conn).await.unwrap().size, data.len() as u64);
+
+        // Reads only return the requested range, across chunk boundaries and up to the end.
+        assert_eq!(file.read_at(10, 5, &fs_conn).await.unwrap(), &data[10..15]);
+        assert_eq!(file.read_at(CHUNK_SIZE as u64 - 3, 6, &fs_conn).await.unwrap(), &data[CHUNK_SIZE - 3..CHUNK_SIZE + 3]);
+        assert_eq!(file.read_at(CHUNK_SIZE as u64 - 1, CHUNK_SIZE + 2, &fs_conn).await.unwrap(), &data[CHUNK_SIZE - 1..CHUNK_SIZE * 2 + 1]);
+        assert_eq!(file.read_at(data.len() as u64 - 2, 10, &fs_conn).await.unwrap(), &data[data.len() - 2..]);
+        assert!(file.read_at(data.len() as u64 + 1, 10, &fs_conn).await.unwrap().is_empty());
+        assert!(file.read_at(0, 0, &fs_conn).await.unwrap().is_empty());
+
+        // Storing the same data again reuses it.
+        Directory::root().file("copy").mk_bytes(&data, &FileType::Blob, &fs_conn).await.unwrap();
+        assert_eq!(chunk_count().await, 3);
+
+        // Content ids aren't reused once the newest content is gone.
+        let newest_id = || async {
+            sqlx::query(&format!("SELECT MAX(id) AS id FROM {}", fs_conn.content_table))
+                .fetch_one(&fs_conn.pool)
+                .await
+                .unwrap()
+                .get::<i64, &str>("id")
+        };
+        let newest = newest_id().await;
+        let scratch = Directory::root().file("scratch");
+        scratch.mk("scratch data", &FileType::Text, &fs_conn).await.unwrap();
+        let scratch_id = newest_id().await;
+        scratch.del(&fs_conn).await.unwrap();
+        fs_conn.empty_trash().await.unwrap();
+        assert_eq!(newest_id().await, newest);
+        Directory::root().file("fresh").mk("fresh data", &FileType::Text, &fs_conn).await.unwrap();
+        assert!(newest_id().await > scratch_id);
+
+        let mut expected = data.clone();
+        expected[CHUNK_SIZE - 1..CHUNK_SIZE + 1].copy_from_slice(b"ab");
+        file.write_at(CHUNK_SIZE as u64 - 1, b"ab", true, &fs_conn).await.unwrap();
+        assert_eq!(file.read_bytes(&fs_conn).await.unwrap().0, expected);
+        expected.resize(expected.len() + 4, 0);
+        expected.extend(b"end");
+        file.write_at(data.len() as u64 + 4, b"end", true, &fs_conn).await.unwrap();
+        assert_eq!(file.read_bytes(&fs_conn).await.unwrap(), (expected.clone(), FileType::Blob.to_string()));
+        assert_eq!(file.read_version(2, &fs_conn).await.unwrap().0, data);
+        assert_eq!(file.history(&fs_conn).await.unwrap().len(), 3);
+
+        // Writes without a version patch the data in place, touching only the chunks they cover.
+        let chunks = chunk_count().await;
+        expected[1..3].copy_from_slice(b"cd");
+        file.write_at(1, b"cd", false, &fs_conn).await.unwrap();
+        expected[CHUNK_SIZE * 2..CHUNK_SIZE * 2 + 2].copy_from_slice(b"ef");
+        file.write_at(CHUNK_SIZE as u64 * 2, b"ef", false, &fs_conn).await.unwrap();
+        file.write_at(5, b"", true, &fs_conn).await.unwrap();
+        assert_eq!(file.read_bytes(&fs_conn).await.unwrap().0, expected);
+        assert_eq!(chunk_count().await, chunks);
+        assert_eq!(file.history(&fs_conn).await.unwrap().len(), 3);
+        let (previous, _) = file.read_version(3, &fs_conn).await.unwrap();
+        assert_eq!((&previous[1..3], &previous[CHUNK_SIZE - 1..CHUNK_SIZE + 1]), (&data[1..3], &b"ab"[..]));
+
+        // Text written in chunks is still searchable.
+        let mut text = Directory::root().file("notes");
+        text.mk("", &FileType::Text, &fs_conn).await.unwrap();
+        text.write_from("streamed budget".as_bytes(), FileType::Text, &fs_conn).await.unwrap();
+        assert_eq!(fs_conn.search("budget", 10).await.unwrap()[0].path, "/notes");
+        text.write_at(9, b"report", false, &fs_conn).await.unwrap();
+        assert_eq!(fs_conn.search("report", 10).await.unwrap()[0].path, "/notes");
+        assert_eq!(text.open(&fs_conn).await.unwrap(), FileType::Text);
+        assert!(matches!(Directory::root().file("missing").read_at(0, 1, &fs_conn).await, Err(FSError::NotFound(_))));
+
+        for name in ["large", "copy", "fresh", "notes"] {
+            Directory::root().file(name).del(&fs_conn).await.unwrap();
+        }
+        fs_conn.empty_trash().await.unwrap();
+        assert_eq!(chunk_count().await, 0);
+
+        remove_test_db("test_chunks.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        remove_test_db("test_compression.db").await;
+
+        let fs_conn = FSConnection::new("sqlite://test_compression.db", "servefs_", true).await.unwrap();
+        let stored = |file: &'static str| {
+            let fs_conn = &fs_conn;
+            async move {
+                let row = sqlx::query(&format!(r#"
+                        SELECT compression, (SELECT SUM(length(data)) FROM {chunks} WHERE content = c.id) AS bytes
+                        FROM {contents} c JOIN {files} f ON f.content = c.hash WHERE f.name=?
+                    "#, chunks = fs_conn.chunk_table, contents = fs_conn.content_table, files = fs_conn.file_table))
+                    .bind(file)
+                    .fetch_one(&fs_conn.pool)
+                    .await
+                    .unwrap();
+                (row.get::<Option<String>, &str>("compression"), row.get::<i64, &str>("bytes"))
+            }
+        };
+        let logs = Directory::new(PathBuf::from_str("/logs/").unwrap()).unwrap();
+        logs.mk(&fs_conn).await.unwrap();
+        fs_conn.set_compression("/logs/", Compression::Zstd).await.unwrap();
+        fs_conn.set_compression("/logs/raw", Compression::None).await.unwrap();
+        assert_eq!(fs_conn.compression_rules().await.unwrap(), vec![
+            CompressionRule { prefix: "/logs/".to_string(), compression: Compression::Zstd },
+            CompressionRule { prefix: "/logs/raw".to_string(), compression: Compression::None },
+        ]);
+
+        // Only files under a compressed prefix are stored compressed, and read back the same.
+        let text = format!("request served\n{}", "idle\n".repeat(CHUNK_SIZE * 3 / 10));
+        let mut log = logs.file("app.log");
+        log.mk(&text, &FileType::Text, &fs_conn).await.unwrap();
+        logs.file("raw.log").mk("request served", &FileType::Text, &fs_conn).await.unwrap();
+        let (compression, bytes) = stored("app.log").await;
+        assert_eq!(compression.as_deref(), Some("zstd"));
+        assert!((bytes as usize) < text.len() / 10);
+        assert_eq!(stored("raw.log").await, (None, 14));
+        assert_eq!(log.read(&fs_conn).await.unwrap().0, text);
+        assert_eq!(log.read_at(CHUNK_SIZE as u64 - 2, 5, &fs_conn).await.unwrap(), &text.as_bytes()[CHUNK_SIZE - 2..CHUNK_SIZE + 3]);
+
+        // Compressed text is still searchable, including after a rename.
+        assert_eq!(fs_conn.search("served", 10).await.unwrap().len(), 2);
+        log.rename("old.log", &fs_conn).await.unwrap();
+        log = logs.file("old.log");
+        assert!(fs_conn.search("served", 10).await.unwrap().iter().any(|result| result.path == "/logs/old.log"));
+        log.write_at(0, b"answer ", true, &fs_conn).await.unwrap();
+        assert_eq!(stored("old.log").await.0.as_deref(), Some("zstd"));
+        assert_eq!(&log.read_at(0, 30, &fs_conn).await.unwrap(), &format!("answer {}", &text[7..30]).as_bytes());
+        assert_eq!(fs_conn.search("answer", 10).await.unwrap()[0].path, "/logs/old.log");
+
+        // Recompressing applies the current rules to existing data and its history.
+        fs_conn.set_compression("/logs/", Compression::None).await.unwrap();
+        assert_eq!(logs.recompress(&fs_conn).await.unwrap(), 2);
+        assert_eq!(stored("old.log").await, (None, text.len() as i64));
+        assert_eq!(log.read_version(1, &fs_conn).await.unwrap().0, text.as_bytes());
+        assert_eq!(logs.recompress(&fs_conn).await.unwrap(), 0);
+
+        fs_conn.remove_compression("/logs/raw").await.unwrap();
+        assert!(matches!(fs_conn.remove_compression("/logs/raw").await, Err(FSError::NotFound(_))));
+        assert!(matches!("gzip".parse::<Compression>(), Err(FSError::InvalidData(_))));
+
+        remove_test_db("test_compression.db").await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_encryption() {
+        remove_test_db("test_encryption.db").await;
+
+        let mut fs_conn = FSConnection::new("sqlite://test_encryption.db", "servefs_", true).await.unwrap();
+        let key = EncryptionKey::from_bytes(b"  000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n").unwrap();
+        assert!(matches!(EncryptionKey::from_bytes(b"abcd"), Err(FSError::InvalidKey(_))));
+        let (pool, chunks) = (fs_conn.pool.clone(), fs_conn.chunk_table.clone());
+        let plaintext = |token: &'static str| {
+            let (pool, chunks) = (&pool, &chunks);
+            async move {
+                sqlx::query(&format!("SELECT COUNT(*) AS hits FROM {} WHERE instr(data, ?) > 0", chunks))
+                    .bind(token.as_bytes())
+                    .fetch_one(pool)
+                    .await
+                    .unwrap()
+                    .get::<i64, &str>("hits")
+            }
+        };
+        let mut secret = File::new(PathBuf::from_str("/secret").unwrap()).unwrap();
+        assert!(matches!(secret.mk_encrypted(b"token=abc123", &FileType::Text, &fs_conn).await, Err(FSError::KeyRequired)));
+
+        // Encrypted data never reaches the database in plaintext, not even in the search index.
+        fs_conn.set_key(Some(key.clone()));
+        secret.mk_encrypted(b"token=abc123", &FileType::Text, &fs_conn).await.unwrap();
+        assert!(secret.is_encrypted(&fs_conn).await.unwrap());
+        assert_eq!(plaintext("abc123").await, 0);
+        assert_eq!(secret.read(&fs_conn).await.unwrap().0, "token=abc123");
+        assert_eq!(secret.read_at(6, 3, &fs_conn).await.unwrap(), b"abc");
+        assert!(fs_conn.search("abc123", 10).await.unwrap().is_empty());
+
+        // A chunk moved to other encrypted content fails to open.
+        let (first, second) = (Directory::root().file("first"), Directory::root().file("second"));
+        first.mk_encrypted(b"one", &FileType::Text, &fs_conn).await.unwrap();
+        second.mk_encrypted(b"two", &FileType::Text, &fs_conn).await.unwrap();
+        sqlx::query(&format!(r#"
+                UPDATE {chunks} SET data=(SELECT data FROM {chunks} c JOIN {contents} ON {contents}.id = c.content
+                    JOIN {files} ON {files}.content = {contents}.hash WHERE {files}.name="second")
+                WHERE content=(SELECT {contents}.id FROM {contents} JOIN {files} ON {files}.content = {contents}.hash WHERE {files}.name="first")
+            "#, chunks = fs_conn.chunk_table, contents = fs_conn.content_table, files = fs_conn.file_table))
+            .execute(&fs_conn.pool)
+            .await
+            .unwrap();
+        assert!(matches!(first.read(&fs_conn).await, Err(FSError::InvalidKey(_))));
+        assert_eq!(second.read(&fs_conn).await.unwrap().0, "two");
+
+        // Writes keep the file encrypted.
+        secret.write_at(6, b"xyz", true, &fs_conn).await.unwrap();
+        secret.write("token=def456", FileType::Text, &fs_conn).await.unwrap();
+        assert!(secret.is_encrypted(&fs_conn).await.unwrap());
+        assert_eq!(plaintext("def456").await + plaintext("xyz123").await, 0);
+        assert_eq!(secret.read_version(2, &fs_conn).await.unwrap().0, b"token=xyz123");
+
+        // Without the right key the file can't be opened or read.
+        fs_conn.set_key(None);
+        assert!(matches!(secret.open(&fs_conn).await, Err(FSError::KeyRequired)));
+        assert_eq!(secret.stat(&fs_conn).await.unwrap(), (FileType::Text, secret.metadata(&fs_conn).await.unwrap()));
+        assert!(matches!(secret.read(&fs_conn).await, Err(FSError::KeyRequired)));
+        fs_conn.set_key(Some(EncryptionKey::new([7; 32])));
+        assert!(matches!(secret.read(&fs_conn).await, Err(FSError::InvalidKey(_))));
+
+        // Decrypting brings back the plaintext, including the history, and makes it searchable.
+        fs_conn.set_key(Some(key));
+        secret.set_encrypted(false, &fs_conn).await.unwrap();
+        assert!(!secret.is_encrypted(&fs_conn).await.unwrap());
+        assert_eq!(plaintext("xyz123").await, 1);
+        assert_eq!(fs_conn.search("def456", 10).await.unwrap()[0].path, "/secret");
+        fs_conn.set_key(None);
+        assert_eq!(secret.read_version(1, &fs_conn).await.unwrap().0, b"token=abc123");
+        assert_eq!(secret.read(&fs_conn).await.unwrap().0, "token=def456");
+
+        remove_test_db("test_encryption.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        remove_test_db("test_import.db").await;
+
+        let host = std::env::temp_dir().join("servefs_test_import");
+        let _ = std::fs::remove_dir_all(&host);
+        std::fs::create_dir_all(host.join("notes/drafts")).unwrap();
+        std::fs::create_dir_all(host.join("target")).unwrap();
+        std::fs::write(host.join("readme.md"), "hello").unwrap();
+        std::fs::write(host.join("logo.png"), [0x89, 0x50, 0xff, 0x00]).unwrap();
+        std::fs::write(host.join("notes/todo.md"), "buy milk").unwrap();
+        std::fs::write(host.join("notes/drafts/idea.txt"), "later").unwrap();
+        std::fs::write(host.join("target/out.md"), "built").unwrap();
+        // A character split across chunks is still read as text.
+        let large = format!("{}é{}", "a".repeat(CHUNK_SIZE - 1), "b".repeat(10));
+        std::fs::write(host.join("target/large.txt"), &large).unwrap();
+        let host = host.canonicalize().unwrap();
+
+        let fs_conn = FSConnection::new("sqlite://test_import.db", "servefs_", true).await.unwrap();
+        let site = Directory::new(PathBuf::from_str("/site/").unwrap()).unwrap();
+        let import = Import::new().include("*.md").exclude("target");
+
+        // A dry run reports what would be made and leaves the tree alone.
+        let report = site.import(&host, &import.clone().dry_run(true), &fs_conn).await.unwrap();
+        assert_eq!(report.dirs, vec!["/site/", "/site/notes/"]);
+        assert_eq!(report.files.iter().map(|file| (file.path.as_str(), file.size)).collect::<Vec<_>>(), vec![
+            ("/site/notes/todo.md", 8),
+            ("/site/readme.md", 5),
+        ]);
+        assert_eq!(report.skipped, vec![host.join("logo.png"), host.join("notes/drafts/idea.txt"), host.join("target")]);
+        assert!(!site.exists(&fs_conn).await.unwrap());
+
+        assert_eq!(site.import(&host, &import, &fs_conn).await.unwrap(), report);
+        assert_eq!(site.dir("notes").unwrap().file("todo.md").read(&fs_conn).await.unwrap(), ("buy milk".to_string(), "text".to_string()));
+        assert!(!site.dir("notes").unwrap().dir("drafts").unwrap().exists(&fs_conn).await.unwrap());
+
+        // Existing directories are merged into, but an existing file fails the whole import.
+        assert!(matches!(site.import(&host, &Import::new(), &fs_conn).await, Err(FSError::AlreadyExists(_))));
+        assert!(matches!(site.import(&host, &Import::new().dry_run(true), &fs_conn).await, Err(FSError::AlreadyExists(_))));
+        let orphan = Directory::new(PathBuf::from_str("/missing/site/").unwrap()).unwrap();
+        assert!(matches!(orphan.import(&host, &Import::new().dry_run(true), &fs_conn).await, Err(FSError::NotFound(_))));
+        assert!(!site.file("logo.png").exists(&fs_conn).await.unwrap());
+        let report = site.import(&host, &Import::new().exclude("*.md"), &fs_conn).await.unwrap();
+        assert_eq!(report.dirs, vec!["/site/notes/drafts/", "/site/target/"]);
+        assert_eq!(site.file("logo.png").read_bytes(&fs_conn).await.unwrap(), (vec![0x89, 0x50, 0xff, 0x00], "blob".to_string()));
+        assert_eq!(site.dir("target").unwrap().file("large.txt").read(&fs_conn).await.unwrap(), (large, "text".to_string()));
+
+        // Linked files point at the host files instead of holding a copy.
+        let linked = Directory::new(PathBuf::from_str("/linked/").unwrap()).unwrap();
+        let report = linked.import(&host, &Import::new().mode(ImportMode::Link).include("readme.md"), &fs_conn).await.unwrap();
+        assert_eq!(report.files[0].ftype, FileType::File);
+        assert_eq!(linked.file("readme.md").read(&fs_conn).await.unwrap().0, host.join("readme.md").display().to_string());
+
+        assert!(matches!(linked.import(&host, &Import::new().include("[md"), &fs_conn).await, Err(FSError::InvalidGlob(_))));
+
+        std::fs::remove_dir_all(&host).unwrap();
+        remove_test_db("test_import.db").await;
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        remove_test_db("test_export.db").await;
+
+        let host = std::env::temp_dir().join("servefs_test_export");
+        let _ = std::fs::remove_dir_all(&host);
+        std::fs::create_dir_all(&host).unwrap();
+        std::fs::write(host.join("source.txt"), "from the host").unwrap();
+
+        let fs_conn = FSConnection::new("sqlite://test_export.db", "servefs_", true).await.unwrap();
+        let site = Directory::new(PathBuf::from_str("/site/").unwrap()).unwrap();
+        site.mk(&fs_conn).await.unwrap();
+        site.dir("assets").unwrap().mk(&fs_conn).await.unwrap();
+        site.file("index.html").mk("<h1>hi</h1>", &FileType::Text, &fs_conn).await.unwrap();
+        site.file("index.html").set_mode(0o600, &fs_conn).await.unwrap();
+        site.file("latest").mk_link(Path::new("index.html"), &fs_conn).await.unwrap();
+        // Absolute targets are rewritten to stay inside the export.
+        site.dir("assets").unwrap().file("home").mk_link(Path::new("/site/index.html"), &fs_conn).await.unwrap();
+        site.file("built").mk("echo built", &FileType::Exec, &fs_conn).await.unwrap();
+        let assets = site.dir("assets").unwrap();
+        assets.file("logo.png").mk_bytes(&[0x89, 0x50, 0xff], &FileType::Blob, &fs_conn).await.unwrap();
+        assets.file("copied.txt").mk(&host.join("source.txt").display().to_string(), &FileType::File, &fs_conn).await.unwrap();
+
+        // By default exec files are written as scripts and metadata is left to the host.
+        let out = host.join("default");
+        let report = site.export(&out, &Export::new(), &fs_conn).await.unwrap();
+        assert_eq!(report.dirs, vec![out.join("assets")]);
+        assert_eq!(report.files.iter().map(|file| (file.path.as_str(), file.size)).collect::<Vec<_>>(), vec![
+            ("/site/assets/copied.txt", 13),
+            ("/site/assets/home", 13),
+            ("/site/assets/logo.png", 3),
+            ("/site/built", 31),
+            ("/site/index.html", 11),
+            ("/site/latest", 10),
+        ]);
+        assert_eq!(std::fs::read_to_string(out.join("index.html")).unwrap(), "<h1>hi</h1>");
+        assert_eq!(std::fs::read(out.join("assets/logo.png")).unwrap(), vec![0x89, 0x50, 0xff]);
+        assert_eq!(std::fs::read_to_string(out.join("assets/copied.txt")).unwrap(), "from the host");
+        assert_eq!(std::fs::read_link(out.join("latest")).unwrap(), PathBuf::from("index.html"));
+        assert_eq!(std::fs::read_link(out.join("assets/home")).unwrap(), PathBuf::from("../index.html"));
+        assert_eq!(std::fs::read_to_string(out.join("assets/home")).unwrap(), "<h1>hi</h1>");
+        assert_eq!(std::fs::read_to_string(out.join("built")).unwrap(), "#!/usr/bin/env bash\necho built\n");
+        assert_eq!(std::fs::metadata(out.join("built")).unwrap().permissions().mode() & 0o7777, 0o755);
+
+        // Exporting again overwrites, and can run exec files and carry metadata over.
+        let export = Export::new().exec(ExportExec::Run).preserve_metadata(true);
+        site.export(&out, &export, &fs_conn).await.unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("built")).unwrap(), "built\n");
+        let index = std::fs::metadata(out.join("index.html")).unwrap();
+        assert_eq!(index.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(index.modified().unwrap(), site.file("index.html").metadata(&fs_conn).await.unwrap().modified);
+        assert_eq!(std::fs::metadata(out.join("assets")).unwrap().modified().unwrap(), assets.metadata(&fs_conn).await.unwrap().modified);
+
+        std::fs::remove_dir_all(&host).unwrap();
+        remove_test_db("test_export.db").await;
+    }
+}
@@ -0,0 +1,692 @@
+use std::collections::HashMap;
+
+use sqlx::{QueryBuilder, SqliteConnection, Connection, Row};
+
+use crate::{FSConnection, FSError, content_hash};
+
+/// The newest schema version this build knows how to read and write.
+pub const SCHEMA_VERSION: i64 = 17;
+
+async fn create_schema_table(conn: &mut SqliteConnection, schema_table: &str) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE IF NOT EXISTS {} (version INTEGER NOT NULL);
+        "#, schema_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn get_version(conn: &mut SqliteConnection, schema_table: &str) -> Result<i64, sqlx::Error> {
+    Ok(QueryBuilder::new(format!(r#"
+            SELECT MAX(version) AS version FROM {}
+        "#, schema_table))
+        .build()
+        .fetch_one(conn)
+        .await?
+        .try_get::<Option<i64>, &str>("version")?
+        .unwrap_or(0))
+}
+
+async fn set_version(conn: &mut SqliteConnection, schema_table: &str, version: i64) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!(r#"
+            DELETE FROM {};
+            INSERT INTO {}(version) VALUES(
+        "#, schema_table, schema_table))
+        .push_bind(version)
+        .push(");")
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn create_file_type_table(conn: &mut SqliteConnection, file_type_table: &str) -> Result<(), sqlx::Error>{
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {} (type TEXT PRIMARY KEY NOT NULL);
+            INSERT INTO {} VALUES("file");
+            INSERT INTO {} VALUES("text");
+            INSERT INTO {} VALUES("exec");
+        "#, file_type_table, file_type_table, file_type_table, file_type_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn create_dir_table(conn: &mut SqliteConnection, dir_table: &str) -> Result<(), sqlx::Error>{
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {} (id INTEGER PRIMARY KEY NOT NULL CHECK(id > 0) UNIQUE,
+                directory TEXT NOT NULL CHECK(directory != "" AND (directory = "/" OR directory LIKE "/%/")),
+                CONSTRAINT unq UNIQUE(directory));
+            INSERT INTO {}(directory) VALUES("/");
+        "#, dir_table, dir_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn create_file_table(conn: &mut SqliteConnection, dir_table: &str, file_table: &str, file_type_table: &str) -> Result<(), sqlx::Error>{
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {} (id INTEGER PRIMARY KEY check(id > 0), name TEXT NOT NULL, type TEXT NOT NULL, data TEXT NOT NULL, directory INTEGER NOT NULL,
+                FOREIGN KEY(directory) REFERENCES {}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                FOREIGN KEY(type) REFERENCES {}(type) ON DELETE RESTRICT ON UPDATE RESTRICT,
+                CONSTRAINT unq UNIQUE(name, directory));
+        "#, file_table, dir_table, file_type_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 1: the original three tables. Databases created before versioning was introduced
+/// already have some or all of them, so only the missing ones are created.
+async fn v1(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let found_tables = FSConnection::find_tables(conn, &fs_conn.dir_table, &fs_conn.file_table, &fs_conn.file_type_table).await?;
+
+    if !found_tables.contains(&fs_conn.file_type_table) {
+        create_file_type_table(conn, &fs_conn.file_type_table).await?;
+    }
+    if !found_tables.contains(&fs_conn.dir_table) {
+        create_dir_table(conn, &fs_conn.dir_table).await?;
+    }
+    if !found_tables.contains(&fs_conn.file_table) {
+        create_file_table(conn, &fs_conn.dir_table, &fs_conn.file_table, &fs_conn.file_type_table).await?;
+    }
+    Ok(())
+}
+
+/// Version 2: binary-safe file data and the blob file type.
+async fn v2(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!(r#"
+            INSERT OR IGNORE INTO {} VALUES("blob");
+            CREATE TABLE {}_new (id INTEGER PRIMARY KEY check(id > 0), name TEXT NOT NULL, type TEXT NOT NULL, data BLOB NOT NULL, directory INTEGER NOT NULL,
+                FOREIGN KEY(directory) REFERENCES {}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                FOREIGN KEY(type) REFERENCES {}(type) ON DELETE RESTRICT ON UPDATE RESTRICT,
+                CONSTRAINT unq UNIQUE(name, directory));
+            INSERT INTO {}_new(id,name,type,data,directory) SELECT id,name,type,CAST(data AS BLOB),directory FROM {};
+            DROP TABLE {};
+            ALTER TABLE {}_new RENAME TO {};
+        "#, fs_conn.file_type_table,
+            fs_conn.file_table, fs_conn.dir_table, fs_conn.file_type_table,
+            fs_conn.file_table, fs_conn.file_table,
+            fs_conn.file_table,
+            fs_conn.file_table, fs_conn.file_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 3: directories become a tree of `parent` ids and names instead of full path strings.
+/// Ids are kept so file rows stay attached; ancestors missing from the old table are created.
+async fn v3(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let rows = QueryBuilder::new(format!(r#"
+            SELECT id, directory FROM {}
+        "#, fs_conn.dir_table))
+        .build()
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let mut ids: HashMap<String, i64> = rows.iter()
+        .map(|row| (row.get("directory"), row.get("id")))
+        .collect();
+    let mut next_id = ids.values().max().copied().unwrap_or(0) + 1;
+
+    let mut paths: Vec<String> = ids.keys().cloned().collect();
+    paths.push("/".to_string());
+    for path in paths {
+        let mut ancestor = path.as_str();
+        loop {
+            if !ids.contains_key(ancestor) {
+                ids.insert(ancestor.to_string(), next_id);
+                next_id += 1;
+            }
+            match parent_path(ancestor) {
+                Some((parent, _)) => ancestor = parent,
+                None => break,
+            }
+        }
+    }
+
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {}_new (id INTEGER PRIMARY KEY NOT NULL CHECK(id > 0),
+                parent INTEGER CHECK(parent != id),
+                name TEXT NOT NULL CHECK(instr(name, "/") = 0 AND (name != "" OR parent IS NULL)),
+                FOREIGN KEY(parent) REFERENCES {}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                CONSTRAINT unq UNIQUE(parent, name));
+        "#, fs_conn.dir_table, fs_conn.dir_table))
+        .build()
+        .execute(&mut *conn)
+        .await?;
+
+    for (path, id) in ids.iter() {
+        let (parent, name) = match parent_path(path) {
+            Some((parent, name)) => (Some(ids[parent]), name),
+            None => (None, ""),
+        };
+        QueryBuilder::new(format!("INSERT INTO {}_new(id, parent, name) VALUES(", fs_conn.dir_table))
+            .push_bind(*id)
+            .push(",")
+            .push_bind(parent)
+            .push(",")
+            .push_bind(name)
+            .push(")")
+            .build()
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    QueryBuilder::new(format!(r#"
+            DROP TABLE {};
+            ALTER TABLE {}_new RENAME TO {};
+            CREATE UNIQUE INDEX {}_roots ON {}(name) WHERE parent IS NULL;
+        "#, fs_conn.dir_table,
+            fs_conn.dir_table, fs_conn.dir_table,
+            fs_conn.dir_table, fs_conn.dir_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 4: timestamps (unix seconds), permission bits and ownership on files and directories,
+/// plus a stored file size. Existing rows are stamped with the time of the migration.
+async fn v4(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    for (table, mode) in [(&fs_conn.file_table, 0o644), (&fs_conn.dir_table, 0o755)] {
+        QueryBuilder::new(format!(r#"
+                ALTER TABLE {table} ADD COLUMN created INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE {table} ADD COLUMN modified INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE {table} ADD COLUMN accessed INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE {table} ADD COLUMN mode INTEGER NOT NULL DEFAULT {mode};
+                ALTER TABLE {table} ADD COLUMN uid INTEGER;
+                ALTER TABLE {table} ADD COLUMN gid INTEGER;
+                UPDATE {table} SET created=
+            "#))
+            .push_bind(now)
+            .push(", modified=")
+            .push_bind(now)
+            .push(", accessed=")
+            .push_bind(now)
+            .build()
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    QueryBuilder::new(format!(r#"
+            ALTER TABLE {table} ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
+            UPDATE {table} SET size=length(data);
+        "#, table = fs_conn.file_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 5: the link file type.
+async fn v5(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!(r#"
+            INSERT OR IGNORE INTO {} VALUES("link");
+        "#, fs_conn.file_type_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 6: custom file types carry the command that renders them.
+async fn v6(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!(r#"
+            ALTER TABLE {} ADD COLUMN handler TEXT;
+        "#, fs_conn.file_type_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 7: file data moves into a content table keyed by its SHA-256 and shared by every file
+/// with identical data. Triggers on the file table keep each entry's reference count and remove it
+/// once nothing refers to it, including when files go with a deleted directory.
+/// Later, data patched by [`crate::File::write_at`] is keyed by the patch instead and isn't shared.
+async fn v7(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let (files, contents) = (&fs_conn.file_table, &fs_conn.content_table);
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {contents} (hash TEXT PRIMARY KEY NOT NULL, data BLOB NOT NULL, refs INTEGER NOT NULL DEFAULT 0);
+            CREATE TABLE {files}_new (id INTEGER PRIMARY KEY check(id > 0), name TEXT NOT NULL, type TEXT NOT NULL, content TEXT NOT NULL, directory INTEGER NOT NULL,
+                created INTEGER NOT NULL DEFAULT 0, modified INTEGER NOT NULL DEFAULT 0, accessed INTEGER NOT NULL DEFAULT 0,
+                mode INTEGER NOT NULL DEFAULT 420, uid INTEGER, gid INTEGER, size INTEGER NOT NULL DEFAULT 0,
+                FOREIGN KEY(directory) REFERENCES {dirs}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                FOREIGN KEY(type) REFERENCES {types}(type) ON DELETE RESTRICT ON UPDATE RESTRICT,
+                FOREIGN KEY(content) REFERENCES {contents}(hash) ON DELETE RESTRICT ON UPDATE RESTRICT,
+                CONSTRAINT unq UNIQUE(name, directory));
+        "#, dirs = fs_conn.dir_table, types = fs_conn.file_type_table))
+        .build()
+        .execute(&mut *conn)
+        .await?;
+
+    let rows = QueryBuilder::new(format!("SELECT id, data FROM {files}"))
+        .build()
+        .fetch_all(&mut *conn)
+        .await?;
+    for row in rows {
+        let data: Vec<u8> = row.get("data");
+        let hash = content_hash(&data);
+        QueryBuilder::new(format!("INSERT INTO {contents}(hash, data, refs) VALUES("))
+            .push_bind(&hash)
+            .push(",")
+            .push_bind(&data)
+            .push(", 1) ON CONFLICT(hash) DO UPDATE SET refs=refs+1")
+            .build()
+            .execute(&mut *conn)
+            .await?;
+        QueryBuilder::new(format!(r#"
+                INSERT INTO {files}_new(id,name,type,content,directory,created,modified,accessed,mode,uid,gid,size)
+                SELECT id,name,type,
+            "#))
+            .push_bind(&hash)
+            .push(format!(",directory,created,modified,accessed,mode,uid,gid,size FROM {files} WHERE id="))
+            .push_bind(row.get::<i64, &str>("id"))
+            .build()
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    QueryBuilder::new(format!(r#"
+            DROP TABLE {files};
+            ALTER TABLE {files}_new RENAME TO {files};
+            CREATE TRIGGER {files}_content_insert AFTER INSERT ON {files} BEGIN
+                UPDATE {contents} SET refs=refs+1 WHERE hash=NEW.content;
+            END;
+            CREATE TRIGGER {files}_content_update AFTER UPDATE OF content ON {files} WHEN OLD.content != NEW.content BEGIN
+                UPDATE {contents} SET refs=refs+1 WHERE hash=NEW.content;
+                UPDATE {contents} SET refs=refs-1 WHERE hash=OLD.content;
+                DELETE FROM {contents} WHERE hash=OLD.content AND refs=0;
+            END;
+            CREATE TRIGGER {files}_content_delete AFTER DELETE ON {files} BEGIN
+                UPDATE {contents} SET refs=refs-1 WHERE hash=OLD.content;
+                DELETE FROM {contents} WHERE hash=OLD.content AND refs=0;
+            END;
+        "#))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 8: earlier versions of each file, written when the file is overwritten or restored.
+/// Like files, history rows hold references to the content they point at.
+async fn v8(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let (history, contents) = (&fs_conn.history_table, &fs_conn.content_table);
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {history} (id INTEGER PRIMARY KEY check(id > 0), file INTEGER NOT NULL, version INTEGER NOT NULL CHECK(version > 0),
+                type TEXT NOT NULL, content TEXT NOT NULL, size INTEGER NOT NULL, modified INTEGER NOT NULL,
+                FOREIGN KEY(file) REFERENCES {files}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                FOREIGN KEY(type) REFERENCES {types}(type) ON DELETE RESTRICT ON UPDATE RESTRICT,
+                FOREIGN KEY(content) REFERENCES {contents}(hash) ON DELETE RESTRICT ON UPDATE RESTRICT,
+                CONSTRAINT unq UNIQUE(file, version));
+            CREATE TRIGGER {history}_content_insert AFTER INSERT ON {history} BEGIN
+                UPDATE {contents} SET refs=refs+1 WHERE hash=NEW.content;
+            END;
+            CREATE TRIGGER {history}_content_delete AFTER DELETE ON {history} BEGIN
+                UPDATE {contents} SET refs=refs-1 WHERE hash=OLD.content;
+                DELETE FROM {contents} WHERE hash=OLD.content AND refs=0;
+            END;
+        "#, files = fs_conn.file_table, types = fs_conn.file_type_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 9: named snapshots of the whole tree. Each one keeps a copy of the dir, file and file
+/// type rows; snapshot files hold references to their content like live files do.
+async fn v9(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let (snapshots, snapshot_files, contents) = (&fs_conn.snapshot_table, &fs_conn.snapshot_file_table, &fs_conn.content_table);
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {snapshots} (id INTEGER PRIMARY KEY check(id > 0), name TEXT NOT NULL UNIQUE, created INTEGER NOT NULL);
+            CREATE TABLE {snapshot_dirs} (snapshot INTEGER NOT NULL, id INTEGER NOT NULL, parent INTEGER, name TEXT NOT NULL,
+                created INTEGER NOT NULL, modified INTEGER NOT NULL, accessed INTEGER NOT NULL, mode INTEGER NOT NULL, uid INTEGER, gid INTEGER,
+                FOREIGN KEY(snapshot) REFERENCES {snapshots}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                PRIMARY KEY(snapshot, id));
+            CREATE TABLE {snapshot_files} (snapshot INTEGER NOT NULL, id INTEGER NOT NULL, name TEXT NOT NULL, type TEXT NOT NULL, content TEXT NOT NULL, directory INTEGER NOT NULL,
+                created INTEGER NOT NULL, modified INTEGER NOT NULL, accessed INTEGER NOT NULL, mode INTEGER NOT NULL, uid INTEGER, gid INTEGER, size INTEGER NOT NULL,
+                FOREIGN KEY(snapshot) REFERENCES {snapshots}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                FOREIGN KEY(content) REFERENCES {contents}(hash) ON DELETE RESTRICT ON UPDATE RESTRICT,
+                PRIMARY KEY(snapshot, id));
+            CREATE TABLE {snapshot_file_types} (snapshot INTEGER NOT NULL, type TEXT NOT NULL, handler TEXT,
+                FOREIGN KEY(snapshot) REFERENCES {snapshots}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                PRIMARY KEY(snapshot, type));
+            CREATE TRIGGER {snapshot_files}_content_insert AFTER INSERT ON {snapshot_files} BEGIN
+                UPDATE {contents} SET refs=refs+1 WHERE hash=NEW.content;
+            END;
+            CREATE TRIGGER {snapshot_files}_content_delete AFTER DELETE ON {snapshot_files} BEGIN
+                UPDATE {contents} SET refs=refs-1 WHERE hash=OLD.content;
+                DELETE FROM {contents} WHERE hash=OLD.content AND refs=0;
+            END;
+        "#, snapshot_dirs = fs_conn.snapshot_dir_table, snapshot_file_types = fs_conn.snapshot_file_type_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 10: key/value attributes on files and directories, and their snapshot copies.
+async fn v10(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    for (attrs, entries, snapshot_attrs) in [
+        (&fs_conn.file_attr_table, &fs_conn.file_table, &fs_conn.snapshot_file_attr_table),
+        (&fs_conn.dir_attr_table, &fs_conn.dir_table, &fs_conn.snapshot_dir_attr_table),
+    ] {
+        QueryBuilder::new(format!(r#"
+                CREATE TABLE {attrs} (entry INTEGER NOT NULL, key TEXT NOT NULL CHECK(key != ""), value BLOB NOT NULL,
+                    FOREIGN KEY(entry) REFERENCES {entries}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                    PRIMARY KEY(entry, key));
+                CREATE TABLE {snapshot_attrs} (snapshot INTEGER NOT NULL, entry INTEGER NOT NULL, key TEXT NOT NULL, value BLOB NOT NULL,
+                    FOREIGN KEY(snapshot) REFERENCES {snapshots}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                    PRIMARY KEY(snapshot, entry, key));
+            "#, snapshots = fs_conn.snapshot_table))
+            .build()
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Version 11: a full-text index over the names and data of text files, keyed by file id and kept
+/// up to date by triggers on the file table.
+async fn v11(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let (files, contents, search) = (&fs_conn.file_table, &fs_conn.content_table, &fs_conn.search_table);
+    QueryBuilder::new(format!(r#"
+            CREATE VIRTUAL TABLE {search} USING fts5(name, body);
+            INSERT INTO {search}(rowid, name, body)
+                SELECT id, name, CAST(data AS TEXT) FROM {files} JOIN {contents} ON hash=content WHERE type="text";
+            CREATE TRIGGER {files}_search_insert AFTER INSERT ON {files} WHEN NEW.type="text" BEGIN
+                INSERT INTO {search}(rowid, name, body) SELECT NEW.id, NEW.name, CAST(data AS TEXT) FROM {contents} WHERE hash=NEW.content;
+            END;
+            CREATE TRIGGER {files}_search_update AFTER UPDATE OF name, type, content ON {files} BEGIN
+                DELETE FROM {search} WHERE rowid=OLD.id;
+                INSERT INTO {search}(rowid, name, body) SELECT NEW.id, NEW.name, CAST(data AS TEXT) FROM {contents} WHERE hash=NEW.content AND NEW.type="text";
+            END;
+            CREATE TRIGGER {files}_search_delete AFTER DELETE ON {files} BEGIN
+                DELETE FROM {search} WHERE rowid=OLD.id;
+            END;
+        "#))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 12: byte and entry quotas on directory subtrees, and their snapshot copies.
+async fn v12(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {quotas} (directory INTEGER PRIMARY KEY NOT NULL,
+                max_bytes INTEGER CHECK(max_bytes >= 0), max_entries INTEGER CHECK(max_entries >= 0),
+                FOREIGN KEY(directory) REFERENCES {dirs}(id) ON DELETE CASCADE ON UPDATE CASCADE);
+            CREATE TABLE {snapshot_quotas} (snapshot INTEGER NOT NULL, directory INTEGER NOT NULL, max_bytes INTEGER, max_entries INTEGER,
+                FOREIGN KEY(snapshot) REFERENCES {snapshots}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                PRIMARY KEY(snapshot, directory));
+        "#, quotas = fs_conn.quota_table, dirs = fs_conn.dir_table,
+            snapshot_quotas = fs_conn.snapshot_quota_table, snapshots = fs_conn.snapshot_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 13: the change log, one row per create, update, delete or move in the order they were
+/// committed. Moves keep the old path alongside the new one.
+async fn v13(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {changes} (seq INTEGER PRIMARY KEY AUTOINCREMENT, time INTEGER NOT NULL,
+                kind TEXT NOT NULL CHECK(kind IN ("create", "update", "delete", "move")),
+                path TEXT NOT NULL, old_path TEXT);
+        "#, changes = fs_conn.change_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 14: a key/value settings table, which holds the trash retention period, and the trash.
+/// Each trash entry records the path and time of a deletion and owns a parentless container
+/// directory that the deleted entry is moved into; snapshots keep their own copy of the trash.
+async fn v14(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {settings} (key TEXT PRIMARY KEY NOT NULL, value);
+            CREATE TABLE {trash} (id INTEGER PRIMARY KEY CHECK(id > 0), container INTEGER NOT NULL UNIQUE,
+                path TEXT NOT NULL, deleted INTEGER NOT NULL,
+                FOREIGN KEY(container) REFERENCES {dirs}(id) ON DELETE CASCADE ON UPDATE CASCADE);
+            CREATE TABLE {snapshot_trash} (snapshot INTEGER NOT NULL, id INTEGER NOT NULL, container INTEGER NOT NULL,
+                path TEXT NOT NULL, deleted INTEGER NOT NULL,
+                FOREIGN KEY(snapshot) REFERENCES {snapshots}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                PRIMARY KEY(snapshot, id));
+        "#, settings = fs_conn.setting_table, trash = fs_conn.trash_table, dirs = fs_conn.dir_table,
+            snapshot_trash = fs_conn.snapshot_trash_table, snapshots = fs_conn.snapshot_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 15: content data moves out of the content table into 1 MiB chunks keyed by the content's
+/// new integer id, which is never reused, and a sequence number, so files can be read and written
+/// a piece at a time. Each content row also gets its size. The search triggers rebuild text from the chunks.
+async fn v15(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let (files, contents, chunks, search) = (&fs_conn.file_table, &fs_conn.content_table, &fs_conn.chunk_table, &fs_conn.search_table);
+    // Text of a content row, put back together from its chunks.
+    let body = format!(r#"
+        (SELECT COALESCE(CAST(group_concat(data, '') AS TEXT), '') FROM (
+            SELECT {chunks}.data AS data FROM {chunks} JOIN {contents} ON {contents}.id = {chunks}.content
+            WHERE hash=NEW.content ORDER BY seq))
+    "#);
+    // The content table is rebuilt around an id that is never reused. Triggers on other tables refer
+    // to it by name, which the legacy rename leaves alone instead of failing on while it is missing.
+    QueryBuilder::new(format!(r#"
+            CREATE TABLE {contents}_new (id INTEGER PRIMARY KEY AUTOINCREMENT, hash TEXT NOT NULL UNIQUE,
+                refs INTEGER NOT NULL DEFAULT 0, size INTEGER NOT NULL DEFAULT 0);
+            INSERT INTO {contents}_new(id, hash, refs, size) SELECT rowid, hash, refs, length(data) FROM {contents};
+            CREATE TABLE {chunks} (content INTEGER NOT NULL, seq INTEGER NOT NULL CHECK(seq >= 0), data BLOB NOT NULL,
+                FOREIGN KEY(content) REFERENCES {contents}(id) ON DELETE CASCADE ON UPDATE CASCADE,
+                PRIMARY KEY(content, seq));
+            WITH RECURSIVE split(id, seq) AS (
+                SELECT rowid, 0 FROM {contents} WHERE length(data) > 0
+                UNION ALL
+                SELECT s.id, s.seq + 1 FROM split s JOIN {contents} c ON c.rowid = s.id WHERE (s.seq + 1) * {chunk} < length(c.data)
+            )
+            INSERT INTO {chunks}(content, seq, data)
+                SELECT s.id, s.seq, substr(c.data, s.seq * {chunk} + 1, {chunk}) FROM split s JOIN {contents} c ON c.rowid = s.id;
+            DROP TABLE {contents};
+            PRAGMA legacy_alter_table = ON;
+            ALTER TABLE {contents}_new RENAME TO {contents};
+            PRAGMA legacy_alter_table = OFF;
+
+            DROP TRIGGER {files}_search_insert;
+            DROP TRIGGER {files}_search_update;
+            CREATE TRIGGER {files}_search_insert AFTER INSERT ON {files} WHEN NEW.type="text" BEGIN
+                INSERT INTO {search}(rowid, name, body) VALUES(NEW.id, NEW.name, {body});
+            END;
+            CREATE TRIGGER {files}_search_update AFTER UPDATE OF name, type, content ON {files} BEGIN
+                DELETE FROM {search} WHERE rowid=OLD.id;
+                INSERT INTO {search}(rowid, name, body) SELECT NEW.id, NEW.name, {body} WHERE NEW.type="text";
+            END;
+        "#, chunk = crate::CHUNK_SIZE))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 16: content rows record the compression their chunks are stored with, `NULL` for none,
+/// and a rules table maps path prefixes to an algorithm. The longest prefix a file's path starts
+/// with decides how its new data is stored. Existing content is left uncompressed.
+async fn v16(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let (files, contents, chunks, search) = (&fs_conn.file_table, &fs_conn.content_table, &fs_conn.chunk_table, &fs_conn.search_table);
+    // Compressed text can't be read here, so it is left empty for the library to index. Renames
+    // keep the body that is already indexed for the same reason.
+    let body = format!(r#"
+        (SELECT COALESCE(CAST(group_concat(data, '') AS TEXT), '') FROM (
+            SELECT {chunks}.data AS data FROM {chunks} JOIN {contents} ON {contents}.id = {chunks}.content
+            WHERE hash=NEW.content AND compression IS NULL ORDER BY seq))
+    "#);
+    QueryBuilder::new(format!(r#"
+            ALTER TABLE {contents} ADD COLUMN compression TEXT CHECK(compression IN ("zstd"));
+            CREATE TABLE {compression} (prefix TEXT PRIMARY KEY NOT NULL,
+                algorithm TEXT NOT NULL CHECK(algorithm IN ("none", "zstd")));
+
+            DROP TRIGGER {files}_search_insert;
+            DROP TRIGGER {files}_search_update;
+            CREATE TRIGGER {files}_search_insert AFTER INSERT ON {files} WHEN NEW.type="text" BEGIN
+                INSERT INTO {search}(rowid, name, body) VALUES(NEW.id, NEW.name, {body});
+            END;
+            CREATE TRIGGER {files}_search_update AFTER UPDATE OF type, content ON {files} BEGIN
+                DELETE FROM {search} WHERE rowid=OLD.id;
+                INSERT INTO {search}(rowid, name, body) SELECT NEW.id, NEW.name, {body} WHERE NEW.type="text";
+            END;
+            CREATE TRIGGER {files}_search_rename AFTER UPDATE OF name ON {files}
+                WHEN NEW.type IS OLD.type AND NEW.content IS OLD.content BEGIN
+                UPDATE {search} SET name=NEW.name WHERE rowid=NEW.id;
+            END;
+        "#, compression = fs_conn.compression_table))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Version 17: content rows record whether their chunks are encrypted. Encrypted content is keyed by
+/// an HMAC under a subkey of the encryption key, so equal plaintext can't be spotted across
+/// databases, and it is kept out of the search index.
+///
+/// Encrypting a file leaves some plaintext behind. Content the file shares with other files or with
+/// snapshots stays as it is, and so do pages freed before the file was encrypted. The old chunks
+/// themselves are zeroed as they are dropped, but their copies in the write-ahead log last until the
+/// next checkpoint.
+async fn v17(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), sqlx::Error> {
+    let (files, contents, chunks, history, search) = (&fs_conn.file_table, &fs_conn.content_table, &fs_conn.chunk_table, &fs_conn.history_table, &fs_conn.search_table);
+    // Encrypted text is never indexed, so search can't leak it.
+    let body = format!(r#"
+        (SELECT COALESCE(CAST(group_concat(data, '') AS TEXT), '') FROM (
+            SELECT {chunks}.data AS data FROM {chunks} JOIN {contents} ON {contents}.id = {chunks}.content
+            WHERE hash=NEW.content AND compression IS NULL AND NOT encrypted ORDER BY seq))
+    "#);
+    // Encrypting a file rewrites the content its versions point at, so history needs the same
+    // reference counting on update as the file table.
+    QueryBuilder::new(format!(r#"
+            ALTER TABLE {contents} ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0 CHECK(encrypted IN (0, 1));
+            CREATE TRIGGER {history}_content_update AFTER UPDATE OF content ON {history} WHEN OLD.content != NEW.content BEGIN
+                UPDATE {contents} SET refs=refs+1 WHERE hash=NEW.content;
+                UPDATE {contents} SET refs=refs-1 WHERE hash=OLD.content;
+                DELETE FROM {contents} WHERE hash=OLD.content AND refs=0;
+            END;
+
+            DROP TRIGGER {files}_search_insert;
+            DROP TRIGGER {files}_search_update;
+            CREATE TRIGGER {files}_search_insert AFTER INSERT ON {files} WHEN NEW.type="text" BEGIN
+                INSERT INTO {search}(rowid, name, body) VALUES(NEW.id, NEW.name, {body});
+            END;
+            CREATE TRIGGER {files}_search_update AFTER UPDATE OF type, content ON {files} BEGIN
+                DELETE FROM {search} WHERE rowid=OLD.id;
+                INSERT INTO {search}(rowid, name, body) SELECT NEW.id, NEW.name, {body} WHERE NEW.type="text";
+            END;
+        "#))
+        .build()
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Splits a version 2 directory path such as `/a/b/` into its parent `/a/` and name `b`.
+fn parent_path(path: &str) -> Option<(&str, &str)> {
+    let trimmed = path.strip_suffix('/')?;
+    let split = trimmed.rfind('/')?;
+    Some((&path[..split + 1], &trimmed[split + 1..]))
+}
+
+async fn upgrade(conn: &mut SqliteConnection, fs_conn: &FSConnection, version: i64) -> Result<(), sqlx::Error> {
+    match version {
+        1 => v1(conn, fs_conn).await,
+        2 => v2(conn, fs_conn).await,
+        3 => v3(conn, fs_conn).await,
+        4 => v4(conn, fs_conn).await,
+        5 => v5(conn, fs_conn).await,
+        6 => v6(conn, fs_conn).await,
+        7 => v7(conn, fs_conn).await,
+        8 => v8(conn, fs_conn).await,
+        9 => v9(conn, fs_conn).await,
+        10 => v10(conn, fs_conn).await,
+        11 => v11(conn, fs_conn).await,
+        12 => v12(conn, fs_conn).await,
+        13 => v13(conn, fs_conn).await,
+        14 => v14(conn, fs_conn).await,
+        15 => v15(conn, fs_conn).await,
+        16 => v16(conn, fs_conn).await,
+        17 => v17(conn, fs_conn).await,
+        _ => Err(sqlx::Error::Protocol(format!("no migration to schema version {}", version))),
+    }
+}
+
+async fn check_foreign_keys(conn: &mut SqliteConnection, version: i64) -> Result<(), FSError> {
+    let violations = QueryBuilder::new("PRAGMA foreign_key_check;")
+        .build()
+        .fetch_all(conn)
+        .await
+        .map_err(FSError::database)?;
+    if !violations.is_empty() {
+        return Err(FSError::MigrationFailed(format!("schema version {} left {} foreign key violations", version, violations.len())));
+    }
+    Ok(())
+}
+
+async fn apply(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+    loop {
+        let mut tx = conn.begin().await.map_err(FSError::database)?;
+
+        // Re-read inside the transaction so concurrent openers don't apply a step twice.
+        let version = get_version(&mut tx, &fs_conn.schema_table).await.map_err(FSError::database)?;
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        let next = version + 1;
+        upgrade(&mut tx, fs_conn, next).await
+            .map_err(|e| FSError::MigrationFailed(format!("schema version {}: {}", next, e)))?;
+        check_foreign_keys(&mut tx, next).await?;
+        set_version(&mut tx, &fs_conn.schema_table, next).await.map_err(FSError::database)?;
+
+        tx.commit().await.map_err(FSError::database)?;
+    }
+}
+
+/// Brings the tables for `fs_conn`'s prefix up to [`SCHEMA_VERSION`], one committed step at a time.
+/// Fails with [`FSError::SchemaTooNew`] if the database was written by a newer build.
+pub(crate) async fn migrate(conn: &mut SqliteConnection, fs_conn: &FSConnection) -> Result<(), FSError> {
+    create_schema_table(conn, &fs_conn.schema_table).await.map_err(FSError::database)?;
+    let version = get_version(conn, &fs_conn.schema_table).await.map_err(FSError::database)?;
+    if version > SCHEMA_VERSION {
+        return Err(FSError::SchemaTooNew(version));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    // Table rebuilds drop and recreate referenced tables, which must not cascade.
+    QueryBuilder::new("PRAGMA foreign_keys = OFF;")
+        .build()
+        .execute(&mut *conn)
+        .await
+        .map_err(FSError::database)?;
+    let applied = apply(conn, fs_conn).await;
+    QueryBuilder::new("PRAGMA foreign_keys = ON;")
+        .build()
+        .execute(&mut *conn)
+        .await
+        .map_err(FSError::database)?;
+    applied
+}
@@ -1,9 +1,8 @@
 #[macro_use] extern crate rocket;
-use std::{path::{PathBuf}, str::FromStr, net::IpAddr, fs};
-use clap::{command, Parser};
-use rocket::{State, http::{ContentType}, Config};
+use std::{path::{PathBuf}, str::FromStr, net::IpAddr, fs, time::SystemTime, collections::BTreeMap};
+use clap::Parser;
+use rocket::{State, http::{ContentType, Header, Status}, Config, Request, Response, futures::stream, response::{self, Responder, stream::ByteStream}};
 use servefs_lib::*;
-use sqlx::{Row, sqlite::SqliteRow};
 use tera::{Tera, Context};
 use std::{str};
 
@@ -38,7 +37,7 @@ struct Args {
 async fn exec(ext: &str, command: &str) -> Option<(ContentType, Vec<u8>)>{
     tokio::process::Command::new("bash")
         .arg("-c")
-        .arg(&command)
+        .arg(command)
         .output()
         .await
         .ok()
@@ -50,35 +49,60 @@ async fn exec(ext: &str, command: &str) -> Option<(ContentType, Vec<u8>)>{
         .map(|str| (ContentType::from_extension(ext).unwrap_or(ContentType::Text), str.as_bytes().to_vec()))
 }
 
-async fn render_file(ext: &str, data: String, ftype: String) -> Option<(ContentType, Vec<u8>)> {
-    let ftype = match FileType::from_str(&ftype) {
-        Ok(ftype) => ftype,
-        Err(_) => return None,
-    };
-
+/// Text and blobs are streamed from the database and host files from disk, so neither is read
+/// into memory up front. Exec and custom files are rendered in full.
+async fn render_file(ext: &str, file: File, ftype: FileType, fs_conn: &FSConnection) -> Option<(ContentType, Body)> {
     match ftype {
         FileType::File => {
-            let path = PathBuf::from_str(&data).ok()?;
-            tokio::fs::read(path).await.ok()
-                .map(|str| (ContentType::from_extension(ext).unwrap_or(ContentType::Text), str))
+            let (data, _) = file.read_bytes(fs_conn).await.ok()?;
+            let path = PathBuf::from_str(str::from_utf8(&data).ok()?).ok()?;
+            tokio::fs::File::open(path).await.ok()
+                .map(|host| (ContentType::from_extension(ext).unwrap_or(ContentType::Text), Body::Host(host)))
+        },
+        FileType::Text => Some((ContentType::from_extension(ext).unwrap_or(ContentType::Text), Body::Stored(file))),
+        FileType::Exec => {
+            let (data, _) = file.read_bytes(fs_conn).await.ok()?;
+            tokio::time::timeout(
+                tokio::time::Duration::from_secs(1), 
+                exec(ext, str::from_utf8(&data).ok()?)
+            ).await.ok().and_then(|o|o)
+                .map(|(content_type, data)| (content_type, Body::Rendered(data)))
+        },
+        FileType::Blob => Some((ContentType::from_extension(ext).unwrap_or(ContentType::Binary), Body::Stored(file))),
+        // resolve_path follows links, so one only gets here if it was handed a link directly.
+        FileType::Link => None,
+        FileType::Custom(_) => {
+            let (data, _) = file.read_bytes(fs_conn).await.ok()?;
+            fs_conn.render_custom(&ftype, &file.path(), &data).await.ok()
+                .map(|data| (ContentType::from_extension(ext).unwrap_or(ContentType::Text), Body::Rendered(data)))
         },
-        FileType::Text => Some((ContentType::from_extension(ext).unwrap_or(ContentType::Text), data.as_bytes().to_vec())),
-        FileType::Exec => tokio::time::timeout(
-            tokio::time::Duration::from_secs(1), 
-            exec(ext, &data)
-        ).await.ok().and_then(|o|o),
     }
 }
 
-async fn render_dir(parent: &Directory, files: Vec<SqliteRow>, dirs: Vec<SqliteRow>, tera: &State<Tera>, dir_template: &State<String>) -> Option<(ContentType, Vec<u8>)> {
+/// Attribute values as text for templates.
+fn attr_strings(attrs: BTreeMap<String, Vec<u8>>) -> BTreeMap<String, String> {
+    attrs.into_iter()
+        .map(|(key, value)| (key, String::from_utf8_lossy(&value).to_string()))
+        .collect()
+}
+
+async fn render_dir(parent: &Directory, files: Vec<FileEntry>, dirs: Vec<DirEntry>, fs_conn: &FSConnection, tera: &State<Tera>, dir_template: &State<String>) -> Option<(ContentType, Vec<u8>)> {
+    let mut dir_attrs = BTreeMap::new();
+    let mut file_attrs = BTreeMap::new();
+    for dir in &dirs {
+        dir_attrs.insert(format!("{}/", dir.name), attr_strings(dir.directory().ok()?.attrs(fs_conn).await.ok()?));
+    }
+    for file in &files {
+        file_attrs.insert(file.name.clone(), attr_strings(file.file().ok()?.attrs(fs_conn).await.ok()?));
+    }
+
     let mut dirs = dirs
-        .iter()
-        .map(|row| row.get::<String, &str>("directory"))
-        .map(|name| name[parent.path.len()..].to_string())
+        .into_iter()
+        .map(|dir| format!("{}/", dir.name))
         .collect::<Vec<String>>();
     let mut files = files
-        .iter()
-        .map(|row| row.get::<String, &str>("name"))
+        .into_iter()
+        .map(|file| file.name)
         .collect::<Vec<String>>();
 
     dirs.sort();
@@ -88,11 +112,59 @@ async fn render_dir(parent: &Directory, files: Vec<SqliteRow>, dirs: Vec<SqliteR
     context.insert("dirs", &dirs);
     context.insert("files", &files);
     context.insert("parent", &parent.path);
+    context.insert("attrs", &attr_strings(parent.attrs(fs_conn).await.ok()?));
+    context.insert("dir_attrs", &dir_attrs);
+    context.insert("file_attrs", &file_attrs);
     let html = tera.render(dir_template, &context).ok()?;
 
     Some((ContentType::HTML, html.as_bytes().to_vec()))
 }
 
+/// The body of a response: rendered output, a file's stored data read a chunk at a time, or a
+/// host file.
+enum Body {
+    Rendered(Vec<u8>),
+    Stored(File),
+    Host(tokio::fs::File),
+}
+
+/// A rendered file or directory. Stored content carries a `Last-Modified` header; host files and
+/// exec output are produced fresh on every request so they don't.
+struct FSResponse<'r> {
+    content: (ContentType, Body),
+    modified: Option<SystemTime>,
+    fs_conn: &'r FSConnection,
+}
+
+impl<'r> Responder<'r, 'r> for FSResponse<'r> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let (content_type, body) = self.content;
+        let fs_conn = self.fs_conn;
+        let mut response = match body {
+            Body::Rendered(data) => data.respond_to(req)?,
+            Body::Stored(file) => ByteStream(stream::unfold((file, 0), move |(file, offset)| async move {
+                match file.read_at(offset, CHUNK_SIZE, fs_conn).await {
+                    Ok(data) if data.is_empty() => None,
+                    Ok(data) => {
+                        let offset = offset + data.len() as u64;
+                        Some((data, (file, offset)))
+                    },
+                    Err(e) => {
+                        println!("{}", e);
+                        None
+                    },
+                }
+            })).respond_to(req)?,
+            Body::Host(host) => Response::build().sized_body(None, host).finalize(),
+        };
+        response.set_header(content_type);
+        if let Some(modified) = self.modified {
+            response.set_header(Header::new("Last-Modified", httpdate::fmt_http_date(modified)));
+        }
+        Ok(response)
+    }
+}
+
 fn get_ext(name: &str) -> String {
     let path = match PathBuf::from_str(name){
         Ok(path) => path,
@@ -103,20 +175,36 @@ fn get_ext(name: &str) -> String {
     ).unwrap_or("".to_string())
 }
 
+fn status(e: FSError) -> Status {
+    match e {
+        FSError::NotFound(_) | FSError::PathIsNotADir(_) | FSError::PathIsNotAFile(_) => Status::NotFound,
+        FSError::LinkLoop(_) => Status::new(508),
+        FSError::QuotaExceeded(_) => Status::InsufficientStorage,
+        FSError::KeyRequired => Status::Forbidden,
+        _ => Status::InternalServerError,
+    }
+}
+
 #[get("/<path..>")]
-async fn get_fs(path: PathBuf, fs_conn: &State<FSConnection>, tera: &State<Tera>, dir_template: &State<String>) -> Option<(ContentType, Vec<u8>)> {
-    match fs_conn.resolve_path(path).await {
-        Ok(fs_type) => match fs_type {
-            FSType::File(file) => match file.read(&fs_conn).await {
-                Ok((data, ftype)) => render_file(&get_ext(&file.name), data, ftype).await,
-                Err(_) => None,
-            },
-            FSType::Directory(dir) => match dir.contents(fs_conn).await {
-                Ok((files, dirs)) => render_dir(&dir, files, dirs, tera, dir_template).await,
-                Err(_) => None,
-            },
+async fn get_fs<'r>(path: PathBuf, fs_conn: &'r State<FSConnection>, tera: &State<Tera>, dir_template: &State<String>) -> Result<FSResponse<'r>, Status> {
+    match fs_conn.resolve_path(path).await.map_err(status)? {
+        FSType::File(file) => {
+            let ftype = file.open(fs_conn).await.map_err(status)?;
+            let modified = match ftype {
+                FileType::Text | FileType::Blob => Some(file.metadata(fs_conn).await.map_err(status)?.modified),
+                _ => None,
+            };
+            let content = render_file(&get_ext(&file.name), file, ftype, fs_conn).await
+                .ok_or(Status::InternalServerError)?;
+            Ok(FSResponse { content, modified, fs_conn })
+        },
+        FSType::Directory(dir) => {
+            let (files, dirs) = dir.contents(fs_conn).await.map_err(status)?;
+            let modified = Some(dir.metadata(fs_conn).await.map_err(status)?.modified);
+            let (content_type, data) = render_dir(&dir, files, dirs, fs_conn, tera, dir_template).await
+                .ok_or(Status::InternalServerError)?;
+            Ok(FSResponse { content: (content_type, Body::Rendered(data)), modified, fs_conn })
         },
-        _=> None,
     }
 }
 
@@ -186,7 +274,7 @@ async fn servefs() -> _ {
             dir_template_loc
         },
         None => {
-            template_path.push(&default_template_file);
+            template_path.push(default_template_file);
             if !template_path.is_file() {
                 println!("{:?}", template_path);
                 fs::write(template_path, default_directory_template).expect("Couldn't create default directory template");
@@ -217,7 +305,15 @@ async fn servefs() -> _ {
         }
     };
 
-    let fs_conn = FSConnection::new(&db_loc, &db_prefix, true).await.unwrap();
+    let key = match EncryptionKey::from_env() {
+        Ok(key) => key,
+        Err(e) => {
+            println!("Key error: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+    let mut fs_conn = FSConnection::new(&db_loc, &db_prefix, true).await.unwrap();
+    fs_conn.set_key(key);
     rocket::build()
         .configure(rocket_config)
         .manage(fs_conn)